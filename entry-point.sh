@@ -23,7 +23,7 @@ export ROCKET_DATABASES="{mysql={url=mysql://$GITLAB_MIDDLEWARE_DB_USER:$GITLAB_
 MIGRATIONS=$(ls setup/*.sql | awk '{print "source " $0 ";"}')
 
 mysql -u $GITLAB_MIDDLEWARE_DB_USER -p$GITLAB_MIDDLEWARE_DB_PASS -h $GITLAB_MIDDLEWARE_DB_HOST -P $GITLAB_MIDDLEWARE_DB_PORT \
-    -e "use \`${GITLAB_MIDDLEWARE_DB_NAME}\`; ${MIGRATIONS}; call setup_2;"
+    -e "use \`${GITLAB_MIDDLEWARE_DB_NAME}\`; ${MIGRATIONS}; call setup_12;"
 
 ./oj-gitlab-middleware
 
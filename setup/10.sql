@@ -0,0 +1,58 @@
+/*
+Copyright (c) 2019 llk89.
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU Affero General Public License as
+ published by the Free Software Foundation, either version 3 of the
+ License, or (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU Affero General Public License for more details.
+
+ You should have received a copy of the GNU Affero General Public License
+ along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+drop procedure if exists setup_10;
+drop procedure if exists setup_10_;
+delimiter //
+
+create procedure setup_10()
+  modifies sql data
+begin
+  create table if not exists version
+  (
+    id int(7) unsigned not null
+      primary key
+  );
+  set @self = (select count(*) from version where id = 9);
+  if (@self = 0) then
+    call setup_10_();
+  end if;
+end//
+
+create procedure setup_10_()
+  modifies sql data
+begin
+
+  set @parent = (select count(*) from version where id = 8);
+  if (@parent = 0) then
+    call setup_9_();
+  end if;
+
+  create table if not exists assignment_events
+  (
+    assignment_id bigint unsigned not null
+      primary key,
+    tag_push      tinyint(1)      not null default 1,
+    merge_request tinyint(1)      not null default 0,
+    note          tinyint(1)      not null default 0,
+    pipeline      tinyint(1)      not null default 0
+  );
+
+  insert into version(id) VALUES (9);
+end //
+
+delimiter ;
@@ -0,0 +1,55 @@
+/*
+Copyright (c) 2019 llk89.
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU Affero General Public License as
+ published by the Free Software Foundation, either version 3 of the
+ License, or (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU Affero General Public License for more details.
+
+ You should have received a copy of the GNU Affero General Public License
+ along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+drop procedure if exists setup_11;
+drop procedure if exists setup_11_;
+delimiter //
+
+create procedure setup_11()
+  modifies sql data
+begin
+  create table if not exists version
+  (
+    id int(7) unsigned not null
+      primary key
+  );
+  set @self = (select count(*) from version where id = 10);
+  if (@self = 0) then
+    call setup_11_();
+  end if;
+end//
+
+create procedure setup_11_()
+  modifies sql data
+begin
+
+  set @parent = (select count(*) from version where id = 9);
+  if (@parent = 0) then
+    call setup_10_();
+  end if;
+
+  alter table idempotency_keys
+    add column reserved_at bigint unsigned not null default 0;
+
+  update idempotency_keys set reserved_at = unix_timestamp(created_at);
+
+  create index idempotency_keys_reserved_at_index on idempotency_keys (reserved_at);
+
+  insert into version(id) VALUES (10);
+end //
+
+delimiter ;
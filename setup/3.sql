@@ -0,0 +1,58 @@
+/*
+Copyright (c) 2019 llk89.
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU Affero General Public License as
+ published by the Free Software Foundation, either version 3 of the
+ License, or (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU Affero General Public License for more details.
+
+ You should have received a copy of the GNU Affero General Public License
+ along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+drop procedure if exists setup_3;
+drop procedure if exists setup_3_;
+delimiter //
+
+create procedure setup_3()
+  modifies sql data
+begin
+  create table if not exists version
+  (
+    id int(7) unsigned not null
+      primary key
+  );
+  set @self = (select count(*) from version where id = 2);
+  if (@self = 0) then
+    call setup_3_();
+  end if;
+end//
+
+create procedure setup_3_()
+  modifies sql data
+begin
+
+  set @parent = (select count(*) from version where id = 1);
+  if (@parent = 0) then
+    call setup_2_();
+  end if;
+
+  create table if not exists idempotency_keys
+  (
+    idem_key     varchar(255)      not null
+      primary key,
+    request_hash char(128)         not null,
+    status       smallint unsigned null,
+    body         mediumtext        null,
+    created_at   timestamp         not null default current_timestamp
+  );
+
+  insert into version(id) VALUES (2);
+end //
+
+delimiter ;
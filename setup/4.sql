@@ -0,0 +1,65 @@
+/*
+Copyright (c) 2019 llk89.
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU Affero General Public License as
+ published by the Free Software Foundation, either version 3 of the
+ License, or (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU Affero General Public License for more details.
+
+ You should have received a copy of the GNU Affero General Public License
+ along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+drop procedure if exists setup_4;
+drop procedure if exists setup_4_;
+delimiter //
+
+create procedure setup_4()
+  modifies sql data
+begin
+  create table if not exists version
+  (
+    id int(7) unsigned not null
+      primary key
+  );
+  set @self = (select count(*) from version where id = 3);
+  if (@self = 0) then
+    call setup_4_();
+  end if;
+end//
+
+create procedure setup_4_()
+  modifies sql data
+begin
+
+  set @parent = (select count(*) from version where id = 2);
+  if (@parent = 0) then
+    call setup_3_();
+  end if;
+
+  create table if not exists jobs
+  (
+    id         bigint unsigned not null auto_increment
+      primary key,
+    kind       varchar(64)     not null,
+    payload    mediumtext      not null,
+    status     varchar(16)     not null,
+    progress   int unsigned    not null default 0,
+    total      int unsigned    not null default 0,
+    result     mediumtext      null,
+    error      text            null,
+    created_at bigint unsigned not null,
+    heartbeat  bigint unsigned not null
+  );
+
+  create index jobs_status_index on jobs (status);
+
+  insert into version(id) VALUES (3);
+end //
+
+delimiter ;
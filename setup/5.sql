@@ -0,0 +1,64 @@
+/*
+Copyright (c) 2019 llk89.
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU Affero General Public License as
+ published by the Free Software Foundation, either version 3 of the
+ License, or (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU Affero General Public License for more details.
+
+ You should have received a copy of the GNU Affero General Public License
+ along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+drop procedure if exists setup_5;
+drop procedure if exists setup_5_;
+delimiter //
+
+create procedure setup_5()
+  modifies sql data
+begin
+  create table if not exists version
+  (
+    id int(7) unsigned not null
+      primary key
+  );
+  set @self = (select count(*) from version where id = 4);
+  if (@self = 0) then
+    call setup_5_();
+  end if;
+end//
+
+create procedure setup_5_()
+  modifies sql data
+begin
+
+  set @parent = (select count(*) from version where id = 3);
+  if (@parent = 0) then
+    call setup_4_();
+  end if;
+
+  create table if not exists webhook_outbox
+  (
+    id              bigint unsigned not null auto_increment
+      primary key,
+    path            varchar(255)    not null,
+    payload         mediumtext      not null,
+    status          varchar(16)     not null,
+    attempts        int unsigned    not null default 0,
+    next_attempt_at bigint unsigned not null,
+    last_error      text            null,
+    created_at      bigint unsigned not null,
+    delivered_at    bigint unsigned null
+  );
+
+  create index webhook_outbox_due_index on webhook_outbox (status, next_attempt_at);
+
+  insert into version(id) VALUES (4);
+end //
+
+delimiter ;
@@ -0,0 +1,55 @@
+/*
+Copyright (c) 2019 llk89.
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU Affero General Public License as
+ published by the Free Software Foundation, either version 3 of the
+ License, or (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU Affero General Public License for more details.
+
+ You should have received a copy of the GNU Affero General Public License
+ along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+drop procedure if exists setup_6;
+drop procedure if exists setup_6_;
+delimiter //
+
+create procedure setup_6()
+  modifies sql data
+begin
+  create table if not exists version
+  (
+    id int(7) unsigned not null
+      primary key
+  );
+  set @self = (select count(*) from version where id = 5);
+  if (@self = 0) then
+    call setup_6_();
+  end if;
+end//
+
+create procedure setup_6_()
+  modifies sql data
+begin
+
+  set @parent = (select count(*) from version where id = 4);
+  if (@parent = 0) then
+    call setup_5_();
+  end if;
+
+  alter table repo_ids
+    add column ddl            bigint unsigned null,
+    add column ddl_enforced   tinyint(1)      not null default 0,
+    add column ddl_claimed_at bigint unsigned null;
+
+  create index repo_ids_ddl_index on repo_ids (ddl_enforced, ddl);
+
+  insert into version(id) VALUES (5);
+end //
+
+delimiter ;
@@ -19,15 +19,15 @@ use std::borrow::Cow;
 use std::net::IpAddr;
 
 use ::{Error, GMResult, SafeNetwork};
+use err::guard_error;
 
-use reqwest::{Client, ClientBuilder, Method, Response};
+use reqwest::{Client, ClientBuilder, Method, Response, StatusCode};
 use reqwest::header::{HeaderMap, HeaderValue};
 use url::Url;
 
 use rocket::{Outcome, Request, State};
 use rocket::http::Status;
 use rocket::request::{self, FromRequest};
-use rocket_contrib::json::JsonValue;
 use serde::Serialize;
 
 use hex::encode;
@@ -74,6 +74,11 @@ pub trait APIAccessor {
         }?;
 
         if res.status().is_server_error() | res.status().is_client_error() {
+            if res.status() == StatusCode::NOT_FOUND {
+                // callers match on NotFound to tell things gone from upstream
+                error!("{} {}: not found", &method, path);
+                return Err(Error::NotFound);
+            }
             if let Ok(body) = res.json::<Value>() {
                 if let Some(json) = body.get("message") {
                     if json.is_string() {
@@ -93,58 +98,7 @@ pub trait APIAccessor {
     fn base(&self) -> &Url;
 }
 
-pub struct GitLabAPI {
-    client: Client,
-    _base_url: Url,
-}
-
-impl GitLabAPI {
-    pub fn new(token: &str, base_url: Url) -> GitLabAPI {
-        let mut default_header = HeaderMap::new();
-        default_header.insert("Private-Token", HeaderValue::from_str(token).expect("Token malformed"));
-        GitLabAPI {
-            client: ClientBuilder::new()
-                .default_headers(default_header)
-                .build().expect("What?"),
-            _base_url: base_url,
-        }
-    }
-
-    pub fn lookup_user_id(&self, username: &str) -> GMResult<u64> {
-        let mut res = self.execute(Method::GET, &format!["users?username={}", username], "", None)?
-            .error_for_status()?;
-        let body = res.text()?;
-
-        if body.is_empty() {
-            Err(Error::NotFound)
-        } else {
-            Ok(serde_json::from_str::<serde_json::Value>(&body)?[0]["id"].as_u64().expect("Schema changed."))
-        }
-    }
-
-    pub fn remove_keys(&self, id: u64) -> GMResult<()> {
-        let keys: JsonValue = self.call_no_body(Method::GET, &format!("users/{}/keys", id))?.json()?;
-
-        for val in keys.as_array().iter().map(|k| k.iter()).flatten() {
-            self.call_no_body(Method::DELETE, &format!("users/{}/keys/{}", id, val["id"].as_u64().expect("Gitlab schema changed.")))?;
-        }
-
-        Ok(())
-    }
-}
-
-impl APIAccessor for GitLabAPI {
-    #[inline]
-    fn client(&self) -> &Client {
-        &self.client
-    }
-
-    #[inline]
-    fn base(&self) -> &Url {
-        &self._base_url
-    }
-}
-
+#[derive(Clone)]
 pub struct BackendAPI {
     client: Client,
     _base_url: Url,
@@ -187,14 +141,14 @@ fn is_ip_same(lhs: &IpAddr, rhs: &IpAddr) -> bool {
 }
 
 macro_rules! gitlab_event {
-    ($clz: tt, $name: expr) => {
+    ($clz: tt, $name: expr, $gitea_name: expr) => {
         pub struct $clz();
 
         impl<'a, 'r> FromRequest<'a, 'r> for $clz {
             type Error = Error;
 
             fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-                $clz::from_request0(request).map_failure(|(s,f)|(s,Error::new(f)))
+                $clz::from_request0(request).map_failure(|(s,f)|(s,guard_error(request, Error::new(f))))
             }
         }
 
@@ -218,21 +172,26 @@ macro_rules! gitlab_event {
                         }
                         if let Outcome::Success(s) = request.guard::<State<TokenSalt>>() {
                             let token = calc_token(&request.uri().to_string(), &*s);
-                            if !request.headers().get("x-gitlab-token").any(|t| t==token) {
+                            // gitea can only send the token as authorization header
+                            let bearer = format!("Bearer {}", token);
+                            if !request.headers().get("x-gitlab-token").any(|t| t==token)
+                                && !request.headers().get("authorization").any(|t| t==bearer) {
                                 info!(target:stringify!(oj_gitlab_middleware::hooks::$clz),"Blocked access with bad token");
                                 return Outcome::Failure((Status::Forbidden, "Require valid token"))
                             }
                         }
                     }
                 }
-                let name: Vec<_> = request.headers().get("x-gitlab-event").collect();
+                let name: Vec<_> = request.headers().get("x-gitlab-event")
+                    .chain(request.headers().get("x-gitea-event")).collect();
                 if name.len() != 1 {
                     info!(target:stringify!(oj_gitlab_middleware::hooks::$clz),"Blocked access with wrong number of event indicator: {}", name.len());
                     return Outcome::Failure((Status::BadRequest, stringify!(No gitlab $name)))
                 }
-                if name[0] != $name {
-                    info!(target:stringify!(oj_gitlab_middleware::hooks::$clz),"Blocked access with bad event indicator: {}, expecting {}", name[0], $name);
-                    return Outcome::Failure((Status::BadRequest, stringify!(Not gitlab $name)))
+                if name[0] != $name && name[0] != $gitea_name {
+                    // another event, for another route on the same url
+                    trace!(target:stringify!(oj_gitlab_middleware::hooks::$clz),"Forwarded event {}, expecting {}", name[0], $name);
+                    return Outcome::Forward(())
                 }
                 trace!(target:stringify!(oj_gitlab_middleware::hooks::$clz),"Accepted {}", $name); // rust stupid here, could be a constant, maybe i'm stupid
                 return Outcome::Success($clz());
@@ -241,7 +200,12 @@ macro_rules! gitlab_event {
     };
 }
 
-gitlab_event!(Push, "Push Hook");
+gitlab_event!(Push, "Push Hook", "push");
+gitlab_event!(TagPush, "Tag Push Hook", "create");
+gitlab_event!(MergeRequest, "Merge Request Hook", "pull_request");
+gitlab_event!(Note, "Note Hook", "issue_comment");
+// a workflow run of gitea actions
+gitlab_event!(Pipeline, "Pipeline Hook", "workflow_run");
 
 pub struct TokenSalt(pub String);
 
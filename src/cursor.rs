@@ -0,0 +1,60 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Opaque cursors for paging through commits of a repo.
+//!
+//! A cursor is the repo id and page number, signed with HMAC-SHA256 keyed by `TokenSalt`, all base64 encoded.
+//! Clients can't forge one, nor reuse one for another repo, so nothing they send ends up in a git server url.
+
+use ::{Error, GMResult};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Length of HMAC-SHA256.
+const MAC_LEN: usize = 32;
+
+const INVALID: &str = "Invalid cursor";
+
+fn mac(salt: &str, payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(salt.as_bytes()).expect("HMAC takes any key");
+    mac.input(payload);
+    mac
+}
+
+pub fn sign(salt: &str, repo: u64, page: u32) -> String {
+    let payload = format!("commits.{}.{}", repo, page);
+    let mut cursor = mac(salt, payload.as_bytes()).result().code().to_vec();
+    cursor.extend_from_slice(payload.as_bytes());
+    base64::encode_config(&cursor, base64::URL_SAFE_NO_PAD)
+}
+
+/// Page number in `cursor`, which must have been signed for `repo`.
+pub fn verify(salt: &str, repo: u64, cursor: &str) -> GMResult<u32> {
+    let cursor = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map_err(|_| Error::BadRequest(INVALID))?;
+    if cursor.len() <= MAC_LEN {
+        return Err(Error::BadRequest(INVALID));
+    }
+    let (code, payload) = cursor.split_at(MAC_LEN);
+    mac(salt, payload).verify(code).map_err(|_| Error::BadRequest(INVALID))?;
+    let payload = String::from_utf8_lossy(payload);
+    let fields: Vec<&str> = payload.split('.').collect();
+    match fields[..] {
+        ["commits", signed_repo, page] if signed_repo.parse() == Ok(repo) => page.parse().map_err(|_| Error::BadRequest(INVALID)),
+        _ => Err(Error::BadRequest(INVALID)),
+    }
+}
@@ -0,0 +1,340 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Closing repos once their deadline passed.
+//!
+//! `expires_at` on git server only has day granularity, and depends on the git server getting to it.
+//! So the deadline is kept in `repo_ids`, and once passed owners are downgraded to reporter and every branch is locked.
+//! An owner may have an extension in `deadline_extensions`, keeping their access and the branches open until it passes too.
+//! A repo or extension is claimed before being closed, so instances don't close it twice. A claim older than `LEASE` was abandoned.
+//! Changing a deadline claims the repo too, so it never acts on a repo halfway closed, nor the other way round.
+//! Marking a repo or extension closed only sticks if it is still claimed by whoever closed it.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ::{DBAccess, DBAccessPool, Error, GMResult};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone};
+use gitserver::{AccessLevel, GitServer, GitServerAPI};
+use jobs::{now, Wakeup};
+
+const LEASE: u64 = 600;
+/// How long changing a deadline waits for the repo to be closed meanwhile.
+const CLAIM_WAIT: u64 = 10;
+
+/// The deadline as unix time, and the `expires_at` date for git server.
+/// RFC 3339, e.g. `2019-01-01T23:59:59+08:00`. Without offset it is in `timezone`, and a date alone means the end of that day.
+pub(crate) fn parse_ddl(ddl: &str, timezone: FixedOffset) -> GMResult<(u64, String)> {
+    let deadline = if let Ok(time) = DateTime::parse_from_rfc3339(ddl) {
+        time
+    } else if let Ok(time) = NaiveDateTime::parse_from_str(ddl, "%Y-%m-%dT%H:%M:%S%.f") {
+        timezone.from_local_datetime(&time).unwrap()
+    } else if let Ok(date) = NaiveDate::parse_from_str(ddl, "%Y-%m-%d") {
+        timezone.from_local_datetime(&date.succ().and_hms(0, 0, 0)).unwrap()
+    } else {
+        return Err(Error::BadRequest("ddl must be RFC 3339, e.g. 2019-01-01T23:59:59+08:00, or a date"));
+    };
+    let deadline = deadline.timestamp().max(0) as u64;
+    Ok((deadline, expires_at(deadline, timezone)))
+}
+
+/// The day after the deadline, so git server never drops members before the deadline is enforced.
+fn expires_at(deadline: u64, timezone: FixedOffset) -> String {
+    let last_day = timezone.timestamp(deadline as i64 - 1, 0).date().naive_local();
+    last_day.succ().format("%Y-%m-%d").to_string()
+}
+
+/// An RFC 3339 offset, `Z` or e.g. `+08:00`.
+pub(crate) fn parse_timezone(timezone: &str) -> Option<FixedOffset> {
+    DateTime::parse_from_rfc3339(&format!("2000-01-01T00:00:00{}", timezone)).ok().map(|time| *time.offset())
+}
+
+pub struct DeadlineEnforcer {
+    wakeup: Arc<Wakeup>,
+}
+
+impl DeadlineEnforcer {
+    pub(crate) fn start(poll_interval: Duration, pool: DBAccessPool, git_server: GitServerAPI) -> DeadlineEnforcer {
+        let wakeup = Wakeup::new();
+        let shared = wakeup.clone();
+        thread::Builder::new()
+            .name("deadline-enforcer".to_string())
+            .spawn(move || enforce(&shared, poll_interval, &pool, &*git_server))
+            .expect("Failed to start deadline enforcer");
+        DeadlineEnforcer { wakeup }
+    }
+}
+
+impl Drop for DeadlineEnforcer {
+    fn drop(&mut self) {
+        self.wakeup.stop();
+    }
+}
+
+fn enforce(wakeup: &Wakeup, poll_interval: Duration, pool: &DBAccessPool, git_server: &dyn GitServer) {
+    while let Some(mark) = wakeup.mark() {
+        let result = pool.connect().and_then(|mut db| {
+            for repo in db.passed_deadlines(now())? {
+                let claim = now();
+                if db.claim_deadline(repo, claim)? {
+                    match close(&mut db, git_server, repo) {
+                        Ok(()) => db.deadline_enforced(repo, claim)?,
+                        Err(_) => db.release_repo(repo, claim)?,
+                    }
+                }
+            }
+            for (repo, owner) in db.passed_extensions(now())? {
+                let claim = now();
+                // closing acts on the whole repo
+                if db.claim_repo(repo, claim)? {
+                    if db.claim_extension(repo, owner, claim)? {
+                        match close(&mut db, git_server, repo) {
+                            Ok(()) => db.extension_enforced(repo, owner, claim)?,
+                            Err(_) => db.release_extension(repo, owner, claim)?,
+                        }
+                    }
+                    db.release_repo(repo, claim)?;
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("Failed to enforce deadlines: {:?}", e);
+        }
+        wakeup.wait(mark, poll_interval);
+    }
+}
+
+/// Downgrade owners whose deadline passed, and lock branches once all did.
+/// Nothing to do before the deadline of the repo itself.
+fn close(db: &mut DBAccess, git_server: &dyn GitServer, repo: u64) -> GMResult<()> {
+    let now = now();
+    let (ddl, _) = db.repo_deadline(repo)?;
+    if ddl.map_or(true, |ddl| ddl > now) {
+        return Ok(());
+    }
+    let extended = db.open_extensions(repo, now)?;
+    let result = git_server.list_repo_members(repo).and_then(|members| {
+        for member in members.into_iter().filter(|m| !extended.contains(m)) {
+            git_server.update_repo_member(repo, member, AccessLevel::Reporter, None)?;
+        }
+        if extended.is_empty() {
+            git_server.lock_branches(repo)?;
+        }
+        Ok(())
+    });
+    match result {
+        // nothing left to close
+        Ok(()) | Err(Error::NotFound) => {
+            info!("Closed repo {} after its deadline, {} owners extended", repo, extended.len());
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Failed to close repo {} after its deadline, will retry: {:?}", repo, e);
+            Err(e)
+        }
+    }
+}
+
+/// Move the deadline of the whole repo, or only of `owners`, dropping extensions of the former.
+/// Owners are given back access if the new deadline is still to come.
+/// `Err(Error::AlreadyExists)` if the repo is still being closed after `CLAIM_WAIT`.
+pub(crate) fn set_deadline(db: &mut DBAccess, git_server: &dyn GitServer, repo: u64, deadline: u64, expires_at: &str,
+                           owners: Option<&[u64]>) -> GMResult<()> {
+    let give_up = now() + CLAIM_WAIT;
+    let claim = loop {
+        let claim = now();
+        if db.claim_repo(repo, claim)? {
+            break claim;
+        }
+        // deleted meanwhile
+        db.repo_name(repo)?;
+        if claim >= give_up {
+            warn!("Repo {} is being closed, not changing its deadline", repo);
+            return Err(Error::AlreadyExists);
+        }
+        thread::sleep(Duration::from_millis(200));
+    };
+    let result = change_deadline(db, git_server, repo, deadline, expires_at, owners);
+    db.release_repo(repo, claim)?;
+    result
+}
+
+fn change_deadline(db: &mut DBAccess, git_server: &dyn GitServer, repo: u64, deadline: u64, expires_at: &str,
+                   owners: Option<&[u64]>) -> GMResult<()> {
+    let members = git_server.list_repo_members(repo)?;
+    let (_, closed) = db.repo_deadline(repo)?;
+    let owners = match owners {
+        Some(owners) => {
+            if let Some(owner) = owners.iter().find(|o| !members.contains(o)) {
+                warn!("User {} doesn't own repo {}", owner, repo);
+                return Err(Error::NotFound);
+            }
+            for &owner in owners {
+                db.extend_deadline(repo, owner, deadline)?;
+            }
+            owners.to_vec()
+        }
+        None => {
+            db.set_repo_deadline(repo, deadline)?;
+            members
+        }
+    };
+    if deadline > now() {
+        for &owner in &owners {
+            git_server.update_repo_member(repo, owner, AccessLevel::Maintainer, Some(expires_at))?;
+        }
+        if closed {
+            git_server.unlock_branches(repo)?;
+        }
+        info!("Reopened repo {} for {} owners", repo, owners.len());
+    }
+    Ok(())
+}
+
+/// Access and `expires_at` of an owner joining now: maintainer until the deadline, reporter once it passed.
+pub(crate) fn owner_access(db: &mut DBAccess, repo: u64, timezone: FixedOffset) -> GMResult<(AccessLevel, Option<String>)> {
+    Ok(match db.repo_deadline(repo)? {
+        (Some(ddl), _) if ddl <= now() => (AccessLevel::Reporter, None),
+        (Some(ddl), _) => (AccessLevel::Maintainer, Some(expires_at(ddl, timezone))),
+        (None, _) => (AccessLevel::Maintainer, None),
+    })
+}
+
+impl DBAccess {
+    fn passed_deadlines(&mut self, now: u64) -> GMResult<Vec<u64>> {
+        let rows = self.0.prep_exec(
+            r"SELECT repo_id FROM repo_ids WHERE ddl<=? AND ddl_enforced=0 AND (ddl_claimed_at IS NULL OR ddl_claimed_at<?) ORDER BY ddl",
+            (now, now.saturating_sub(LEASE)))?;
+        let mut repos = Vec::new();
+        for row in rows {
+            repos.push(::mysql::from_row(row?));
+        }
+        Ok(repos)
+    }
+
+    /// `false` if another instance was faster, or the repo is claimed otherwise.
+    fn claim_deadline(&mut self, repo: u64, now: u64) -> GMResult<bool> {
+        let claimed = self.0.prep_exec(
+            r"UPDATE repo_ids SET ddl_claimed_at=? WHERE repo_id=? AND ddl_enforced=0 AND (ddl_claimed_at IS NULL OR ddl_claimed_at<?)",
+            (now, repo, now.saturating_sub(LEASE)))?.affected_rows();
+        Ok(claimed == 1)
+    }
+
+    /// As `claim_deadline`, whether or not the deadline passed. `false` if the repo is gone.
+    fn claim_repo(&mut self, repo: u64, now: u64) -> GMResult<bool> {
+        let claimed = self.0.prep_exec(
+            r"UPDATE repo_ids SET ddl_claimed_at=? WHERE repo_id=? AND (ddl_claimed_at IS NULL OR ddl_claimed_at<?)",
+            (now, repo, now.saturating_sub(LEASE)))?.affected_rows();
+        Ok(claimed == 1)
+    }
+
+    /// Deadline, and whether the repo was closed.
+    pub(crate) fn repo_deadline(&mut self, repo: u64) -> GMResult<(Option<u64>, bool)> {
+        self.0.first_exec(r"SELECT ddl, ddl_enforced FROM repo_ids WHERE repo_id=?", (repo, ))
+            ?.ok_or(Error::NotFound)
+    }
+
+    fn set_repo_deadline(&mut self, repo: u64, ddl: u64) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE repo_ids SET ddl=?, ddl_enforced=0 WHERE repo_id=?", (ddl, repo))?;
+        self.0.prep_exec(r"DELETE FROM deadline_extensions WHERE repo_id=?", (repo, ))?;
+
+        Ok(())
+    }
+
+    fn extend_deadline(&mut self, repo: u64, owner: u64, ddl: u64) -> GMResult<()> {
+        self.forget_extension(repo, owner)?;
+        self.0.prep_exec(r"INSERT INTO deadline_extensions(repo_id, uid, ddl) VALUES (?, ?, ?)", (repo, owner, ddl))?;
+
+        Ok(())
+    }
+
+    /// Once `owner` left the repo.
+    pub(crate) fn forget_extension(&mut self, repo: u64, owner: u64) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM deadline_extensions WHERE repo_id=? AND uid=?", (repo, owner))?;
+
+        Ok(())
+    }
+
+    /// The deadline of `owner`, extension included.
+    pub(crate) fn owner_deadline(&mut self, repo: u64, owner: u64) -> GMResult<Option<u64>> {
+        let (ddl, _) = self.repo_deadline(repo)?;
+        let extension: Option<u64> = self.0.first_exec(
+            r"SELECT ddl FROM deadline_extensions WHERE repo_id=? AND uid=?", (repo, owner))?;
+        Ok(ddl.into_iter().chain(extension).max())
+    }
+
+    /// The deadline of the owner who got the longest extension, if any.
+    pub(crate) fn latest_deadline(&mut self, repo: u64) -> GMResult<Option<u64>> {
+        let (ddl, _) = self.repo_deadline(repo)?;
+        let extension: Option<Option<u64>> = self.0.first_exec(r"SELECT MAX(ddl) FROM deadline_extensions WHERE repo_id=?", (repo, ))?;
+        Ok(ddl.into_iter().chain(extension.and_then(|e| e)).max())
+    }
+
+    /// Owners whose extension is still to come.
+    fn open_extensions(&mut self, repo: u64, now: u64) -> GMResult<Vec<u64>> {
+        let rows = self.0.prep_exec(r"SELECT uid FROM deadline_extensions WHERE repo_id=? AND ddl>?", (repo, now))?;
+        let mut owners = Vec::new();
+        for row in rows {
+            owners.push(::mysql::from_row(row?));
+        }
+        Ok(owners)
+    }
+
+    fn passed_extensions(&mut self, now: u64) -> GMResult<Vec<(u64, u64)>> {
+        let rows = self.0.prep_exec(
+            r"SELECT repo_id, uid FROM deadline_extensions WHERE ddl<=? AND enforced=0 AND (claimed_at IS NULL OR claimed_at<?) ORDER BY ddl",
+            (now, now.saturating_sub(LEASE)))?;
+        let mut extensions = Vec::new();
+        for row in rows {
+            extensions.push(::mysql::from_row(row?));
+        }
+        Ok(extensions)
+    }
+
+    fn claim_extension(&mut self, repo: u64, owner: u64, now: u64) -> GMResult<bool> {
+        let claimed = self.0.prep_exec(
+            r"UPDATE deadline_extensions SET claimed_at=? WHERE repo_id=? AND uid=? AND enforced=0 AND (claimed_at IS NULL OR claimed_at<?)",
+            (now, repo, owner, now.saturating_sub(LEASE)))?.affected_rows();
+        Ok(claimed == 1)
+    }
+
+    fn extension_enforced(&mut self, repo: u64, owner: u64, claim: u64) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE deadline_extensions SET enforced=1 WHERE repo_id=? AND uid=? AND claimed_at=?", (repo, owner, claim))?;
+
+        Ok(())
+    }
+
+    fn release_extension(&mut self, repo: u64, owner: u64, claim: u64) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE deadline_extensions SET claimed_at=NULL WHERE repo_id=? AND uid=? AND claimed_at=?", (repo, owner, claim))?;
+
+        Ok(())
+    }
+
+    fn deadline_enforced(&mut self, repo: u64, claim: u64) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE repo_ids SET ddl_enforced=1, ddl_claimed_at=NULL WHERE repo_id=? AND ddl_claimed_at=?", (repo, claim))?;
+
+        Ok(())
+    }
+
+    fn release_repo(&mut self, repo: u64, claim: u64) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE repo_ids SET ddl_claimed_at=NULL WHERE repo_id=? AND ddl_claimed_at=?", (repo, claim))?;
+
+        Ok(())
+    }
+}
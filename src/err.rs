@@ -20,10 +20,13 @@ use mysql::Error as MySQLError;
 use reqwest::Error as HTTPError;
 use serde_json::error::Error as JSONError;
 
-use rocket::{Request, Response};
-use rocket::http::Status;
+use rocket::{Catcher, Request, Response};
+use rocket::http::{ContentType, Header, Status};
 use rocket::response::Responder;
+use std::borrow::Cow;
 use std::io::Cursor;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use time::ParseError;
 
 #[derive(Debug)]
@@ -35,11 +38,22 @@ pub enum Error {
     NotFound,
     TimeError(ParseError),
     UpstreamError(u16, String),
+    BadRequest(&'static str),
+    Unprocessable(&'static str),
     SomeError(&'static str),
 }
 
 pub type GMResult<T> = Result<T, Error>;
 
+/// `None` instead of `NotFound`, for lookups allowed to miss.
+pub fn optional<T>(result: GMResult<T>) -> GMResult<Option<T>> {
+    match result {
+        Ok(t) => Ok(Some(t)),
+        Err(Error::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
 impl Error {
     pub fn upstream(code: u16, reason: String) -> Error {
         Error::UpstreamError(code, reason)
@@ -47,6 +61,28 @@ impl Error {
     pub fn new(reason: &'static str) -> Error {
         Error::SomeError(reason)
     }
+    /// The git server answered in a shape we don't know.
+    pub fn schema_changed() -> Error {
+        Error::UpstreamError(502, "Git server schema changed".to_string())
+    }
+
+    /// Status, machine readable code, message and upstream status.
+    fn describe(&self) -> (Status, &'static str, Cow<str>, Option<u16>) {
+        match self {
+            Error::AlreadyExists => (Status::Conflict, "already_exists", Cow::Borrowed("Already exists"), None),
+            Error::NotFound => (Status::NotFound, "not_found", Cow::Borrowed("Not found"), None),
+            Error::BadRequest(reason) => (Status::BadRequest, "bad_request", Cow::Borrowed(reason), None),
+            Error::Unprocessable(reason) => (Status::UnprocessableEntity, "unprocessable", Cow::Borrowed(reason), None),
+            Error::UpstreamError(code, message) =>
+                (Status::from_code(*code).unwrap_or(Status::InternalServerError), "upstream_error", Cow::Borrowed(message), Some(*code)),
+            Error::HTTPError(e) =>
+                (Status::InternalServerError, "upstream_unavailable", Cow::Owned(e.to_string()), e.status().map(|s| s.as_u16())),
+            Error::MySQLError(_) => (Status::InternalServerError, "database_error", Cow::Borrowed("Database error"), None),
+            Error::JSONError(e) => (Status::InternalServerError, "json_error", Cow::Owned(e.to_string()), None),
+            Error::TimeError(e) => (Status::InternalServerError, "time_error", Cow::Owned(e.to_string()), None),
+            Error::SomeError(reason) => (Status::InternalServerError, "internal_error", Cow::Borrowed(reason), None),
+        }
+    }
 }
 
 impl From<MySQLError> for Error {
@@ -73,17 +109,106 @@ impl From<ParseError> for Error {
     }
 }
 
+/// An error as reported to backend, without the request it happened in.
+#[derive(Serialize)]
+pub struct ErrorSummary {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_status: Option<u16>,
+}
+
+impl Error {
+    pub fn summary(&self) -> ErrorSummary {
+        let (_, code, message, upstream_status) = self.describe();
+        ErrorSummary { code, message: message.into_owned(), upstream_status }
+    }
+}
+
+/// Every error goes out in this shape, so backend can branch on `code`.
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_status: Option<u16>,
+    request_id: &'a str,
+}
+
+fn error_response<'r>(request: &Request, status: Status, code: &str, message: &str, upstream_status: Option<u16>) -> Response<'r> {
+    let body = ErrorBody { code, message, upstream_status, request_id: RequestId::of(request) };
+    Response::build()
+        .status(status)
+        .header(ContentType::JSON)
+        .sized_body(Cursor::new(serde_json::to_string(&body).expect("What?")))
+        .finalize()
+}
+
 impl<'r> Responder<'r> for Error {
-    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
         warn!("Caught error: {:?}", self);
-        match self {
-            Error::AlreadyExists => Err(Status::Conflict),
-            Error::NotFound => Err(Status::NotFound),
-            Error::UpstreamError(code, message) => Ok(Response::build()
-                .status(Status::from_code(code).unwrap_or(Status::InternalServerError))
-                .sized_body(Cursor::new(message))
-                .finalize()),
-            _ => Err(Status::InternalServerError)
-        }
+        let (status, code, message, upstream_status) = self.describe();
+        Ok(error_response(request, status, code, &message, upstream_status))
+    }
+}
+
+/// Why a request guard failed. Rocket hands catchers only the status, so guards leave it here.
+#[derive(Default)]
+struct GuardError(Mutex<Option<String>>);
+
+/// Keep `error` for the catcher, to be returned from a failing guard.
+pub fn guard_error(request: &Request, error: Error) -> Error {
+    let (_, _, message, _) = error.describe();
+    *request.local_cache(GuardError::default).0.lock().expect("What?") = Some(message.into_owned());
+    error
+}
+
+/// Failures that never reached a route, e.g. guards and malformed bodies.
+fn catch(status: Status, code: &'static str, request: &Request) -> Response<'static> {
+    let message = request.local_cache(GuardError::default).0.lock().expect("What?").clone();
+    error_response(request, status, code, message.as_ref().map_or(status.reason, String::as_str), None)
+}
+
+#[catch(400)]
+fn bad_request(request: &Request) -> Response<'static> { catch(Status::BadRequest, "bad_request", request) }
+
+#[catch(403)]
+fn forbidden(request: &Request) -> Response<'static> { catch(Status::Forbidden, "forbidden", request) }
+
+#[catch(404)]
+fn not_found(request: &Request) -> Response<'static> { catch(Status::NotFound, "not_found", request) }
+
+#[catch(409)]
+fn conflict(request: &Request) -> Response<'static> { catch(Status::Conflict, "already_exists", request) }
+
+#[catch(422)]
+fn unprocessable(request: &Request) -> Response<'static> { catch(Status::UnprocessableEntity, "unprocessable", request) }
+
+#[catch(500)]
+fn internal_error(request: &Request) -> Response<'static> { catch(Status::InternalServerError, "internal_error", request) }
+
+#[catch(503)]
+fn unavailable(request: &Request) -> Response<'static> { catch(Status::ServiceUnavailable, "unavailable", request) }
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![bad_request, forbidden, not_found, conflict, unprocessable, internal_error, unavailable]
+}
+
+static REQUEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Taken from `X-Request-Id` if backend sent one, otherwise made up. Sent back on every response.
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn of<'r>(request: &'r Request) -> &'r str {
+        &request.local_cache(|| {
+            RequestId(request.headers().get_one("X-Request-Id").map_or_else(|| {
+                format!("{:x}-{:x}", time::get_time().sec, REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed))
+            }, str::to_string))
+        }).0
     }
-}
\ No newline at end of file
+
+    pub fn header(request: &Request) -> Header<'static> {
+        Header::new("X-Request-Id", RequestId::of(request).to_string())
+    }
+}
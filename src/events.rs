@@ -0,0 +1,165 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Git server events other than pushes, e.g. for code review or CI.
+//!
+//! Every repo webhook subscribes to all of them, and each assignment picks which ones reach the backend,
+//! so a change applies to its existing repos right away. Webhooks made before these events were supported
+//! don't subscribe and are left as they are. Each kind goes to its own backend endpoint.
+
+use std::borrow::Cow;
+
+use ::{DBAccess, Error, GMResult, UuidRaw};
+use apis::APIFunction;
+use err::optional;
+use gitserver::GitServer;
+use outbox::Forwarder;
+
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    MergeRequest,
+    Note,
+    Pipeline,
+}
+
+impl EventKind {
+    /// Backend endpoint events of this kind are forwarded to.
+    fn path(self) -> &'static str {
+        match self {
+            EventKind::MergeRequest => "internal/merge_request",
+            EventKind::Note => "internal/note",
+            EventKind::Pipeline => "internal/pipeline",
+        }
+    }
+}
+
+/// Which events of an assignment are forwarded. Submission tags are unless turned off, the others only if turned on.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AssignmentEvents {
+    #[serde(default = "forwarded")]
+    pub tag_push: bool,
+    #[serde(default)]
+    pub merge_request: bool,
+    #[serde(default)]
+    pub note: bool,
+    #[serde(default)]
+    pub pipeline: bool,
+}
+
+fn forwarded() -> bool {
+    true
+}
+
+impl Default for AssignmentEvents {
+    fn default() -> Self {
+        AssignmentEvents { tag_push: true, merge_request: false, note: false, pipeline: false }
+    }
+}
+
+impl AssignmentEvents {
+    fn forwards(&self, kind: EventKind) -> bool {
+        match kind {
+            EventKind::MergeRequest => self.merge_request,
+            EventKind::Note => self.note,
+            EventKind::Pipeline => self.pipeline,
+        }
+    }
+}
+
+impl DBAccess {
+    /// The defaults if never set.
+    pub(crate) fn assignment_events(&mut self, assignment: u64) -> GMResult<AssignmentEvents> {
+        let events: Option<(bool, bool, bool, bool)> = self.0.first_exec(
+            r"SELECT tag_push, merge_request, note, pipeline FROM assignment_events WHERE assignment_id=?", (assignment, ))?;
+        Ok(events.map_or_else(AssignmentEvents::default, |(tag_push, merge_request, note, pipeline)| {
+            AssignmentEvents { tag_push, merge_request, note, pipeline }
+        }))
+    }
+
+    pub(crate) fn set_assignment_events(&mut self, assignment: u64, events: &AssignmentEvents) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM assignment_events WHERE assignment_id=?", (assignment, ))?;
+        self.0.prep_exec(r"INSERT INTO assignment_events(assignment_id, tag_push, merge_request, note, pipeline) VALUES (?, ?, ?, ?, ?)",
+                         (assignment, events.tag_push, events.merge_request, events.note, events.pipeline))?;
+
+        Ok(())
+    }
+
+    pub(crate) fn forget_assignment_events(&mut self, assignment: u64) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM assignment_events WHERE assignment_id=?", (assignment, ))?;
+
+        Ok(())
+    }
+
+    /// Events of the assignment behind a hook url, the defaults if it is gone.
+    pub(crate) fn hook_events(&mut self, assignment_uid: &UuidRaw) -> GMResult<AssignmentEvents> {
+        match optional(self.translate_uuid(assignment_uid))? {
+            Some(assignment) => self.assignment_events(assignment),
+            None => Ok(AssignmentEvents::default()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ForwardedEvent<'a> {
+    #[serde(skip)]
+    kind: EventKind,
+    assignment_uid: &'a str,
+    /// `None` if the repo is no longer known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repo_name: Option<String>,
+    /// `None` if the sender wasn't created through the middleware, e.g. an admin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender_email: Option<String>,
+    /// When the event was received, RFC 3339 in UTC
+    received_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    additional_data: Option<String>,
+    /// As sent by the git server
+    payload: &'a Value,
+}
+
+impl<'a> APIFunction for ForwardedEvent<'a> {
+    fn path(&self) -> Cow<str> {
+        Cow::Borrowed(self.kind.path())
+    }
+}
+
+/// Queue `payload` to the backend, unless the assignment doesn't want this kind. `None` if it was dropped.
+pub(crate) fn forward_event(kind: EventKind, forwarder: &mut Forwarder, git_server: &dyn GitServer,
+                            assignment_uid: &UuidRaw, assignment: &str, payload: &Value, additional_data: Option<String>)
+                            -> GMResult<Option<u64>> {
+    if !forwarder.db.hook_events(assignment_uid)?.forwards(kind) {
+        return Ok(None);
+    }
+    let source = git_server.parse_event(payload).ok_or(Error::BadRequest("Event without a repo"))?;
+    let sender_email = match source.sender_id {
+        Some(sender) => optional(forwarder.db.user_email(sender))?,
+        None => None,
+    };
+    let event = ForwardedEvent {
+        kind,
+        assignment_uid: assignment,
+        repo_name: optional(forwarder.db.repo_name(source.repo_id))?,
+        sender_email,
+        received_at: ::time::now_utc().rfc3339().to_string(),
+        additional_data,
+        payload,
+    };
+    forwarder.forward(&event).map(Some)
+}
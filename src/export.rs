@@ -0,0 +1,195 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Every repo of an assignment in a single archive, e.g. for similarity checks.
+//!
+//! Each repo is fetched from git server as tar.gz at its last commit before the deadline, and repacked under
+//! a folder named after the repo. `manifest.json` at the top lists what was exported.
+//! Commits and owners are looked up before responding, so the archive itself only fails on git server hiccups,
+//! which cut the download short.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+use ::{DBAccess, Error, GMResult, UuidRaw};
+use err::optional;
+use gitserver::{rfc3339, ArchiveFormat, GitServer, GitServerAPI};
+use jobs::now;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tar::{Archive, Builder, EntryType, Header};
+use zip::ZipWriter;
+use zip::write::FileOptions;
+
+/// Chunks in flight between the thread building the archive and the response.
+const CHUNKS: usize = 16;
+
+/// Exports are either tar.gz or zip.
+pub(crate) fn check_format(format: ArchiveFormat) -> GMResult<ArchiveFormat> {
+    match format {
+        ArchiveFormat::TarGz | ArchiveFormat::Zip => Ok(format),
+        _ => Err(Error::BadRequest("Only tar.gz and zip can be exported")),
+    }
+}
+
+/// A line of the manifest.
+#[derive(Serialize)]
+pub struct ExportedRepo {
+    name: String,
+    #[serde(skip)]
+    id: u64,
+    /// `None` if nothing was committed before the deadline
+    sha: Option<String>,
+    owners: Vec<String>,
+    deadline: Option<String>,
+}
+
+/// Pin every repo of the assignment to its last commit before the latest deadline of its owners.
+pub(crate) fn pin_repos(db: &mut DBAccess, git_server: &dyn GitServer, course_uid: &UuidRaw, assignment_uid: &UuidRaw)
+                        -> GMResult<Vec<ExportedRepo>> {
+    let mut repos = Vec::new();
+    for name in db.repo_names(course_uid, assignment_uid)? {
+        let id = db.translate_repo_id(course_uid, assignment_uid, &name)?;
+        let deadline = db.latest_deadline(id)?;
+        let sha = git_server.last_commit(id, None, deadline)?;
+        let mut owners = Vec::new();
+        for member in git_server.list_repo_members(id)? {
+            if let Some(email) = optional(db.user_email(member))? {
+                owners.push(email);
+            }
+        }
+        repos.push(ExportedRepo { name, id, sha, owners, deadline: deadline.map(rfc3339) });
+    }
+    Ok(repos)
+}
+
+/// The archive, built as it is read.
+pub(crate) fn export(git_server: GitServerAPI, repos: Vec<ExportedRepo>, format: ArchiveFormat) -> impl Read {
+    let (sender, receiver) = sync_channel(CHUNKS);
+    thread::spawn(move || {
+        let result = match format {
+            ArchiveFormat::Zip => write_zip(&*git_server, &repos, &sender),
+            // tar.gz, see `check_format`
+            _ => write_tar_gz(&*git_server, &repos, BufWriter::new(ChunkWriter(sender.clone()))),
+        };
+        if let Err(e) = result {
+            warn!("Export failed: {:?}", e);
+            // the response may be gone already
+            let _ = sender.send(Err(e));
+        }
+    });
+    ChunkReader { receiver, chunk: Cursor::new(Vec::new()) }
+}
+
+fn write_tar_gz<W: Write>(git_server: &dyn GitServer, repos: &[ExportedRepo], out: W) -> io::Result<()> {
+    let mut tar = Builder::new(GzEncoder::new(out, Compression::default()));
+    add_repos(git_server, repos, &mut |path, mode, size, data| {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(mode);
+        header.set_size(size);
+        header.set_mtime(now());
+        tar.append_data(&mut header, path, data)
+    })?;
+    tar.into_inner()?.finish()?.flush()
+}
+
+/// Zip needs to seek back over each file, so it is put together in a temporary file first.
+fn write_zip(git_server: &dyn GitServer, repos: &[ExportedRepo], sender: &SyncSender<io::Result<Vec<u8>>>) -> io::Result<()> {
+    let path = ::std::env::temp_dir().join(format!("export-{}-{:?}.zip", now(), thread::current().id()));
+    let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+    // gone once closed
+    fs::remove_file(&path)?;
+    let mut zip = ZipWriter::new(file);
+    add_repos(git_server, repos, &mut |path, mode, _, data| {
+        zip.start_file(path, FileOptions::default().unix_permissions(mode))?;
+        io::copy(data, &mut zip).map(|_| ())
+    })?;
+    let mut file = zip.finish()?;
+    file.seek(SeekFrom::Start(0))?;
+    io::copy(&mut file, &mut BufWriter::new(ChunkWriter(sender.clone())))?;
+    Ok(())
+}
+
+/// Puts a file into the archive, given `(path, mode, size, content)`.
+type AddFile<'a> = dyn FnMut(&str, u32, u64, &mut dyn Read) -> io::Result<()> + 'a;
+
+/// Feed `add` with the manifest, then every file of every repo.
+fn add_repos(git_server: &dyn GitServer, repos: &[ExportedRepo], add: &mut AddFile) -> io::Result<()> {
+    let manifest = serde_json::to_vec_pretty(repos)?;
+    add("manifest.json", 0o644, manifest.len() as u64, &mut &manifest[..])?;
+    for repo in repos {
+        let sha = match repo.sha {
+            Some(ref sha) => sha,
+            None => continue,
+        };
+        let archive = git_server.archive(repo.id, ArchiveFormat::TarGz, Some(sha))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to fetch repo {}: {:?}", repo.name, e)))?;
+        let mut archive = Archive::new(GzDecoder::new(archive));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type() != EntryType::Regular {
+                continue;
+            }
+            // git server puts everything under a folder named after the repo and commit
+            let path = entry.path()?.components().skip(1).collect::<::std::path::PathBuf>();
+            let path = format!("{}/{}", repo.name, path.to_string_lossy());
+            let mode = entry.header().mode()?;
+            let size = entry.header().size()?;
+            add(&path, mode, size, &mut entry)?;
+        }
+    }
+    Ok(())
+}
+
+struct ChunkWriter(SyncSender<io::Result<Vec<u8>>>);
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(Ok(buf.to_vec())).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Download cancelled"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ChunkReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.chunk.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.receiver.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk?),
+                // all sent
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
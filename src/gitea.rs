@@ -0,0 +1,667 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Gitea (and Forgejo, which keeps the same API) as a git server.
+//!
+//! Gitea has no nested organizations, so an assignment is an organization of its own named
+//! `<course>-<assignment>`. Gitea addresses almost everything by name, while we keep ids in DB,
+//! so names are looked up on every call. Organizations can't be looked up by id, so their names
+//! are kept in DB as they are created.
+
+use std::collections::BTreeSet;
+
+use ::{DBAccess, DBAccessPool, Error, GMResult};
+use apis::APIAccessor;
+use err::optional;
+use gitserver::*;
+
+use percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use reqwest::{Client, ClientBuilder, Method, Response};
+use reqwest::header::{HeaderMap, HeaderValue};
+use url::Url;
+use url::form_urlencoded::Serializer;
+
+use serde_json::Value;
+
+const PAGE_SIZE: u32 = 50;
+
+pub struct GiteaAPI {
+    client: Client,
+    _base_url: Url,
+    pool: DBAccessPool,
+}
+
+impl GiteaAPI {
+    pub(crate) fn new(token: &str, base_url: Url, pool: DBAccessPool) -> GiteaAPI {
+        let mut default_header = HeaderMap::new();
+        default_header.insert("Authorization", HeaderValue::from_str(&format!("token {}", token)).expect("Token malformed"));
+        GiteaAPI {
+            client: ClientBuilder::new()
+                .default_headers(default_header)
+                .build().expect("What?"),
+            _base_url: base_url,
+            pool,
+        }
+    }
+
+    fn all_pages(&self, path: &str) -> GMResult<Vec<Value>> {
+        let mut ret = Vec::new();
+        let separator = if path.contains('?') { '&' } else { '?' };
+        for page in 1.. {
+            let res: Value = self.call_no_body(Method::GET, &format!("{}{}page={}&limit={}", path, separator, page, PAGE_SIZE))?.json()?;
+            let items = res.as_array().expect("Gitea schema changed");
+            ret.extend(items.iter().cloned());
+            if items.len() < PAGE_SIZE as usize {
+                break;
+            }
+        }
+        Ok(ret)
+    }
+
+    fn username(&self, id: u64) -> GMResult<String> {
+        let res: Value = self.call_no_body(Method::GET, &format!("users/search?uid={}", id))?.json()?;
+        res["data"].as_array().expect("Gitea schema changed").first()
+            .map(|user| user["login"].as_str().expect("Gitea schema changed").to_string())
+            .ok_or(Error::NotFound)
+    }
+
+    fn org_name(&self, id: u64) -> GMResult<String> {
+        let mut db = self.pool.connect()?;
+        if let Some(name) = db.gitea_org_name(id)? {
+            return Ok(name);
+        }
+        // created before names were kept
+        let name = self.all_pages("admin/orgs")?.iter()
+            .find(|org| org["id"].as_u64() == Some(id))
+            .map(|org| org["username"].as_str().expect("Gitea schema changed").to_string())
+            .ok_or(Error::NotFound)?;
+        db.remember_gitea_org(id, &name)?;
+        Ok(name)
+    }
+
+    fn repo(&self, id: u64) -> GMResult<Value> {
+        Ok(self.call_no_body(Method::GET, &format!("repositories/{}", id))?.json()?)
+    }
+
+    fn repo_path(&self, id: u64) -> GMResult<String> {
+        Ok(self.repo(id)?["full_name"].as_str().expect("Gitea schema changed").to_string())
+    }
+
+    /// Default branch and full name of the repo, `Err(Error::NotFound)` if it is empty.
+    fn default_branch(&self, id: u64) -> GMResult<(String, String)> {
+        let repo = self.repo(id)?;
+        if repo["empty"].as_bool() == Some(true) {
+            return Err(Error::NotFound);
+        }
+        Ok((repo["default_branch"].as_str().expect("Gitea schema changed").to_string(),
+            repo["full_name"].as_str().expect("Gitea schema changed").to_string()))
+    }
+
+    /// Id of the team granting `access_level` in `org`, `None` if there is none yet.
+    fn find_team(&self, org: &str, access_level: AccessLevel) -> GMResult<Option<u64>> {
+        let name = team_of(access_level).0;
+        let teams = self.all_pages(&format!("orgs/{}/teams", org))?;
+        Ok(teams.iter().find(|t| t["name"].as_str() == Some(name))
+            .map(|team| team["id"].as_u64().expect("Gitea schema changed")))
+    }
+
+    /// Teams are the only way to grant access on an organization.
+    /// Find or create the one for given access level.
+    fn team(&self, org: &str, access_level: AccessLevel) -> GMResult<u64> {
+        if let Some(team) = self.find_team(org, access_level)? {
+            return Ok(team);
+        }
+        let (name, permission) = team_of(access_level);
+        let body = json!({
+            "name": name,
+            "permission": permission,
+            "includes_all_repositories": true,
+            "units": ["repo.code", "repo.issues", "repo.pulls", "repo.releases", "repo.wiki"],
+        });
+        let res: Value = self.execute(Method::POST, &format!("orgs/{}/teams", org), &body, None)?.json()?;
+        Ok(res["id"].as_u64().expect("Gitea schema changed"))
+    }
+
+    fn create_org(&self, name: &str) -> GMResult<u64> {
+        let body = json!({ "username": name, "visibility": "private" });
+        let res: Value = self.execute(Method::POST, "orgs", &body, None)?.json()?;
+        let id = res["id"].as_u64().expect("Gitea schema changed");
+        self.pool.connect()?.remember_gitea_org(id, name)?;
+        Ok(id)
+    }
+
+    fn sub_orgs(&self, org: &str) -> GMResult<Vec<Value>> {
+        let prefix = format!("{}-", org);
+        Ok(self.all_pages("admin/orgs")?.into_iter()
+            .filter(|o| o["username"].as_str().map_or(false, |n| n.starts_with(&prefix)))
+            .collect())
+    }
+}
+
+/// A path in a repo, each folder encoded on its own.
+fn encode_path(path: &str) -> String {
+    path.split('/').map(|segment| utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string()).collect::<Vec<_>>().join("/")
+}
+
+/// A ref as a single path segment. It mustn't lead anywhere else, nor run into `...` between two refs.
+fn encode_ref(git_ref: &str) -> GMResult<String> {
+    if git_ref.contains("..") || git_ref.contains('?') || git_ref.contains('#') || git_ref.starts_with('/') {
+        return Err(Error::BadRequest("Invalid ref"));
+    }
+    Ok(utf8_percent_encode(git_ref, PATH_SEGMENT_ENCODE_SET).to_string())
+}
+
+/// Name and permission of the team granting `access_level`.
+fn team_of(access_level: AccessLevel) -> (&'static str, &'static str) {
+    match access_level {
+        AccessLevel::Owner => ("Owners", "owner"),
+        AccessLevel::Maintainer => ("Maintainers", "admin"),
+        AccessLevel::Developer => ("Developers", "write"),
+        AccessLevel::Reporter => ("Reporters", "read"),
+    }
+}
+
+fn new_repo(res: &Value) -> Repo {
+    Repo {
+        id: res["id"].as_u64().expect("Gitea schema changed"),
+        ssh_url: res["ssh_url"].as_str().expect("Gitea schema changed").to_string(),
+    }
+}
+
+impl APIAccessor for GiteaAPI {
+    #[inline]
+    fn client(&self) -> &Client {
+        &self.client
+    }
+
+    #[inline]
+    fn base(&self) -> &Url {
+        &self._base_url
+    }
+}
+
+impl GitServer for GiteaAPI {
+    fn create_user(&self, email: &str, username: &str, password: &str) -> GMResult<u64> {
+        let body = json!({
+            "email": email,
+            "username": username,
+            "full_name": username,
+            "password": password,
+            "must_change_password": false,
+        });
+        let res: Value = self.execute(Method::POST, "admin/users", &body, None)?.json()?;
+        Ok(res["id"].as_u64().expect("Gitea schema changed"))
+    }
+
+    fn replace_key(&self, user: u64, key: &str) -> GMResult<()> {
+        let username = self.username(user)?;
+        for old in self.all_pages(&format!("users/{}/keys", username))? {
+            let key_id = old["id"].as_u64().expect("Gitea schema changed");
+            self.call_no_body(Method::DELETE, &format!("admin/users/{}/keys/{}", username, key_id))?;
+        }
+        let body = json!({ "title": "key", "key": key });
+        self.execute(Method::POST, &format!("admin/users/{}/keys", username), &body, None)?;
+        Ok(())
+    }
+
+    fn create_course(&self, name: &str) -> GMResult<u64> {
+        self.create_org(name)
+    }
+
+    fn create_assignment(&self, course: u64, name: &str) -> GMResult<u64> {
+        let course = self.org_name(course)?;
+        self.create_org(&format!("{}-{}", course, name))
+    }
+
+    fn list_assignments(&self, course: u64) -> GMResult<Vec<u64>> {
+        let course = self.org_name(course)?;
+        Ok(self.sub_orgs(&course)?.iter()
+            .map(|o| o["id"].as_u64().expect("Gitea schema changed"))
+            .collect())
+    }
+
+    fn get_group(&self, group: u64) -> GMResult<()> {
+        let org = self.org_name(group)?;
+        match self.call_no_body(Method::GET, &format!("orgs/{}", org)) {
+            Err(Error::NotFound) => {
+                self.pool.connect()?.forget_gitea_org(group)?;
+                Err(Error::NotFound)
+            }
+            res => res.map(|_| ()),
+        }
+    }
+
+    fn delete_group(&self, group: u64) -> GMResult<()> {
+        let org = self.org_name(group)?;
+        // gitea refuses to delete a non-empty organization
+        for sub in self.sub_orgs(&org)? {
+            self.delete_group(sub["id"].as_u64().expect("Gitea schema changed"))?;
+        }
+        for repo in self.all_pages(&format!("orgs/{}/repos", org))? {
+            let full_name = repo["full_name"].as_str().expect("Gitea schema changed");
+            self.call_no_body(Method::DELETE, &format!("repos/{}", full_name))?;
+        }
+        self.call_no_body(Method::DELETE, &format!("orgs/{}", org))?;
+        self.pool.connect()?.forget_gitea_org(group)?;
+        Ok(())
+    }
+
+    fn add_group_member(&self, group: u64, user: u64, access_level: AccessLevel) -> GMResult<()> {
+        let org = self.org_name(group)?;
+        let team = self.team(&org, access_level)?;
+        let username = self.username(user)?;
+        self.call_no_body(Method::PUT, &format!("teams/{}/members/{}", team, username))?;
+        Ok(())
+    }
+
+    fn update_group_member(&self, group: u64, user: u64, access_level: AccessLevel) -> GMResult<()> {
+        // access comes from the team, so move to another one
+        self.remove_group_member(group, user)?;
+        self.add_group_member(group, user, access_level)
+    }
+
+    fn remove_group_member(&self, group: u64, user: u64) -> GMResult<()> {
+        let org = self.org_name(group)?;
+        let username = self.username(user)?;
+        for team in self.all_pages(&format!("orgs/{}/teams", org))? {
+            let team = team["id"].as_u64().expect("Gitea schema changed");
+            self.call_no_body(Method::DELETE, &format!("teams/{}/members/{}", team, username))?;
+        }
+        Ok(())
+    }
+
+    fn list_group_members(&self, group: u64, access_level: AccessLevel) -> GMResult<Vec<u64>> {
+        let org = self.org_name(group)?;
+        let team = match self.find_team(&org, access_level)? {
+            Some(team) => team,
+            None => return Ok(Vec::new()),
+        };
+        Ok(self.all_pages(&format!("teams/{}/members", team))?.iter()
+            .map(|user| user["id"].as_u64().expect("Gitea schema changed"))
+            .collect())
+    }
+
+    fn create_repo(&self, assignment: u64, name: &str) -> GMResult<Repo> {
+        let org = self.org_name(assignment)?;
+        let body = json!({ "name": name, "private": true });
+        let res: Value = self.execute(Method::POST, &format!("orgs/{}/repos", org), &body, None)?.json()?;
+        Ok(new_repo(&res))
+    }
+
+    fn create_repo_from(&self, assignment: u64, name: &str, template: u64, keep_history: bool) -> GMResult<Repo> {
+        let org = self.org_name(assignment)?;
+        let template = self.repo_path(template)?;
+        let res: Value = if keep_history {
+            let body = json!({ "organization": org, "name": name });
+            self.execute(Method::POST, &format!("repos/{}/forks", template), &body, None)?.json()?
+        } else {
+            // only template repos can be generated from, which gives a single commit
+            let source: Value = self.call_no_body(Method::GET, &format!("repos/{}", template))?.json()?;
+            let was_template = source["template"].as_bool().unwrap_or(false);
+            if !was_template {
+                self.execute(Method::PATCH, &format!("repos/{}", template), &json!({ "template": true }), None)?;
+            }
+            let body = json!({ "owner": org, "name": name, "private": true, "git_content": true });
+            let generated = self.execute(Method::POST, &format!("repos/{}/generate", template), &body, None)
+                .and_then(|mut res| Ok(res.json()?));
+            if !was_template {
+                if let Err(e) = self.execute(Method::PATCH, &format!("repos/{}", template), &json!({ "template": false }), None) {
+                    warn!("Failed to unmark {} as template: {:?}", template, e);
+                }
+            }
+            generated?
+        };
+        Ok(new_repo(&res))
+    }
+
+    fn find_repo(&self, path: &str) -> GMResult<u64> {
+        let res: Value = self.call_no_body(Method::GET, &format!("repos/{}", path))?.json()?;
+        Ok(res["id"].as_u64().expect("Gitea schema changed"))
+    }
+
+    fn changed_files(&self, repo: u64, base: Option<&str>, head: &str) -> GMResult<Vec<FileChange>> {
+        let (default_branch, path) = self.default_branch(repo)?;
+        let range = format!("{}...{}", encode_ref(base.unwrap_or(&default_branch))?, encode_ref(head)?);
+        let compare: Value = self.call_no_body(Method::GET, &format!("repos/{}/compare/{}", path, range))?.json()?;
+        // commits only list new names of renamed files
+        let mut paths = BTreeSet::new();
+        for commit in compare["commits"].as_array().expect("Gitea schema changed") {
+            for file in commit["files"].as_array().into_iter().flatten() {
+                paths.insert(file["filename"].as_str().expect("Gitea schema changed").to_string());
+            }
+        }
+        paths.into_iter()
+            .map(|file| {
+                let url = format!("repos/{}/raw/{}?{}", path, encode_path(&file),
+                                  Serializer::new(String::new()).append_pair("ref", head).finish());
+                let content = match optional(self.call_no_body(Method::GET, &url))? {
+                    Some(mut res) => {
+                        let mut content = Vec::new();
+                        res.copy_to(&mut content)?;
+                        Some(content)
+                    }
+                    None => None,
+                };
+                Ok(FileChange { path: file, content })
+            })
+            .collect()
+    }
+
+    fn push_branch(&self, repo: u64, branch: &str, message: &str, changes: &[FileChange]) -> GMResult<bool> {
+        let (start, path) = self.default_branch(repo)?;
+        let encoded = utf8_percent_encode(branch, PATH_SEGMENT_ENCODE_SET);
+        if optional(self.call_no_body(Method::GET, &format!("repos/{}/branches/{}", path, encoded)))?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        let mut files = Vec::new();
+        for change in changes {
+            // updating or deleting a file takes its current sha
+            let url = format!("repos/{}/contents/{}?{}", path, encode_path(&change.path),
+                              Serializer::new(String::new()).append_pair("ref", &start).finish());
+            let sha = match optional(self.call_no_body(Method::GET, &url))? {
+                Some(mut res) => {
+                    let res: Value = res.json()?;
+                    Some(res["sha"].as_str().expect("Gitea schema changed").to_string())
+                }
+                None => None,
+            };
+            match (&change.content, sha) {
+                (Some(content), Some(sha)) => files.push(json!({ "operation": "update", "path": change.path, "content": base64::encode(content), "sha": sha })),
+                (Some(content), None) => files.push(json!({ "operation": "create", "path": change.path, "content": base64::encode(content) })),
+                (None, Some(sha)) => files.push(json!({ "operation": "delete", "path": change.path, "sha": sha })),
+                (None, None) => {}
+            }
+        }
+        if files.is_empty() {
+            return Ok(false);
+        }
+        let body = json!({ "branch": start, "new_branch": branch, "message": message, "files": files });
+        self.execute(Method::POST, &format!("repos/{}/contents", path), &body, None)?;
+        Ok(true)
+    }
+
+    fn open_merge_request(&self, repo: u64, branch: &str, title: &str, description: &str) -> GMResult<String> {
+        let (base, path) = self.default_branch(repo)?;
+        let body = json!({ "head": branch, "base": base, "title": title, "body": description });
+        let res: Value = self.execute(Method::POST, &format!("repos/{}/pulls", path), &body, None)?.json()?;
+        Ok(res["html_url"].as_str().expect("Gitea schema changed").to_string())
+    }
+
+    fn get_repo(&self, repo: u64) -> GMResult<()> {
+        self.repo(repo).map(|_| ())
+    }
+
+    fn delete_repo(&self, repo: u64) -> GMResult<()> {
+        let path = self.repo_path(repo)?;
+        self.call_no_body(Method::DELETE, &format!("repos/{}", path))?;
+        Ok(())
+    }
+
+    fn create_webhook(&self, repo: u64, url: &str, token: &str) -> GMResult<()> {
+        let path = self.repo_path(repo)?;
+        let mut body = json!({
+            "type": "gitea",
+            "config": { "url": url, "content_type": "json" },
+            // workflow runs of gitea actions are its pipelines
+            "events": ["push", "create", "pull_request", "issue_comment", "workflow_run"],
+            "active": true,
+        });
+        if !token.is_empty() {
+            body["authorization_header"] = Value::String(format!("Bearer {}", token));
+        }
+        self.execute(Method::POST, &format!("repos/{}/hooks", path), &body, None)?;
+        Ok(())
+    }
+
+    fn protect_branches(&self, repo: u64) -> GMResult<()> {
+        let path = self.repo_path(repo)?;
+        // protected branches in gitea reject force push by default
+        let body = json!({ "rule_name": "*", "enable_push": true });
+        self.execute(Method::POST, &format!("repos/{}/branch_protections", path), &body, None)?;
+        Ok(())
+    }
+
+    fn add_repo_member(&self, repo: u64, user: u64, access_level: AccessLevel, _expires_at: Option<&str>) -> GMResult<()> {
+        // gitea collaborators never expire
+        let path = self.repo_path(repo)?;
+        let username = self.username(user)?;
+        let permission = match access_level {
+            AccessLevel::Reporter => "read",
+            AccessLevel::Developer | AccessLevel::Maintainer => "write",
+            AccessLevel::Owner => "admin",
+        };
+        let body = json!({ "permission": permission });
+        self.execute(Method::PUT, &format!("repos/{}/collaborators/{}", path, username), &body, None)?;
+        Ok(())
+    }
+
+    fn remove_repo_member(&self, repo: u64, user: u64) -> GMResult<()> {
+        let path = self.repo_path(repo)?;
+        let username = self.username(user)?;
+        self.call_no_body(Method::DELETE, &format!("repos/{}/collaborators/{}", path, username))?;
+        Ok(())
+    }
+
+    fn list_repo_members(&self, repo: u64) -> GMResult<Vec<u64>> {
+        let path = self.repo_path(repo)?;
+        Ok(self.all_pages(&format!("repos/{}/collaborators", path))?.iter()
+            .map(|user| user["id"].as_u64().expect("Gitea schema changed"))
+            .collect())
+    }
+
+    fn update_repo_member(&self, repo: u64, user: u64, access_level: AccessLevel, _expires_at: Option<&str>) -> GMResult<()> {
+        // adding an existing collaborator changes the permission
+        self.add_repo_member(repo, user, access_level, None)
+    }
+
+    fn lock_branches(&self, repo: u64) -> GMResult<()> {
+        let path = self.repo_path(repo)?;
+        let body = json!({ "enable_push": false });
+        self.execute(Method::PATCH, &format!("repos/{}/branch_protections/*", path), &body, None)?;
+        Ok(())
+    }
+
+    fn unlock_branches(&self, repo: u64) -> GMResult<()> {
+        let path = self.repo_path(repo)?;
+        let body = json!({ "enable_push": true });
+        self.execute(Method::PATCH, &format!("repos/{}/branch_protections/*", path), &body, None)?;
+        Ok(())
+    }
+
+    fn archive(&self, repo: u64, format: ArchiveFormat, git_ref: Option<&str>) -> GMResult<Response> {
+        let format = match format {
+            ArchiveFormat::TarGz | ArchiveFormat::Zip => format.extension(),
+            _ => return Err(Error::BadRequest("Gitea only archives as tar.gz or zip")),
+        };
+        let repo = self.repo(repo)?;
+        let path = repo["full_name"].as_str().expect("Gitea schema changed");
+        let git_ref = git_ref.or_else(|| repo["default_branch"].as_str()).expect("Gitea schema changed");
+        self.call_no_body(Method::GET, &format!("repos/{}/archive/{}.{}", path, encode_ref(git_ref)?, format))
+    }
+
+    fn compare(&self, _repo: u64, _from: &str, _to: &str) -> GMResult<Vec<FileDiff>> {
+        // its compare API lists changed files, without any diff
+        Err(Error::BadRequest("Gitea can't diff between commits"))
+    }
+
+    fn tags(&self, repo: u64) -> GMResult<Vec<Tag>> {
+        let path = self.repo_path(repo)?;
+        Ok(self.all_pages(&format!("repos/{}/tags", path))?.iter()
+            .map(|tag| Tag {
+                name: tag["name"].as_str().expect("Gitea schema changed").to_string(),
+                sha: tag["commit"]["sha"].as_str().expect("Gitea schema changed").to_string(),
+                message: tag["message"].as_str().filter(|message| !message.is_empty()).map(str::to_string),
+                committed_at: normalize_date(tag["commit"]["created"].as_str().expect("Gitea schema changed")).expect("Gitea schema changed"),
+            })
+            .collect())
+    }
+
+    fn tree(&self, repo: u64, git_ref: Option<&str>, path: Option<&str>) -> GMResult<Vec<TreeEntry>> {
+        let repo = self.repo(repo)?;
+        if repo["empty"].as_bool() == Some(true) {
+            return match path {
+                Some(_) => Err(Error::NotFound),
+                None => Ok(Vec::new()),
+            };
+        }
+        let full_name = repo["full_name"].as_str().expect("Gitea schema changed");
+        let mut url = format!("repos/{}/contents/{}", full_name, encode_path(path.unwrap_or("")));
+        if let Some(git_ref) = git_ref {
+            url += &format!("?{}", Serializer::new(String::new()).append_pair("ref", git_ref).finish());
+        }
+        let contents: Value = self.call_no_body(Method::GET, &url)?.json()?;
+        // a file is not a folder
+        let contents = contents.as_array().ok_or(Error::NotFound)?;
+        Ok(contents.iter()
+            .map(|entry| TreeEntry {
+                name: entry["name"].as_str().expect("Gitea schema changed").to_string(),
+                path: entry["path"].as_str().expect("Gitea schema changed").to_string(),
+                entry_type: match entry["type"].as_str() {
+                    Some("dir") => EntryType::Dir,
+                    Some("symlink") => EntryType::Symlink,
+                    Some("submodule") => EntryType::Submodule,
+                    _ => EntryType::File,
+                },
+            })
+            .collect())
+    }
+
+    fn file(&self, repo: u64, path: &str, git_ref: Option<&str>) -> GMResult<Response> {
+        let mut url = format!("repos/{}/raw/{}", self.repo_path(repo)?, encode_path(path));
+        if let Some(git_ref) = git_ref {
+            url += &format!("?{}", Serializer::new(String::new()).append_pair("ref", git_ref).finish());
+        }
+        self.call_no_body(Method::GET, &url)
+    }
+
+    fn last_commit(&self, repo: u64, git_ref: Option<&str>, before: Option<u64>) -> GMResult<Option<String>> {
+        let (default_branch, path) = match self.default_branch(repo) {
+            Ok(repo) => repo,
+            Err(Error::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let branch = git_ref.unwrap_or(&default_branch);
+        let mut query = Serializer::new(String::new());
+        query.append_pair("sha", branch).append_pair("limit", "1").append_pair("stat", "false");
+        if let Some(before) = before {
+            query.append_pair("until", &rfc3339(before));
+        }
+        let commits: Value = self.call_no_body(Method::GET, &format!("repos/{}/commits?{}", path, query.finish()))?.json()?;
+        commits.as_array().ok_or_else(Error::schema_changed)?.first()
+            .map(|commit| commit["sha"].as_str().map(str::to_string).ok_or_else(Error::schema_changed))
+            .transpose()
+    }
+
+    fn commits(&self, repo: u64, filter: &CommitFilter, page: u32) -> GMResult<CommitPage> {
+        let repo = self.repo(repo)?;
+        if repo["empty"].as_bool() == Some(true) {
+            return Ok(CommitPage { commits: Vec::new(), next: None });
+        }
+        let path = repo["full_name"].as_str().ok_or_else(Error::schema_changed)?;
+        let mut query = Serializer::new(String::new());
+        query.append_pair("limit", &filter.per_page.to_string()).append_pair("page", &page.to_string()).append_pair("stat", "false");
+        if let Some(git_ref) = filter.git_ref {
+            query.append_pair("sha", git_ref);
+        }
+        if let Some(file) = filter.path {
+            query.append_pair("path", file);
+        }
+        if let Some(since) = filter.since {
+            query.append_pair("since", &rfc3339(since));
+        }
+        if let Some(until) = filter.until {
+            query.append_pair("until", &rfc3339(until));
+        }
+        let mut body = self.call_no_body(Method::GET, &format!("repos/{}/commits?{}", path, query.finish()))?;
+        let next = next_link(&body).map(|_| page + 1);
+        let commits: Value = body.json()?;
+        let commits = commits.as_array().ok_or_else(Error::schema_changed)?.iter().map(commit).collect::<GMResult<_>>()?;
+        Ok(CommitPage { commits, next })
+    }
+
+    fn parse_push<'a>(&self, payload: &'a Value) -> Option<PushEvent<'a>> {
+        Some(PushEvent {
+            upstream: payload["repository"]["ssh_url"].as_str()?,
+            repo_id: payload["repository"]["id"].as_u64()?,
+            pusher_id: payload["pusher"]["id"].as_u64()?,
+            git_ref: payload["ref"].as_str()?,
+            after: payload["after"].as_str()?,
+        })
+    }
+
+    /// Gitea tells about new tags in `create` events.
+    fn parse_tag_push<'a>(&self, payload: &'a Value) -> Option<TagPushEvent<'a>> {
+        if payload["ref_type"] != "tag" {
+            return None;
+        }
+        Some(TagPushEvent {
+            upstream: payload["repository"]["ssh_url"].as_str()?,
+            repo_id: payload["repository"]["id"].as_u64()?,
+            pusher_id: payload["sender"]["id"].as_u64()?,
+            tag: payload["ref"].as_str()?,
+            sha: Some(payload["sha"].as_str()?),
+        })
+    }
+
+    fn parse_event(&self, payload: &Value) -> Option<EventSource> {
+        Some(EventSource {
+            repo_id: payload["repository"]["id"].as_u64()?,
+            sender_id: payload["sender"]["id"].as_u64(),
+        })
+    }
+
+    fn health(&self) -> GMResult<()> {
+        self.call_no_body(Method::GET, "../healthz")?;
+        Ok(())
+    }
+}
+
+fn commit(commit: &Value) -> GMResult<Commit> {
+    let field = |value: &Value| value.as_str().map(str::to_string).ok_or_else(Error::schema_changed);
+    let date = |value: &Value| value.as_str().and_then(normalize_date).ok_or_else(Error::schema_changed);
+    let details = &commit["commit"];
+    Ok(Commit {
+        sha: field(&commit["sha"])?,
+        message: field(&details["message"])?,
+        author_name: field(&details["author"]["name"])?,
+        author_email: field(&details["author"]["email"])?,
+        author: None,
+        authored_at: date(&details["author"]["date"])?,
+        committed_at: date(&details["committer"]["date"])?,
+        parents: commit["parents"].as_array().ok_or_else(Error::schema_changed)?.iter()
+            .map(|parent| field(&parent["sha"]))
+            .collect::<GMResult<_>>()?,
+    })
+}
+
+impl DBAccess {
+    fn gitea_org_name(&mut self, org: u64) -> GMResult<Option<String>> {
+        Ok(self.0.first_exec(r"SELECT name FROM gitea_orgs WHERE org_id=?", (org, ))?)
+    }
+
+    fn remember_gitea_org(&mut self, org: u64, name: &str) -> GMResult<()> {
+        self.0.prep_exec(r"INSERT INTO gitea_orgs(org_id, name) VALUES (?, ?)", (org, name))?;
+
+        Ok(())
+    }
+
+    fn forget_gitea_org(&mut self, org: u64) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM gitea_orgs WHERE org_id=?", (org, ))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,799 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ::{Error, GMResult};
+use apis::{APIAccessor, APIFunction};
+use err::optional;
+use gitserver::*;
+
+use percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use reqwest::{Client, ClientBuilder, Method, Response};
+use reqwest::header::{HeaderMap, HeaderValue};
+use url::Url;
+use url::form_urlencoded::Serializer;
+
+use rocket_contrib::json::JsonValue;
+use serde_json::Value;
+
+#[derive(Serialize)]
+struct CreateUserGitLab<'a> {
+    email: &'a str,
+    username: &'a str,
+    password: &'a str,
+    name: &'a str,
+}
+
+impl<'a> APIFunction for CreateUserGitLab<'a> {
+    fn path(&self) -> Cow<str> {
+        Cow::Borrowed("users")
+    }
+}
+
+#[derive(Serialize)]
+struct AddKeyGitlab<'a> {
+    #[serde(skip)]
+    id: u64,
+    title: &'static str,
+    // we are only giving one PK, so use default
+    key: &'a str,
+}
+
+impl<'a> AddKeyGitlab<'a> {
+    fn new(id: u64, key: &'a str) -> Self {
+        AddKeyGitlab { id, title: "key", key }
+    }
+}
+
+impl<'a> APIFunction for AddKeyGitlab<'a> {
+    fn path(&self) -> Cow<str> {
+        Cow::Owned(format!("users/{}/keys", self.id))
+    }
+}
+
+#[derive(Serialize)]
+struct CreateGroupGitlab<'a> {
+    name: &'a str,
+    path: &'a str,
+    visibility: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_id: Option<u64>,
+}
+
+impl<'a> CreateGroupGitlab<'a> {
+    fn new(name: &'a str, parent_id: Option<u64>) -> Self {
+        CreateGroupGitlab { name, path: name, visibility: "private", parent_id }
+    }
+}
+
+impl<'a> APIFunction for CreateGroupGitlab<'a> {
+    fn path(&self) -> Cow<str> {
+        Cow::Borrowed("groups")
+    }
+}
+
+#[derive(Serialize)]
+struct AddUserToGroupGitlab {
+    #[serde(skip)]
+    group_id: u64,
+    user_id: u64,
+    access_level: u8,
+}
+
+impl AddUserToGroupGitlab {
+    fn new(user_id: u64, group_id: u64, access_level: AccessLevel) -> Self {
+        AddUserToGroupGitlab { group_id, user_id, access_level: gitlab_access_level(access_level) }
+    }
+}
+
+impl APIFunction for AddUserToGroupGitlab {
+    fn path(&self) -> Cow<str> {
+        Cow::Owned(format!("groups/{}/members", self.group_id))
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Visibility { Public, Internal, Private }
+
+#[derive(Serialize)]
+struct CreateRepoGitlab<'a> {
+    name: &'a str,
+    namespace_id: u64,
+    visibility: Visibility,
+}
+
+impl<'a> CreateRepoGitlab<'a> {
+    fn new(name: &'a str, assignment_id: u64) -> Self {
+        CreateRepoGitlab { name, namespace_id: assignment_id, visibility: Visibility::Private }
+    }
+}
+
+impl<'a> APIFunction for CreateRepoGitlab<'a> {
+    fn path(&self) -> Cow<str> {
+        Cow::Borrowed("projects/")
+    }
+}
+
+#[derive(Serialize)]
+struct ForkRepoGitlab<'a> {
+    #[serde(skip)]
+    template_id: u64,
+    name: &'a str,
+    path: &'a str,
+    namespace_id: u64,
+    visibility: Visibility,
+}
+
+impl<'a> ForkRepoGitlab<'a> {
+    fn new(template_id: u64, name: &'a str, assignment_id: u64) -> Self {
+        ForkRepoGitlab { template_id, name, path: name, namespace_id: assignment_id, visibility: Visibility::Private }
+    }
+}
+
+impl<'a> APIFunction for ForkRepoGitlab<'a> {
+    fn path(&self) -> Cow<str> {
+        Cow::Owned(format!("projects/{}/fork", self.template_id))
+    }
+}
+
+#[derive(Serialize)]
+struct CreateWebhookGitlab<'a> {
+    #[serde(skip)]
+    project_id: u64,
+    url: &'a str,
+    push_events: bool,
+    tag_push_events: bool,
+    merge_requests_events: bool,
+    note_events: bool,
+    pipeline_events: bool,
+    token: &'a str,
+}
+
+impl<'a> CreateWebhookGitlab<'a> {
+    fn new(project_id: u64, url: &'a str, token: &'a str) -> Self {
+        CreateWebhookGitlab { project_id, url, push_events: true, tag_push_events: true,
+            merge_requests_events: true, note_events: true, pipeline_events: true, token }
+    }
+}
+
+impl<'a> APIFunction for CreateWebhookGitlab<'a> {
+    fn path(&self) -> Cow<str> {
+        Cow::Owned(format!("projects/{}/hooks", self.project_id))
+    }
+}
+
+#[derive(Serialize)]
+struct AddUserToProjectGitlab<'a> {
+    #[serde(skip)]
+    project_id: u64,
+    user_id: u64,
+    access_level: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<&'a str>,
+}
+
+impl<'a> AddUserToProjectGitlab<'a> {
+    fn new(project_id: u64, user_id: u64, access_level: AccessLevel, expires_at: Option<&'a str>) -> Self {
+        AddUserToProjectGitlab { project_id, user_id, access_level: gitlab_access_level(access_level), expires_at }
+    }
+}
+
+impl<'a> APIFunction for AddUserToProjectGitlab<'a> {
+    fn path(&self) -> Cow<str> {
+        Cow::Owned(format!("projects/{}/members", self.project_id))
+    }
+}
+
+#[derive(Serialize)]
+struct UpdateProjectMemberGitlab<'a> {
+    #[serde(skip)]
+    project_id: u64,
+    #[serde(skip)]
+    user_id: u64,
+    access_level: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<&'a str>,
+}
+
+impl<'a> UpdateProjectMemberGitlab<'a> {
+    fn new(project_id: u64, user_id: u64, access_level: AccessLevel, expires_at: Option<&'a str>) -> Self {
+        UpdateProjectMemberGitlab { project_id, user_id, access_level: gitlab_access_level(access_level), expires_at }
+    }
+}
+
+impl<'a> APIFunction for UpdateProjectMemberGitlab<'a> {
+    fn method() -> Method { Method::PUT }
+
+    fn path(&self) -> Cow<str> {
+        Cow::Owned(format!("projects/{}/members/{}", self.project_id, self.user_id))
+    }
+}
+
+fn gitlab_access_level(access_level: AccessLevel) -> u8 {
+    match access_level {
+        AccessLevel::Reporter => 20,
+        AccessLevel::Developer => 30,
+        // 40 is maintainer access, so users can push
+        AccessLevel::Maintainer => 40,
+        AccessLevel::Owner => 50,
+    }
+}
+
+pub struct GitLabAPI {
+    client: Client,
+    _base_url: Url,
+}
+
+impl GitLabAPI {
+    pub fn new(token: &str, base_url: Url) -> GitLabAPI {
+        let mut default_header = HeaderMap::new();
+        default_header.insert("Private-Token", HeaderValue::from_str(token).expect("Token malformed"));
+        GitLabAPI {
+            client: ClientBuilder::new()
+                .default_headers(default_header)
+                .build().expect("What?"),
+            _base_url: base_url,
+        }
+    }
+
+    pub fn lookup_user_id(&self, username: &str) -> GMResult<u64> {
+        let mut res = self.execute(Method::GET, &format!["users?username={}", username], "", None)?
+            .error_for_status()?;
+        let body = res.text()?;
+
+        if body.is_empty() {
+            Err(Error::NotFound)
+        } else {
+            Ok(serde_json::from_str::<serde_json::Value>(&body)?[0]["id"].as_u64().expect("Schema changed."))
+        }
+    }
+
+    /// Every item of a listing, following its `Link` header.
+    fn all_pages(&self, path: &str) -> GMResult<Vec<Value>> {
+        let mut items = Vec::new();
+        let mut next = Some(path.to_string());
+        while let Some(page) = next {
+            let mut res = self.call_no_body(Method::GET, &page)?;
+            next = next_link(&res);
+            let page: Value = res.json()?;
+            items.extend(page.as_array().expect("Gitlab schema changed").iter().cloned());
+        }
+        Ok(items)
+    }
+
+    /// `None` for an empty repo.
+    fn default_branch(&self, repo: u64) -> GMResult<Option<String>> {
+        let response: Value = self.call_no_body(Method::GET, &format!("projects/{}", repo))?.json()?;
+        Ok(response["default_branch"].as_str().map(str::to_string))
+    }
+
+    /// Wait for a fork to have its commits. Webhooks and branch protection aren't applied before.
+    fn wait_imported(&self, repo: u64) -> GMResult<()> {
+        let give_up = Instant::now() + Duration::from_secs(60);
+        loop {
+            let response: Value = self.call_no_body(Method::GET, &format!("projects/{}", repo))?.json()?;
+            match response["import_status"].as_str() {
+                None | Some("none") | Some("finished") => return Ok(()),
+                Some("failed") => {
+                    let reason = response["import_error"].as_str().unwrap_or("Fork failed").to_string();
+                    return Err(Error::upstream(500, reason));
+                }
+                Some(_) if Instant::now() >= give_up => return Err(Error::new("Fork not done in time")),
+                Some(_) => thread::sleep(Duration::from_millis(500)),
+            }
+        }
+    }
+
+    fn is_empty(&self, repo: u64) -> GMResult<bool> {
+        let response: Value = self.call_no_body(Method::GET, &format!("projects/{}", repo))?.json()?;
+        Ok(response["empty_repo"].as_bool().unwrap_or(false))
+    }
+
+    fn raw_file(&self, repo: u64, path: &str, git_ref: &str) -> GMResult<Vec<u8>> {
+        let mut content = Vec::new();
+        self.file(repo, path, Some(git_ref))?.copy_to(&mut content)?;
+        Ok(content)
+    }
+
+    fn file_exists(&self, repo: u64, path: &str, git_ref: &str) -> GMResult<bool> {
+        let file = utf8_percent_encode(path, PATH_SEGMENT_ENCODE_SET);
+        let query = Serializer::new(String::new()).append_pair("ref", git_ref).finish();
+        let found = optional(self.call_no_body(Method::GET, &format!("projects/{}/repository/files/{}?{}", repo, file, query)))?;
+        Ok(found.is_some())
+    }
+
+    /// Commit every file on the default branch of `from` to `to` at once.
+    fn copy_files(&self, from: u64, to: u64) -> GMResult<()> {
+        let branch = match self.default_branch(from)? {
+            Some(branch) => branch,
+            // empty template
+            None => return Ok(()),
+        };
+        let mut actions = Vec::new();
+        let query = Serializer::new(String::new())
+            .append_pair("ref", &branch)
+            .append_pair("recursive", "true")
+            .append_pair("per_page", "100")
+            .finish();
+        let mut next = Some(format!("projects/{}/repository/tree?{}", from, query));
+        while let Some(page) = next {
+            let mut res = self.call_no_body(Method::GET, &page)?;
+            next = next_link(&res);
+            let entries: Value = res.json()?;
+            for entry in entries.as_array().expect("Gitlab schema changed").iter().filter(|e| e["type"] == "blob") {
+                let path = entry["path"].as_str().expect("Gitlab schema changed");
+                let content = self.raw_file(from, path, &branch)?;
+                actions.push(json!({ "action": "create", "file_path": path, "content": base64::encode(&content), "encoding": "base64" }));
+            }
+        }
+        if !actions.is_empty() {
+            let body = json!({ "branch": branch, "commit_message": "Initial commit", "actions": actions });
+            self.execute(Method::POST, &format!("projects/{}/repository/commits", to), &body, None)?;
+        }
+        Ok(())
+    }
+
+    /// A protected branch can't be changed in place, so it is removed and protected again.
+    fn unprotect_branches(&self, repo: u64) -> GMResult<()> {
+        match self.call_no_body(Method::DELETE, &format!("projects/{}/protected_branches/*", repo)) {
+            Ok(_) | Err(Error::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn remove_keys(&self, id: u64) -> GMResult<()> {
+        let keys: JsonValue = self.call_no_body(Method::GET, &format!("users/{}/keys", id))?.json()?;
+
+        for val in keys.as_array().iter().map(|k| k.iter()).flatten() {
+            self.call_no_body(Method::DELETE, &format!("users/{}/keys/{}", id, val["id"].as_u64().expect("Gitlab schema changed.")))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl APIAccessor for GitLabAPI {
+    #[inline]
+    fn client(&self) -> &Client {
+        &self.client
+    }
+
+    #[inline]
+    fn base(&self) -> &Url {
+        &self._base_url
+    }
+}
+
+impl GitServer for GitLabAPI {
+    fn create_user(&self, email: &str, username: &str, password: &str) -> GMResult<u64> {
+        let response: Value = self.call(&CreateUserGitLab { email, username, password, name: username })?.json()?;
+        Ok(response["id"].as_u64().expect("Gitlab schema changed"))
+    }
+
+    fn replace_key(&self, user: u64, key: &str) -> GMResult<()> {
+        self.remove_keys(user)?;
+        self.call(&AddKeyGitlab::new(user, key))?;
+        Ok(())
+    }
+
+    fn create_course(&self, name: &str) -> GMResult<u64> {
+        let response: Value = self.call(&CreateGroupGitlab::new(name, None))?.json()?;
+        Ok(response["id"].as_u64().expect("Gitlab schema changed"))
+    }
+
+    fn create_assignment(&self, course: u64, name: &str) -> GMResult<u64> {
+        let response: Value = self.call(&CreateGroupGitlab::new(name, Some(course)))?.json()?;
+        Ok(response["id"].as_u64().expect("Gitlab schema changed"))
+    }
+
+    fn list_assignments(&self, course: u64) -> GMResult<Vec<u64>> {
+        let res: Value = self.call_no_body(Method::GET, &format!("groups/{}/subgroups", course))?.json()?;
+        Ok(res.as_array().expect("Gitlab schema changed").iter()
+            .map(|assignment| assignment["id"].as_u64().expect("Gitlab schema changed"))
+            .collect())
+    }
+
+    fn get_group(&self, group: u64) -> GMResult<()> {
+        self.call_no_body(Method::GET, &format!("groups/{}", group))?;
+        Ok(())
+    }
+
+    fn delete_group(&self, group: u64) -> GMResult<()> {
+        self.call_no_body(Method::DELETE, &format!("groups/{}", group))?;
+        Ok(())
+    }
+
+    fn add_group_member(&self, group: u64, user: u64, access_level: AccessLevel) -> GMResult<()> {
+        self.call(&AddUserToGroupGitlab::new(user, group, access_level))?;
+        Ok(())
+    }
+
+    fn update_group_member(&self, group: u64, user: u64, access_level: AccessLevel) -> GMResult<()> {
+        self.call_no_body(Method::PUT, &format!("groups/{}/members/{}?access_level={}", group, user, gitlab_access_level(access_level)))?;
+        Ok(())
+    }
+
+    fn remove_group_member(&self, group: u64, user: u64) -> GMResult<()> {
+        self.call_no_body(Method::DELETE, &format!("groups/{}/members/{}", group, user))?;
+        Ok(())
+    }
+
+    fn list_group_members(&self, group: u64, access_level: AccessLevel) -> GMResult<Vec<u64>> {
+        Ok(self.all_pages(&format!("groups/{}/members?per_page=100", group))?.iter()
+            .filter(|member| member["access_level"].as_u64() == Some(u64::from(gitlab_access_level(access_level))))
+            .map(|member| member["id"].as_u64().expect("Gitlab schema changed"))
+            .collect())
+    }
+
+    fn create_repo(&self, assignment: u64, name: &str) -> GMResult<Repo> {
+        let response: Value = self.call(&CreateRepoGitlab::new(name, assignment))?.json()?;
+        Ok(Repo {
+            id: response["id"].as_u64().expect("Gitlab schema changed"),
+            ssh_url: response["ssh_url_to_repo"].as_str().expect("Gitlab schema changed").to_string(),
+        })
+    }
+
+    fn create_repo_from(&self, assignment: u64, name: &str, template: u64, keep_history: bool) -> GMResult<Repo> {
+        if !keep_history {
+            let repo = self.create_repo(assignment, name)?;
+            return match self.copy_files(template, repo.id) {
+                Ok(()) => Ok(repo),
+                Err(e) => {
+                    self.delete_repo(repo.id)?;
+                    Err(e)
+                }
+            };
+        }
+        let response: Value = self.call(&ForkRepoGitlab::new(template, name, assignment))?.json()?;
+        let repo = Repo {
+            id: response["id"].as_u64().expect("Gitlab schema changed"),
+            ssh_url: response["ssh_url_to_repo"].as_str().expect("Gitlab schema changed").to_string(),
+        };
+        if let Err(e) = self.wait_imported(repo.id) {
+            self.delete_repo(repo.id)?;
+            return Err(e);
+        }
+        // students shouldn't open merge requests against the template
+        self.call_no_body(Method::DELETE, &format!("projects/{}/fork", repo.id))?;
+        Ok(repo)
+    }
+
+    fn find_repo(&self, path: &str) -> GMResult<u64> {
+        let path = utf8_percent_encode(path, PATH_SEGMENT_ENCODE_SET);
+        let response: Value = self.call_no_body(Method::GET, &format!("projects/{}", path))?.json()?;
+        Ok(response["id"].as_u64().expect("Gitlab schema changed"))
+    }
+
+    fn changed_files(&self, repo: u64, base: Option<&str>, head: &str) -> GMResult<Vec<FileChange>> {
+        let base = match base {
+            Some(base) => base.to_string(),
+            None => self.default_branch(repo)?.ok_or(Error::NotFound)?,
+        };
+        let query = Serializer::new(String::new()).append_pair("from", &base).append_pair("to", head).finish();
+        let compare: Value = self.call_no_body(Method::GET, &format!("projects/{}/repository/compare?{}", repo, query))?.json()?;
+        let mut paths = BTreeSet::new();
+        for diff in compare["diffs"].as_array().expect("Gitlab schema changed") {
+            paths.insert(diff["old_path"].as_str().expect("Gitlab schema changed").to_string());
+            paths.insert(diff["new_path"].as_str().expect("Gitlab schema changed").to_string());
+        }
+        paths.into_iter()
+            .map(|path| {
+                let content = optional(self.raw_file(repo, &path, head))?;
+                Ok(FileChange { path, content })
+            })
+            .collect()
+    }
+
+    fn push_branch(&self, repo: u64, branch: &str, message: &str, changes: &[FileChange]) -> GMResult<bool> {
+        let start = self.default_branch(repo)?.ok_or(Error::NotFound)?;
+        let encoded = utf8_percent_encode(branch, PATH_SEGMENT_ENCODE_SET);
+        if optional(self.call_no_body(Method::GET, &format!("projects/{}/repository/branches/{}", repo, encoded)))?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        let mut actions = Vec::new();
+        for change in changes {
+            let exists = self.file_exists(repo, &change.path, &start)?;
+            match change.content {
+                Some(ref content) => actions.push(json!({
+                    "action": if exists { "update" } else { "create" },
+                    "file_path": change.path,
+                    "content": base64::encode(content),
+                    "encoding": "base64",
+                })),
+                None if exists => actions.push(json!({ "action": "delete", "file_path": change.path })),
+                None => {}
+            }
+        }
+        if actions.is_empty() {
+            return Ok(false);
+        }
+        let body = json!({ "branch": branch, "start_branch": start, "commit_message": message, "actions": actions });
+        self.execute(Method::POST, &format!("projects/{}/repository/commits", repo), &body, None)?;
+        Ok(true)
+    }
+
+    fn open_merge_request(&self, repo: u64, branch: &str, title: &str, description: &str) -> GMResult<String> {
+        let target = self.default_branch(repo)?.ok_or(Error::NotFound)?;
+        let body = json!({
+            "source_branch": branch,
+            "target_branch": target,
+            "title": title,
+            "description": description,
+            "remove_source_branch": true,
+        });
+        let response: Value = self.execute(Method::POST, &format!("projects/{}/merge_requests", repo), &body, None)?.json()?;
+        Ok(response["web_url"].as_str().expect("Gitlab schema changed").to_string())
+    }
+
+    fn get_repo(&self, repo: u64) -> GMResult<()> {
+        self.call_no_body(Method::GET, &format!("projects/{}", repo))?;
+        Ok(())
+    }
+
+    fn delete_repo(&self, repo: u64) -> GMResult<()> {
+        self.call_no_body(Method::DELETE, &format!("projects/{}", repo))?;
+        Ok(())
+    }
+
+    fn create_webhook(&self, repo: u64, url: &str, token: &str) -> GMResult<()> {
+        self.call(&CreateWebhookGitlab::new(repo, url, token))?;
+        Ok(())
+    }
+
+    fn protect_branches(&self, repo: u64) -> GMResult<()> {
+        self.call_no_body(Method::POST, &format!("projects/{}/protected_branches?name=*", repo))?;
+        Ok(())
+    }
+
+    fn add_repo_member(&self, repo: u64, user: u64, access_level: AccessLevel, expires_at: Option<&str>) -> GMResult<()> {
+        self.call(&AddUserToProjectGitlab::new(repo, user, access_level, expires_at))?;
+        Ok(())
+    }
+
+    fn remove_repo_member(&self, repo: u64, user: u64) -> GMResult<()> {
+        self.call_no_body(Method::DELETE, &format!("projects/{}/members/{}", repo, user))?;
+        Ok(())
+    }
+
+    fn list_repo_members(&self, repo: u64) -> GMResult<Vec<u64>> {
+        Ok(self.all_pages(&format!("projects/{}/members?per_page=100", repo))?.iter()
+            .map(|member| member["id"].as_u64().expect("Gitlab schema changed"))
+            .collect())
+    }
+
+    fn update_repo_member(&self, repo: u64, user: u64, access_level: AccessLevel, expires_at: Option<&str>) -> GMResult<()> {
+        self.call(&UpdateProjectMemberGitlab::new(repo, user, access_level, expires_at))?;
+        Ok(())
+    }
+
+    fn lock_branches(&self, repo: u64) -> GMResult<()> {
+        self.unprotect_branches(repo)?;
+        // 0 is no access
+        self.call_no_body(Method::POST, &format!("projects/{}/protected_branches?name=*&push_access_level=0&merge_access_level=0", repo))?;
+        Ok(())
+    }
+
+    fn unlock_branches(&self, repo: u64) -> GMResult<()> {
+        self.unprotect_branches(repo)?;
+        self.protect_branches(repo)
+    }
+
+    fn archive(&self, repo: u64, format: ArchiveFormat, git_ref: Option<&str>) -> GMResult<Response> {
+        let format = format.extension();
+        match git_ref {
+            // takes any ref despite the name
+            Some(git_ref) => self.call_no_body(Method::GET, &format!("projects/{}/repository/archive.{}?{}", repo, format,
+                                                                      Serializer::new(String::new()).append_pair("sha", git_ref).finish())),
+            None => self.call_no_body(Method::GET, &format!("projects/{}/repository/archive.{}", repo, format)),
+        }
+    }
+
+    fn compare(&self, repo: u64, from: &str, to: &str) -> GMResult<Vec<FileDiff>> {
+        let query = Serializer::new(String::new()).append_pair("from", from).append_pair("to", to).append_pair("straight", "true").finish();
+        let compare: Value = self.call_no_body(Method::GET, &format!("projects/{}/repository/compare?{}", repo, query))?.json()?;
+        let field = |diff: &Value, name: &str| diff[name].as_str().expect("Gitlab schema changed").to_string();
+        // a file that isn't there has mode 0
+        let mode = |diff: &Value, name: &str| diff[name].as_str().filter(|mode| *mode != "0").map(str::to_string);
+        Ok(compare["diffs"].as_array().expect("Gitlab schema changed").iter()
+            .map(|diff| FileDiff {
+                old_path: field(diff, "old_path"),
+                new_path: field(diff, "new_path"),
+                status: if diff["new_file"] == true {
+                    DiffStatus::Added
+                } else if diff["deleted_file"] == true {
+                    DiffStatus::Deleted
+                } else if diff["renamed_file"] == true {
+                    DiffStatus::Renamed
+                } else {
+                    DiffStatus::Modified
+                },
+                old_mode: mode(diff, "a_mode"),
+                new_mode: mode(diff, "b_mode"),
+                diff: field(diff, "diff"),
+            })
+            .collect())
+    }
+
+    fn tags(&self, repo: u64) -> GMResult<Vec<Tag>> {
+        let mut tags = Vec::new();
+        let mut next = Some(format!("projects/{}/repository/tags?order_by=updated&per_page=100", repo));
+        while let Some(page) = next {
+            let mut res = self.call_no_body(Method::GET, &page)?;
+            next = next_link(&res);
+            let page: Value = res.json()?;
+            for tag in page.as_array().expect("Gitlab schema changed") {
+                let commit = &tag["commit"];
+                tags.push(Tag {
+                    name: tag["name"].as_str().expect("Gitlab schema changed").to_string(),
+                    sha: commit["id"].as_str().expect("Gitlab schema changed").to_string(),
+                    message: tag["message"].as_str().filter(|message| !message.is_empty()).map(str::to_string),
+                    committed_at: normalize_date(commit["committed_date"].as_str().expect("Gitlab schema changed")).expect("Gitlab schema changed"),
+                });
+            }
+        }
+        Ok(tags)
+    }
+
+    fn tree(&self, repo: u64, git_ref: Option<&str>, path: Option<&str>) -> GMResult<Vec<TreeEntry>> {
+        let mut query = Serializer::new(String::new());
+        query.append_pair("per_page", "100");
+        if let Some(git_ref) = git_ref {
+            query.append_pair("ref", git_ref);
+        }
+        if let Some(path) = path {
+            query.append_pair("path", path);
+        }
+        let mut entries = Vec::new();
+        let mut next = Some(format!("projects/{}/repository/tree?{}", repo, query.finish()));
+        while let Some(page) = next {
+            let mut res = match self.call_no_body(Method::GET, &page) {
+                Ok(res) => res,
+                Err(Error::NotFound) if path.is_none() && self.is_empty(repo)? => return Ok(entries),
+                Err(e) => return Err(e),
+            };
+            next = next_link(&res);
+            let page: Value = res.json()?;
+            for entry in page.as_array().expect("Gitlab schema changed") {
+                let entry_type = match (entry["type"].as_str(), entry["mode"].as_str()) {
+                    (_, Some("120000")) => EntryType::Symlink,
+                    (Some("tree"), _) => EntryType::Dir,
+                    (Some("commit"), _) => EntryType::Submodule,
+                    _ => EntryType::File,
+                };
+                entries.push(TreeEntry {
+                    name: entry["name"].as_str().expect("Gitlab schema changed").to_string(),
+                    path: entry["path"].as_str().expect("Gitlab schema changed").to_string(),
+                    entry_type,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn file(&self, repo: u64, path: &str, git_ref: Option<&str>) -> GMResult<Response> {
+        let git_ref = match git_ref {
+            Some(git_ref) => Cow::Borrowed(git_ref),
+            None => Cow::Owned(self.default_branch(repo)?.ok_or(Error::NotFound)?),
+        };
+        let file = utf8_percent_encode(path, PATH_SEGMENT_ENCODE_SET);
+        let query = Serializer::new(String::new()).append_pair("ref", &git_ref).finish();
+        self.call_no_body(Method::GET, &format!("projects/{}/repository/files/{}/raw?{}", repo, file, query))
+    }
+
+    fn last_commit(&self, repo: u64, git_ref: Option<&str>, before: Option<u64>) -> GMResult<Option<String>> {
+        let mut query = Serializer::new(String::new());
+        query.append_pair("per_page", "1");
+        if let Some(git_ref) = git_ref {
+            query.append_pair("ref_name", git_ref);
+        }
+        if let Some(before) = before {
+            query.append_pair("until", &rfc3339(before));
+        }
+        let path = format!("projects/{}/repository/commits?{}", repo, query.finish());
+        // an empty repo has no tree at all
+        let commits: Value = match optional(self.call_no_body(Method::GET, &path))? {
+            Some(mut res) => res.json()?,
+            None => return Ok(None),
+        };
+        commits.as_array().ok_or_else(Error::schema_changed)?.first()
+            .map(|commit| commit["id"].as_str().map(str::to_string).ok_or_else(Error::schema_changed))
+            .transpose()
+    }
+
+    fn commits(&self, repo: u64, filter: &CommitFilter, page: u32) -> GMResult<CommitPage> {
+        let mut query = Serializer::new(String::new());
+        query.append_pair("per_page", &filter.per_page.to_string()).append_pair("page", &page.to_string());
+        if let Some(git_ref) = filter.git_ref {
+            query.append_pair("ref_name", git_ref);
+        }
+        if let Some(file) = filter.path {
+            query.append_pair("path", file);
+        }
+        if let Some(since) = filter.since {
+            query.append_pair("since", &rfc3339(since));
+        }
+        if let Some(until) = filter.until {
+            query.append_pair("until", &rfc3339(until));
+        }
+        let path = format!("projects/{}/repository/commits?{}", repo, query.finish());
+        let mut body = match self.call_no_body(Method::GET, &path) {
+            Ok(body) => body,
+            // an empty repo has no tree at all
+            Err(Error::NotFound) if self.is_empty(repo)? => return Ok(CommitPage { commits: Vec::new(), next: None }),
+            Err(e) => return Err(e),
+        };
+        let next = next_link(&body).map(|_| page + 1);
+        let commits: Value = body.json()?;
+        let commits = commits.as_array().ok_or_else(Error::schema_changed)?.iter().map(commit).collect::<GMResult<_>>()?;
+        Ok(CommitPage { commits, next })
+    }
+
+    fn parse_push<'a>(&self, payload: &'a Value) -> Option<PushEvent<'a>> {
+        Some(PushEvent {
+            upstream: payload["project"]["git_ssh_url"].as_str()?,
+            repo_id: payload["project"]["id"].as_u64()?,
+            pusher_id: payload["user_id"].as_u64()?,
+            git_ref: payload["ref"].as_str()?,
+            after: payload["after"].as_str()?,
+        })
+    }
+
+    fn parse_tag_push<'a>(&self, payload: &'a Value) -> Option<TagPushEvent<'a>> {
+        Some(TagPushEvent {
+            upstream: payload["project"]["git_ssh_url"].as_str()?,
+            repo_id: payload["project"]["id"].as_u64()?,
+            pusher_id: payload["user_id"].as_u64()?,
+            tag: payload["ref"].as_str()?.strip_prefix("refs/tags/")?,
+            // the commit tagged, rather than an annotated tag itself. None if deleted
+            sha: payload["checkout_sha"].as_str(),
+        })
+    }
+
+    fn parse_event(&self, payload: &Value) -> Option<EventSource> {
+        Some(EventSource {
+            repo_id: payload["project"]["id"].as_u64()?,
+            sender_id: payload["user"]["id"].as_u64(),
+        })
+    }
+
+    fn health(&self) -> GMResult<()> {
+        self.call_no_body(Method::GET, "../../-/health")?;
+        Ok(())
+    }
+}
+
+fn commit(commit: &Value) -> GMResult<Commit> {
+    let field = |name: &str| commit[name].as_str().map(str::to_string).ok_or_else(Error::schema_changed);
+    let date = |name: &str| commit[name].as_str().and_then(normalize_date).ok_or_else(Error::schema_changed);
+    Ok(Commit {
+        sha: field("id")?,
+        message: field("message")?,
+        author_name: field("author_name")?,
+        author_email: field("author_email")?,
+        author: None,
+        authored_at: date("authored_date")?,
+        committed_at: date("committed_date")?,
+        parents: commit["parent_ids"].as_array().ok_or_else(Error::schema_changed)?.iter()
+            .map(|sha| sha.as_str().map(str::to_string).ok_or_else(Error::schema_changed))
+            .collect::<GMResult<_>>()?,
+    })
+}
@@ -0,0 +1,394 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use ::{Error, GMResult};
+
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use reqwest::Response;
+use rocket::http::ContentType;
+use serde_json::Value;
+
+/// Permission a user is granted on a course, an assignment or a repo.
+/// Modeled after gitlab, other implementations map these to the closest thing they have.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLevel {
+    Reporter,
+    Developer,
+    Maintainer,
+    Owner,
+}
+
+impl AccessLevel {
+    /// As kept in DB.
+    pub fn name(self) -> &'static str {
+        match self {
+            AccessLevel::Reporter => "reporter",
+            AccessLevel::Developer => "developer",
+            AccessLevel::Maintainer => "maintainer",
+            AccessLevel::Owner => "owner",
+        }
+    }
+
+    pub fn from_name(name: &str) -> GMResult<AccessLevel> {
+        match name {
+            "reporter" => Ok(AccessLevel::Reporter),
+            "developer" => Ok(AccessLevel::Developer),
+            "maintainer" => Ok(AccessLevel::Maintainer),
+            "owner" => Ok(AccessLevel::Owner),
+            _ => {
+                error!("Unknown access level {} in DB", name);
+                Err(Error::new("Unknown access level in DB"))
+            }
+        }
+    }
+}
+
+/// Formats a repo can be archived in, named by their extension.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    TarGz,
+    TarBz2,
+    Tbz,
+    Tbz2,
+    Tb2,
+    Bz2,
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn from_extension(extension: &str) -> Option<ArchiveFormat> {
+        Some(match extension {
+            "tar.gz" => ArchiveFormat::TarGz,
+            "tar.bz2" => ArchiveFormat::TarBz2,
+            "tbz" => ArchiveFormat::Tbz,
+            "tbz2" => ArchiveFormat::Tbz2,
+            "tb2" => ArchiveFormat::Tb2,
+            "bz2" => ArchiveFormat::Bz2,
+            "tar" => ArchiveFormat::Tar,
+            "zip" => ArchiveFormat::Zip,
+            _ => return None,
+        })
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarBz2 => "tar.bz2",
+            ArchiveFormat::Tbz => "tbz",
+            ArchiveFormat::Tbz2 => "tbz2",
+            ArchiveFormat::Tb2 => "tb2",
+            ArchiveFormat::Bz2 => "bz2",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(self) -> ContentType {
+        match self {
+            ArchiveFormat::TarGz => ContentType::new("application", "gzip"),
+            ArchiveFormat::TarBz2 | ArchiveFormat::Tbz | ArchiveFormat::Tbz2 | ArchiveFormat::Tb2 | ArchiveFormat::Bz2 =>
+                ContentType::new("application", "x-bzip2"),
+            ArchiveFormat::Tar => ContentType::new("application", "x-tar"),
+            ArchiveFormat::Zip => ContentType::new("application", "zip"),
+        }
+    }
+}
+
+/// A freshly created repo.
+pub struct Repo {
+    pub id: u64,
+    pub ssh_url: String,
+}
+
+/// What we care about in an inbound push webhook.
+pub struct PushEvent<'a> {
+    pub upstream: &'a str,
+    pub repo_id: u64,
+    /// Git server id of the pusher
+    pub pusher_id: u64,
+    /// e.g. `refs/heads/master`
+    pub git_ref: &'a str,
+    /// SHA the ref points to after the push
+    pub after: &'a str,
+}
+
+/// What we care about in an inbound tag push webhook.
+pub struct TagPushEvent<'a> {
+    pub upstream: &'a str,
+    pub repo_id: u64,
+    /// Git server id of the pusher
+    pub pusher_id: u64,
+    /// Name of the tag, e.g. `submit-1`
+    pub tag: &'a str,
+    /// SHA the tag points to, `None` if it was deleted
+    pub sha: Option<&'a str>,
+}
+
+/// Where an inbound merge request, note or pipeline webhook came from.
+pub struct EventSource {
+    pub repo_id: u64,
+    /// Git server id of whoever caused it, `None` if the git server doesn't say
+    pub sender_id: Option<u64>,
+}
+
+/// A tag of a repo.
+#[derive(Serialize)]
+pub struct Tag {
+    pub name: String,
+    pub sha: String,
+    /// `None` unless annotated
+    pub message: Option<String>,
+    /// Of the commit tagged
+    pub committed_at: String,
+}
+
+/// A file touched between two commits, with its content afterwards. `None` if it was deleted.
+pub struct FileChange {
+    pub path: String,
+    pub content: Option<Vec<u8>>,
+}
+
+/// Which commits to list.
+pub struct CommitFilter<'a> {
+    /// Branch, tag or sha, the default branch if `None`
+    pub git_ref: Option<&'a str>,
+    /// Only commits touching this file or folder
+    pub path: Option<&'a str>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub per_page: u32,
+}
+
+/// A commit, the same whatever the git server.
+#[derive(Serialize)]
+pub struct Commit {
+    pub sha: String,
+    pub message: String,
+    pub author_name: String,
+    /// As in the commit, may be anything
+    pub author_email: String,
+    /// Registered email of the author, if `author_email` is one we know
+    pub author: Option<String>,
+    pub authored_at: String,
+    pub committed_at: String,
+    pub parents: Vec<String>,
+}
+
+/// One page of commits, along with the number of the next page if there is one.
+pub struct CommitPage {
+    pub commits: Vec<Commit>,
+    pub next: Option<u32>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryType {
+    File,
+    Dir,
+    Symlink,
+    Submodule,
+}
+
+/// An entry of a folder in a repo.
+#[derive(Serialize)]
+pub struct TreeEntry {
+    pub name: String,
+    /// From the top of the repo
+    pub path: String,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffStatus {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+/// How a file changed between two commits.
+#[derive(Serialize)]
+pub struct FileDiff {
+    pub old_path: String,
+    pub new_path: String,
+    pub status: DiffStatus,
+    /// e.g. `100644`, `None` if the file wasn't there
+    pub old_mode: Option<String>,
+    pub new_mode: Option<String>,
+    /// Hunks of the unified diff, without the `---`/`+++` lines
+    pub diff: String,
+}
+
+/// Everything the middleware needs from an actual git server implementation.
+///
+/// Courses and assignments are both groups, assignments being nested in courses.
+/// Ids are whatever the git server uses to identify the object, and are what we keep in DB.
+pub trait GitServer {
+    fn create_user(&self, email: &str, username: &str, password: &str) -> GMResult<u64>;
+    /// Remove all keys of the user and add the given one.
+    fn replace_key(&self, user: u64, key: &str) -> GMResult<()>;
+
+    fn create_course(&self, name: &str) -> GMResult<u64>;
+    fn create_assignment(&self, course: u64, name: &str) -> GMResult<u64>;
+    fn list_assignments(&self, course: u64) -> GMResult<Vec<u64>>;
+    /// `Err(Error::NotFound)` if the group is gone.
+    fn get_group(&self, group: u64) -> GMResult<()>;
+    /// Delete the group along with everything in it.
+    fn delete_group(&self, group: u64) -> GMResult<()>;
+    fn add_group_member(&self, group: u64, user: u64, access_level: AccessLevel) -> GMResult<()>;
+    /// Change access of an existing member.
+    fn update_group_member(&self, group: u64, user: u64, access_level: AccessLevel) -> GMResult<()>;
+    fn remove_group_member(&self, group: u64, user: u64) -> GMResult<()>;
+    /// Members given exactly `access_level` on the group itself.
+    fn list_group_members(&self, group: u64, access_level: AccessLevel) -> GMResult<Vec<u64>>;
+
+    /// Create a private repo under given assignment.
+    fn create_repo(&self, assignment: u64, name: &str) -> GMResult<Repo>;
+    /// As `create_repo`, seeded with the default branch of `template`.
+    /// Without history, the files of `template` are committed at once.
+    fn create_repo_from(&self, assignment: u64, name: &str, template: u64, keep_history: bool) -> GMResult<Repo>;
+    /// Id of the repo at `path`, e.g. `SI100c/templates/hw0`.
+    fn find_repo(&self, path: &str) -> GMResult<u64>;
+    /// Files changed on `head` since `base`, both branches or commits of `repo`.
+    /// `None` for the default branch. Renames are a deletion and an addition.
+    fn changed_files(&self, repo: u64, base: Option<&str>, head: &str) -> GMResult<Vec<FileChange>>;
+    /// Commit `changes` to a new `branch` off the default branch. Deleting a file which is gone already is skipped.
+    /// An existing branch is never touched, `Err(Error::AlreadyExists)` instead. `Ok(false)` if there was nothing to commit.
+    fn push_branch(&self, repo: u64, branch: &str, message: &str, changes: &[FileChange]) -> GMResult<bool>;
+    /// Ask to merge `branch` into the default branch. Returns the link to the merge request.
+    fn open_merge_request(&self, repo: u64, branch: &str, title: &str, description: &str) -> GMResult<String>;
+    /// `Err(Error::NotFound)` if the repo is gone.
+    fn get_repo(&self, repo: u64) -> GMResult<()>;
+    fn delete_repo(&self, repo: u64) -> GMResult<()>;
+    /// Add a push webhook. An empty token means no token.
+    fn create_webhook(&self, repo: u64, url: &str, token: &str) -> GMResult<()>;
+    /// Protect all branches against force push.
+    fn protect_branches(&self, repo: u64) -> GMResult<()>;
+    /// `expires_at` is a `%Y-%m-%d` date, `None` for never. Implementations may ignore it if not supported.
+    fn add_repo_member(&self, repo: u64, user: u64, access_level: AccessLevel, expires_at: Option<&str>) -> GMResult<()>;
+    /// `Err(Error::NotFound)` if the user isn't a member.
+    fn remove_repo_member(&self, repo: u64, user: u64) -> GMResult<()>;
+    /// Users given access on the repo itself, not through its assignment or course.
+    fn list_repo_members(&self, repo: u64) -> GMResult<Vec<u64>>;
+    /// Change access of an existing member. `expires_at` as in `add_repo_member`, `None` keeps the current one.
+    fn update_repo_member(&self, repo: u64, user: u64, access_level: AccessLevel, expires_at: Option<&str>) -> GMResult<()>;
+    /// Protect all branches against any push, e.g. once the deadline passed.
+    fn lock_branches(&self, repo: u64) -> GMResult<()>;
+    /// Undo `lock_branches`, leaving branches protected against force push only.
+    fn unlock_branches(&self, repo: u64) -> GMResult<()>;
+
+    /// Archive of the default branch, or of `git_ref`, a branch, tag or commit.
+    /// `Err(Error::BadRequest(_))` if the format isn't supported.
+    fn archive(&self, repo: u64, format: ArchiveFormat, git_ref: Option<&str>) -> GMResult<Response>;
+    /// Every file changed from `from` to `to`, both being a sha, branch or tag.
+    fn compare(&self, repo: u64, from: &str, to: &str) -> GMResult<Vec<FileDiff>>;
+    /// Every tag of the repo, newest first.
+    fn tags(&self, repo: u64) -> GMResult<Vec<Tag>>;
+    /// Entries of folder `path` or the top one, on `git_ref` or the default branch. An empty repo has none.
+    fn tree(&self, repo: u64, git_ref: Option<&str>, path: Option<&str>) -> GMResult<Vec<TreeEntry>>;
+    /// Raw content of file `path`, on `git_ref` or the default branch.
+    fn file(&self, repo: u64, path: &str, git_ref: Option<&str>) -> GMResult<Response>;
+    /// Latest commit on `git_ref` or the default branch, committed no later than `before` if given. `None` if there is none.
+    fn last_commit(&self, repo: u64, git_ref: Option<&str>, before: Option<u64>) -> GMResult<Option<String>>;
+    /// Commits matching `filter`, newest first. Pages count from 1. An empty repo has no commits, rather than `Err(Error::NotFound)`.
+    fn commits(&self, repo: u64, filter: &CommitFilter, page: u32) -> GMResult<CommitPage>;
+
+    /// `None` if the payload isn't a push event from this git server.
+    fn parse_push<'a>(&self, payload: &'a Value) -> Option<PushEvent<'a>>;
+    /// `None` if the payload isn't a tag push event from this git server.
+    fn parse_tag_push<'a>(&self, payload: &'a Value) -> Option<TagPushEvent<'a>>;
+    /// `None` if the payload isn't a merge request, note or pipeline event from this git server.
+    fn parse_event(&self, payload: &Value) -> Option<EventSource>;
+
+    fn health(&self) -> GMResult<()>;
+}
+
+/// The git server in use, as selected by `git_server` config. Shared with background jobs.
+#[derive(Clone)]
+pub struct GitServerAPI(Arc<dyn GitServer + Send + Sync>);
+
+impl GitServerAPI {
+    pub fn new<T: GitServer + Send + Sync + 'static>(server: T) -> GitServerAPI {
+        GitServerAPI(Arc::new(server))
+    }
+}
+
+impl Deref for GitServerAPI {
+    type Target = dyn GitServer + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+/// Shared by implementations that page with a `Link` header.
+pub fn next_link(response: &Response) -> Option<String> {
+    let link = response.headers().get("Link")?.to_str().ok()?;
+    let next = link.split(',').find(|s| s.trim().ends_with(r#"rel="next""#))?;
+    Some(next[next.find('<')? + 1..next.find('>')?].to_string())
+}
+
+/// `diffs` as one patch, in the format of `git diff`, which `git apply` takes.
+pub fn patch(diffs: &[FileDiff]) -> String {
+    let mut patch = String::new();
+    for diff in diffs {
+        patch += &format!("diff --git a/{} b/{}\n", diff.old_path, diff.new_path);
+        match (&diff.old_mode, &diff.new_mode) {
+            (None, Some(mode)) => patch += &format!("new file mode {}\n", mode),
+            (Some(mode), None) => patch += &format!("deleted file mode {}\n", mode),
+            (Some(old), Some(new)) if old != new => patch += &format!("old mode {}\nnew mode {}\n", old, new),
+            _ => {}
+        }
+        if diff.status == DiffStatus::Renamed {
+            patch += &format!("rename from {}\nrename to {}\n", diff.old_path, diff.new_path);
+        }
+        // nothing but a rename or a mode change
+        if diff.diff.is_empty() {
+            continue;
+        }
+        match diff.status {
+            DiffStatus::Added => patch += "--- /dev/null\n",
+            _ => patch += &format!("--- a/{}\n", diff.old_path),
+        }
+        match diff.status {
+            DiffStatus::Deleted => patch += "+++ /dev/null\n",
+            _ => patch += &format!("+++ b/{}\n", diff.new_path),
+        }
+        patch += &diff.diff;
+        if !diff.diff.ends_with('\n') {
+            patch.push('\n');
+        }
+    }
+    patch
+}
+
+/// A unix timestamp as RFC 3339 in UTC, which is what git servers take for dates.
+pub fn rfc3339(timestamp: u64) -> String {
+    Utc.timestamp(timestamp as i64, 0).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// A date from git server, in whatever offset and precision, as `rfc3339` gives it.
+pub fn normalize_date(date: &str) -> Option<String> {
+    Some(DateTime::parse_from_rfc3339(date).ok()?.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true))
+}
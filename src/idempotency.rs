@@ -0,0 +1,185 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `Idempotency-Key` support for create endpoints.
+//!
+//! The key is reserved before doing anything, so a retry arriving while the original request is still running
+//! gets 409 instead of running twice. A successful response is kept and replayed to later retries.
+//! Anything else releases the key, as with rollback nothing was left behind and the request can run again.
+//! A reservation older than `RESERVATION` was left by an instance that died, and is taken over by the next retry.
+//! Keys are forgotten after `KEEP`.
+
+use std::io::Cursor;
+
+use ::{DBAccess, Error, GMResult};
+use err::guard_error;
+use jobs::now;
+
+use rocket::{Outcome, Request};
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{Responder, Response};
+use serde::Serialize;
+
+use hex::encode;
+use sha2::{Digest, Sha512};
+
+const RESERVATION: u64 = 600;
+const KEEP: u64 = 24 * 3600;
+
+/// What a create endpoint responded, in a form we can keep in DB.
+pub struct StoredResponse {
+    status: Status,
+    body: String,
+}
+
+impl StoredResponse {
+    pub fn new(status: Status) -> Self {
+        StoredResponse { status, body: String::new() }
+    }
+
+    pub fn json(status: Status, body: String) -> Self {
+        StoredResponse { status, body }
+    }
+}
+
+impl<'r> Responder<'r> for StoredResponse {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        let mut builder = Response::build();
+        builder.status(self.status);
+        if !self.body.is_empty() {
+            builder.header(ContentType::JSON).sized_body(Cursor::new(self.body));
+        }
+        Ok(builder.finalize())
+    }
+}
+
+pub struct IdempotencyKey {
+    key: Option<String>,
+    route: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IdempotencyKey {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let key = request.headers().get_one("Idempotency-Key");
+        if let Some(key) = key {
+            // primary key in DB
+            if key.is_empty() || key.len() > 255 {
+                return Outcome::Failure((Status::BadRequest,
+                                         guard_error(request, Error::new("Idempotency-Key must be 1 to 255 characters"))));
+            }
+        }
+        Outcome::Success(IdempotencyKey {
+            key: key.map(str::to_string),
+            route: format!("{} {}", request.method(), request.uri()),
+        })
+    }
+}
+
+impl IdempotencyKey {
+    /// Run `f` once per key. Retries with the same key and request get the stored response,
+    /// retries with the same key but another request get 422.
+    pub(crate) fn run<T, F>(&self, message: &T, db: &mut DBAccess, f: F) -> GMResult<StoredResponse>
+        where T: Serialize + ?Sized, F: FnOnce(&mut DBAccess) -> GMResult<StoredResponse> {
+        let key = match self.key {
+            Some(ref key) => key,
+            None => return f(db),
+        };
+        let hash = encode(Sha512::digest(format!("{}\n{}", self.route, serde_json::to_string(message)?).as_bytes()).as_slice());
+
+        db.purge_idempotency_keys(now().saturating_sub(KEEP))?;
+        if !db.reserve_idempotency_key(key, &hash)? {
+            let (stored_hash, reserved_at, response) = db.lookup_idempotency_key(key)?.ok_or(Error::AlreadyExists)?;
+            if stored_hash != hash {
+                info!("Idempotency-Key {} reused for another request", key);
+                return Err(Error::Unprocessable("Idempotency-Key already used for another request"));
+            }
+            match response {
+                Some(response) => {
+                    info!("Replaying response for Idempotency-Key {}", key);
+                    return Ok(response);
+                }
+                None if reserved_at < now().saturating_sub(RESERVATION) && db.renew_idempotency_key(key, reserved_at)? =>
+                    warn!("Request with Idempotency-Key {} was abandoned, running it again", key),
+                None => {
+                    info!("Request with Idempotency-Key {} still running", key);
+                    return Err(Error::AlreadyExists);
+                }
+            }
+        }
+
+        let result = f(db);
+        match result {
+            Ok(ref response) if response.status.class().is_success() =>
+                db.settle_idempotency_key(key, response.status.code, &response.body)?,
+            _ => db.release_idempotency_key(key)?,
+        }
+        result
+    }
+}
+
+impl DBAccess {
+    /// `false` if the key is already taken.
+    fn reserve_idempotency_key(&mut self, key: &str, hash: &str) -> GMResult<bool> {
+        match self.0.prep_exec(r"INSERT INTO idempotency_keys(idem_key, request_hash, reserved_at) VALUES (?, ?, ?)", (key, hash, now())) {
+            Ok(_) => Ok(true),
+            // ER_DUP_ENTRY
+            Err(::mysql::Error::MySqlError(ref e)) if e.code == 1062 => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Request hash, when the key was reserved, and the response if the request has finished.
+    fn lookup_idempotency_key(&mut self, key: &str) -> GMResult<Option<(String, u64, Option<StoredResponse>)>> {
+        let row: Option<(String, u64, Option<u16>, Option<String>)> =
+            self.0.first_exec(r"SELECT request_hash, reserved_at, status, body FROM idempotency_keys WHERE idem_key=?", (key, ))?;
+        Ok(row.map(|(hash, reserved_at, status, body)| {
+            let response = status.map(|status| StoredResponse {
+                status: Status::from_code(status).expect("Unknown status stored"),
+                body: body.unwrap_or_default(),
+            });
+            (hash, reserved_at, response)
+        }))
+    }
+
+    /// Reserve an unfinished key again. `false` if another retry got there first.
+    fn renew_idempotency_key(&mut self, key: &str, reserved_at: u64) -> GMResult<bool> {
+        let renewed = self.0.prep_exec(r"UPDATE idempotency_keys SET reserved_at=? WHERE idem_key=? AND status IS NULL AND reserved_at=?",
+                                       (now(), key, reserved_at))?.affected_rows();
+        Ok(renewed == 1)
+    }
+
+    fn purge_idempotency_keys(&mut self, reserved_before: u64) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM idempotency_keys WHERE reserved_at<?", (reserved_before, ))?;
+
+        Ok(())
+    }
+
+    fn settle_idempotency_key(&mut self, key: &str, status: u16, body: &str) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE idempotency_keys SET status=?, body=? WHERE idem_key=?", (status, body, key))?;
+
+        Ok(())
+    }
+
+    fn release_idempotency_key(&mut self, key: &str) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM idempotency_keys WHERE idem_key=?", (key, ))?;
+
+        Ok(())
+    }
+}
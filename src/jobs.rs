@@ -0,0 +1,548 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Background jobs for operations too long to hold a request for.
+//!
+//! Jobs are kept in DB, so they survive restarts and may be picked up by any instance.
+//! A worker claims a job by moving it from pending to running, and keeps its heartbeat fresh while running it.
+//! A running job whose heartbeat is older than `job_timeout` was abandoned, e.g. its instance died,
+//! and gets claimed again. Jobs must therefore be safe to run more than once.
+
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use ::{provision_repo, CreateRepo, DBAccess, DBAccessPool, Error, GMResult, RepoConfig, Uuid};
+use deadline::{parse_ddl, set_deadline};
+use err::{optional, ErrorSummary};
+use gitserver::GitServerAPI;
+use template::FIX_BRANCH;
+
+use chrono::FixedOffset;
+use rocket::Request;
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::{Responder, Response};
+use serde_json::Value;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Job<'a> {
+    DeleteCourse {
+        course_uid: Cow<'a, str>,
+    },
+    DeleteAssignment {
+        course_uid: Cow<'a, str>,
+        assignment_uid: Cow<'a, str>,
+    },
+    BulkCreateRepos {
+        course_uid: Cow<'a, str>,
+        assignment_uid: Cow<'a, str>,
+        #[serde(borrow)]
+        repos: Vec<CreateRepo<'a>>,
+    },
+    SetAssignmentDeadline {
+        course_uid: Cow<'a, str>,
+        assignment_uid: Cow<'a, str>,
+        ddl: Cow<'a, str>,
+        /// Repos at the time of submitting
+        repos: Vec<Cow<'a, str>>,
+    },
+    PushTemplateFix {
+        course_uid: Cow<'a, str>,
+        assignment_uid: Cow<'a, str>,
+        /// Branch or commit of the template with the fix
+        git_ref: Cow<'a, str>,
+        /// Where the fix starts from, the default branch of the template if `None`
+        base: Option<Cow<'a, str>>,
+        merge_request: bool,
+        /// Repos at the time of submitting
+        repos: Vec<Cow<'a, str>>,
+    },
+}
+
+impl<'a> Job<'a> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Job::DeleteCourse { .. } => "delete_course",
+            Job::DeleteAssignment { .. } => "delete_assignment",
+            Job::BulkCreateRepos { .. } => "bulk_create_repos",
+            Job::SetAssignmentDeadline { .. } => "set_assignment_deadline",
+            Job::PushTemplateFix { .. } => "push_template_fix",
+        }
+    }
+
+    /// Steps reported as progress.
+    fn total(&self) -> usize {
+        match self {
+            Job::BulkCreateRepos { repos, .. } => repos.len(),
+            Job::SetAssignmentDeadline { repos, .. } => repos.len(),
+            Job::PushTemplateFix { repos, .. } => repos.len(),
+            _ => 1,
+        }
+    }
+}
+
+/// `202 Accepted` pointing at the job.
+pub struct Accepted(pub u64);
+
+impl<'r> Responder<'r> for Accepted {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        Ok(Response::build()
+            .status(Status::Accepted)
+            .header(ContentType::JSON)
+            .header(Header::new("Location", format!("/jobs/{}", self.0)))
+            .sized_body(Cursor::new(format!(r#"{{"job_id":{}}}"#, self.0)))
+            .finalize())
+    }
+}
+
+#[derive(Serialize)]
+pub struct JobStatus {
+    id: u64,
+    kind: String,
+    /// `pending`, `running`, `succeeded` or `failed`
+    status: String,
+    progress: u64,
+    total: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum BulkResult {
+    Created { ssh_url_to_repo: String },
+    Updated,
+    Pushed,
+    Opened { merge_request_url: String },
+    /// Left alone, e.g. the fix branch is there already
+    Conflict,
+    Skipped,
+    Failed { error: ErrorSummary },
+}
+
+#[derive(Serialize)]
+struct BulkReport<'a> {
+    repo_name: &'a str,
+    #[serde(flatten)]
+    result: BulkResult,
+}
+
+/// Everything jobs need, taken from managed state as the queue starts.
+pub(crate) struct JobContext {
+    pub pool: DBAccessPool,
+    pub git_server: GitServerAPI,
+    pub token_salt: String,
+    pub middleware_base: String,
+    pub safe_network: bool,
+    pub timezone: FixedOffset,
+    pub bulk_concurrency: usize,
+}
+
+impl JobContext {
+    fn repo_config(&self) -> RepoConfig {
+        RepoConfig {
+            token_salt: &self.token_salt,
+            middleware_base: &self.middleware_base,
+            safe_network: self.safe_network,
+            timezone: self.timezone,
+        }
+    }
+}
+
+pub struct JobQueueConfig {
+    pub workers: usize,
+    pub poll_interval: Duration,
+    pub timeout: Duration,
+}
+
+struct Signal {
+    stop: bool,
+    /// Bumped on every notify, so a thread busy polling doesn't miss a wakeup.
+    notified: u64,
+}
+
+/// Wakes background threads sleeping between polls, and tells them to stop.
+pub(crate) struct Wakeup {
+    signal: Mutex<Signal>,
+    condvar: Condvar,
+}
+
+impl Wakeup {
+    pub(crate) fn new() -> Arc<Wakeup> {
+        Arc::new(Wakeup { signal: Mutex::new(Signal { stop: false, notified: 0 }), condvar: Condvar::new() })
+    }
+
+    pub(crate) fn notify(&self) {
+        self.signal.lock().unwrap().notified += 1;
+        self.condvar.notify_one();
+    }
+
+    pub(crate) fn stop(&self) {
+        self.signal.lock().unwrap().stop = true;
+        self.condvar.notify_all();
+    }
+
+    /// Take before polling and pass to `wait` after. `None` once stopped.
+    pub(crate) fn mark(&self) -> Option<u64> {
+        let signal = self.signal.lock().unwrap();
+        if signal.stop { None } else { Some(signal.notified) }
+    }
+
+    /// Sleep up to `timeout`, unless notified or stopped since `mark`.
+    pub(crate) fn wait(&self, mark: u64, timeout: Duration) {
+        let signal = self.signal.lock().unwrap();
+        if !signal.stop && signal.notified == mark {
+            let _ = self.condvar.wait_timeout(signal, timeout);
+        }
+    }
+}
+
+pub struct JobQueue {
+    wakeup: Arc<Wakeup>,
+}
+
+impl JobQueue {
+    pub(crate) fn start(config: JobQueueConfig, context: JobContext) -> JobQueue {
+        let wakeup = Wakeup::new();
+        let context = Arc::new(context);
+        for i in 0..config.workers {
+            let (wakeup, context) = (wakeup.clone(), context.clone());
+            let (poll_interval, timeout) = (config.poll_interval, config.timeout);
+            thread::Builder::new()
+                .name(format!("job-worker-{}", i))
+                .spawn(move || work(&wakeup, &context, poll_interval, timeout))
+                .expect("Failed to start job worker");
+        }
+        JobQueue { wakeup }
+    }
+
+    pub(crate) fn submit(&self, db: &mut DBAccess, job: &Job) -> GMResult<u64> {
+        let id = db.insert_job(job.kind(), &serde_json::to_string(job)?, job.total() as u64)?;
+        self.wakeup.notify();
+        Ok(id)
+    }
+}
+
+impl Drop for JobQueue {
+    fn drop(&mut self) {
+        self.wakeup.stop();
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    time::get_time().sec as u64
+}
+
+fn work(wakeup: &Wakeup, context: &JobContext, poll_interval: Duration, timeout: Duration) {
+    while let Some(mark) = wakeup.mark() {
+        let claimed = context.pool.connect().and_then(|mut db| db.claim_job(now().saturating_sub(timeout.as_secs())));
+        match claimed {
+            Ok(Some((id, payload))) => {
+                run(context, id, &payload, timeout);
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to look for jobs: {:?}", e),
+        }
+        wakeup.wait(mark, poll_interval);
+    }
+}
+
+fn run(context: &JobContext, id: u64, payload: &str, timeout: Duration) {
+    info!("Running job {}", id);
+    let (done, beat) = channel::<()>();
+    let result = thread::scope(|scope| {
+        scope.spawn(move || {
+            // fresh enough to not be taken as abandoned
+            while let Err(RecvTimeoutError::Timeout) = beat.recv_timeout(timeout / 3) {
+                if let Err(e) = context.pool.connect().and_then(|mut db| db.job_heartbeat(id, now())) {
+                    warn!("Failed to keep job {} alive: {:?}", id, e);
+                }
+            }
+        });
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            serde_json::from_str(payload).map_err(Error::from).and_then(|job| execute(context, id, &job))
+        })).unwrap_or_else(|_| Err(Error::new("Job panicked")));
+        drop(done);
+        result
+    });
+    let finished = context.pool.connect().and_then(|mut db| match result {
+        Ok(ref value) => {
+            info!("Job {} succeeded", id);
+            db.finish_job(id, "succeeded", Some(&value.to_string()), None)
+        }
+        Err(ref e) => {
+            warn!("Job {} failed: {:?}", id, e);
+            db.finish_job(id, "failed", None, Some(&serde_json::to_string(&e.summary()).expect("What?")))
+        }
+    });
+    if let Err(e) = finished {
+        error!("Failed to record the end of job {}: {:?}", id, e);
+    }
+}
+
+fn execute(context: &JobContext, id: u64, job: &Job) -> GMResult<Value> {
+    let git_server = &*context.git_server;
+    match job {
+        Job::DeleteCourse { course_uid } => {
+            let course_uid = Uuid::parse(Cow::Borrowed(course_uid))?;
+            let mut db = context.pool.connect()?;
+            let course_id = match db.translate_uuid(&course_uid.parsed) {
+                // done in an earlier run
+                Err(Error::NotFound) => return Ok(Value::Null),
+                other => other?,
+            };
+            match git_server.list_assignments(course_id) {
+                Ok(assignments) => for assignment in assignments {
+                    optional(db.forget_assignment_template(assignment))?;
+                    db.forget_assignment_events(assignment)?;
+                    db.forget_uuid_by_id(assignment)?;
+                },
+                Err(Error::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+            match git_server.delete_group(course_id) {
+                Ok(()) | Err(Error::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+            db.forget_course_staff(course_id)?;
+            db.forget_uuid_by_id(course_id)?;
+            info!("Deleted course {}", &course_uid.original);
+            Ok(Value::Null)
+        }
+        Job::DeleteAssignment { course_uid, assignment_uid } => {
+            let assignment_uid = Uuid::parse(Cow::Borrowed(assignment_uid))?;
+            let mut db = context.pool.connect()?;
+            let assignment_id = match db.translate_uuid(&assignment_uid.parsed) {
+                Err(Error::NotFound) => return Ok(Value::Null),
+                other => other?,
+            };
+            match git_server.delete_group(assignment_id) {
+                Ok(()) | Err(Error::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+            optional(db.forget_assignment_template(assignment_id))?;
+            db.forget_assignment_events(assignment_id)?;
+            db.forget_uuid_by_id(assignment_id)?;
+            info!("Deleted assignment {} from {}", &assignment_uid.original, course_uid);
+            Ok(Value::Null)
+        }
+        Job::BulkCreateRepos { course_uid, assignment_uid, repos } => {
+            let course_uid = Uuid::parse(Cow::Borrowed(course_uid))?;
+            let assignment_uid = Uuid::parse(Cow::Borrowed(assignment_uid))?;
+            let progress = context.pool.connect()?;
+            let progress = Mutex::new(progress);
+            let reports = bulk_create_repos(context, &course_uid, &assignment_uid, repos, &|| {
+                if let Err(e) = progress.lock().unwrap().job_progress(id) {
+                    warn!("Failed to record progress of job {}: {:?}", id, e);
+                }
+            });
+            info!("Bulk created repos for assignment {} in course {}", &assignment_uid.original, &course_uid.original);
+            Ok(serde_json::to_value(reports)?)
+        }
+        Job::SetAssignmentDeadline { course_uid, assignment_uid, ddl, repos } => {
+            let course_uid = Uuid::parse(Cow::Borrowed(course_uid))?;
+            let assignment_uid = Uuid::parse(Cow::Borrowed(assignment_uid))?;
+            let (deadline, expires_at) = parse_ddl(ddl, context.timezone)?;
+            let mut db = context.pool.connect()?;
+            let mut reports = Vec::with_capacity(repos.len());
+            for repo_name in repos {
+                let result = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, repo_name)
+                    .and_then(|repo| set_deadline(&mut db, git_server, repo, deadline, &expires_at, None));
+                let result = match result {
+                    Ok(()) => BulkResult::Updated,
+                    // deleted since
+                    Err(Error::NotFound) => BulkResult::Skipped,
+                    Err(e) => {
+                        warn!("Failed to set deadline of repo {}: {:?}", repo_name, e);
+                        BulkResult::Failed { error: e.summary() }
+                    }
+                };
+                reports.push(BulkReport { repo_name, result });
+                db.job_progress(id)?;
+            }
+            info!("Set deadline of assignment {} in course {} to {}", &assignment_uid.original, &course_uid.original, ddl);
+            Ok(serde_json::to_value(reports)?)
+        }
+        Job::PushTemplateFix { course_uid, assignment_uid, git_ref, base, merge_request, repos } => {
+            let course_uid = Uuid::parse(Cow::Borrowed(course_uid))?;
+            let assignment_uid = Uuid::parse(Cow::Borrowed(assignment_uid))?;
+            let mut db = context.pool.connect()?;
+            let assignment = db.translate_uuid(&assignment_uid.parsed)?;
+            let template = db.assignment_template(assignment)?.ok_or(Error::NotFound)?;
+            let changes = git_server.changed_files(template.repo_id, base.as_deref(), git_ref)?;
+            let message = format!("Fix starter code\n\nFrom {} of {}", git_ref, template.path);
+            let mut reports = Vec::with_capacity(repos.len());
+            for repo_name in repos {
+                let result = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, repo_name).and_then(|repo| {
+                    if !git_server.push_branch(repo, FIX_BRANCH, &message, &changes)? {
+                        return Ok(BulkResult::Skipped);
+                    }
+                    if !*merge_request {
+                        return Ok(BulkResult::Pushed);
+                    }
+                    let description = format!("The starter code was fixed in {} of {}. Merge to get the fix.", git_ref, template.path);
+                    let merge_request_url = git_server.open_merge_request(repo, FIX_BRANCH, "Fix starter code", &description)?;
+                    Ok(BulkResult::Opened { merge_request_url })
+                });
+                let result = match result {
+                    Ok(result) => result,
+                    Err(Error::AlreadyExists) => BulkResult::Conflict,
+                    // deleted since
+                    Err(Error::NotFound) => BulkResult::Skipped,
+                    Err(e) => {
+                        warn!("Failed to push template fix to repo {}: {:?}", repo_name, e);
+                        BulkResult::Failed { error: e.summary() }
+                    }
+                };
+                reports.push(BulkReport { repo_name, result });
+                db.job_progress(id)?;
+            }
+            info!("Pushed {} of template {} to assignment {} in course {}", git_ref, template.path, &assignment_uid.original, &course_uid.original);
+            Ok(serde_json::to_value(reports)?)
+        }
+    }
+}
+
+/// Provision `repos`, `bulk_concurrency` at a time. Existing ones are skipped.
+fn bulk_create_repos<'a>(context: &JobContext, course_uid: &Uuid, assignment_uid: &Uuid, repos: &'a [CreateRepo<'a>],
+                         step: &(dyn Fn() + Sync)) -> Vec<BulkReport<'a>> {
+    let config = context.repo_config();
+    let git_server = &*context.git_server;
+    let results: Vec<Mutex<Option<BulkResult>>> = repos.iter().map(|_| Mutex::new(None)).collect();
+    // a repo listed twice is only created once
+    for (i, repo) in repos.iter().enumerate() {
+        if repos[..i].iter().any(|r| r.repo_name == repo.repo_name) {
+            *results[i].lock().unwrap() = Some(BulkResult::Skipped);
+            step();
+        }
+    }
+    let next = AtomicUsize::new(0);
+    let workers = context.bulk_concurrency.max(1).min(repos.len());
+    trace!("Creating {} repos with {} workers", repos.len(), workers);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                let mut db = match context.pool.connect() {
+                    Ok(db) => db,
+                    Err(e) => return warn!("Bulk worker gave up: {:?}", e),
+                };
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= repos.len() {
+                        break;
+                    }
+                    if results[i].lock().unwrap().is_some() {
+                        continue;
+                    }
+                    let result = match provision_repo(course_uid, assignment_uid, &repos[i], &config, &mut db, git_server) {
+                        Ok(ssh_url_to_repo) => BulkResult::Created { ssh_url_to_repo },
+                        Err(Error::AlreadyExists) => BulkResult::Skipped,
+                        Err(e) => {
+                            warn!("Failed to create repo {}: {:?}", repos[i].repo_name, e);
+                            BulkResult::Failed { error: e.summary() }
+                        }
+                    };
+                    *results[i].lock().unwrap() = Some(result);
+                    step();
+                }
+            });
+        }
+    });
+
+    repos.iter().zip(results).map(|(repo, result)| BulkReport {
+        repo_name: repo.repo_name,
+        // left behind only if no worker got a DB connection
+        result: result.into_inner().unwrap().unwrap_or_else(|| BulkResult::Failed { error: Error::new("No DB connection available").summary() }),
+    }).collect()
+}
+
+/// kind, status, progress, total, result, error
+type JobRow = (String, String, u64, u64, Option<String>, Option<String>);
+
+impl DBAccess {
+    fn insert_job(&mut self, kind: &str, payload: &str, total: u64) -> GMResult<u64> {
+        let now = now();
+        let result = self.0.prep_exec(r"INSERT INTO jobs(kind, payload, status, progress, total, created_at, heartbeat) VALUES (?, ?, 'pending', 0, ?, ?, ?)",
+                                      (kind, payload, total, now, now))?;
+        Ok(result.last_insert_id())
+    }
+
+    /// Take the oldest pending or abandoned job. Id and payload. An abandoned job starts over, progress too.
+    fn claim_job(&mut self, abandoned_before: u64) -> GMResult<Option<(u64, String)>> {
+        loop {
+            let id: Option<u64> = self.0.first_exec(
+                r"SELECT id FROM jobs WHERE status='pending' OR (status='running' AND heartbeat<?) ORDER BY id LIMIT 1",
+                (abandoned_before, ))?;
+            let id = match id {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            // another worker may have been faster
+            let claimed = self.0.prep_exec(
+                r"UPDATE jobs SET status='running', progress=0, heartbeat=? WHERE id=? AND (status='pending' OR (status='running' AND heartbeat<?))",
+                (now(), id, abandoned_before))?.affected_rows();
+            if claimed == 1 {
+                let payload: String = self.0.first_exec(r"SELECT payload FROM jobs WHERE id=?", (id, ))?.ok_or(Error::NotFound)?;
+                return Ok(Some((id, payload)));
+            }
+        }
+    }
+
+    fn job_heartbeat(&mut self, id: u64, now: u64) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE jobs SET heartbeat=? WHERE id=?", (now, id))?;
+
+        Ok(())
+    }
+
+    fn job_progress(&mut self, id: u64) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE jobs SET progress=progress+1, heartbeat=? WHERE id=?", (now(), id))?;
+
+        Ok(())
+    }
+
+    fn finish_job(&mut self, id: u64, status: &str, result: Option<&str>, error: Option<&str>) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE jobs SET status=?, result=?, error=?, progress=CASE WHEN ?='succeeded' THEN total ELSE progress END WHERE id=?",
+                         (status, result, error, status, id))?;
+
+        Ok(())
+    }
+
+    pub(crate) fn load_job(&mut self, id: u64) -> GMResult<JobStatus> {
+        let row: Option<JobRow> = self.0.first_exec(
+            r"SELECT kind, status, progress, total, result, error FROM jobs WHERE id=?", (id, ))?;
+        let (kind, status, progress, total, result, error) = row.ok_or(Error::NotFound)?;
+        Ok(JobStatus {
+            id,
+            kind,
+            status,
+            progress,
+            total,
+            result: result.map(|r| serde_json::from_str(&r)).transpose()?.filter(|r: &Value| !r.is_null()),
+            error: error.map(|e| serde_json::from_str(&e)).transpose()?,
+        })
+    }
+}
@@ -32,39 +32,83 @@ extern crate serde_derive;
 extern crate url;
 extern crate percent_encoding;
 extern crate hex;
+extern crate hmac;
 extern crate sha2;
 extern crate uuid;
 extern crate time;
+extern crate chrono;
+extern crate base64;
+extern crate flate2;
+extern crate tar;
+extern crate zip;
+#[cfg(test)]
+extern crate msql_srv;
+#[cfg(test)]
+extern crate rusqlite;
+#[cfg(test)]
+extern crate tiny_http;
 
 use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
 use std::net::ToSocketAddrs;
 use std::io::Cursor;
 use std::str::Utf8Error;
 use std::ops::Deref;
+use std::time::Duration;
 
-use reqwest::Method;
 use reqwest::header::HeaderValue;
 
-use rocket::State;
+use rocket::{Rocket, State};
 use rocket::fairing::AdHoc;
 use rocket::http::{ContentType, Header, RawStr, Status};
-use rocket::request::{FromParam, FromFormValue};
-use rocket::response::Response;
+use rocket::http::uri::Segments;
+use rocket::{Outcome, Request};
+use rocket::request::{self, FromParam, FromFormValue, FromRequest, LenientForm};
+use rocket::response::{Body, Response};
 
 use rocket_contrib::databases::mysql;
 use rocket_contrib::json::{Json, JsonValue};
 
-use serde_json::Value;
-
 use mysql::uuid::Uuid as UuidRaw;
 
 use url::Url;
 
+use chrono::FixedOffset;
+
 mod apis;
+mod cursor;
+mod deadline;
+mod events;
 mod err;
+mod export;
+mod gitserver;
+mod gitlab;
+mod gitea;
+mod idempotency;
+mod jobs;
+mod outbox;
+mod rollback;
+mod staff;
+mod submission;
+mod template;
+#[cfg(test)]
+mod tests;
 
 use apis::*;
+use deadline::{owner_access, parse_ddl, parse_timezone, set_deadline, DeadlineEnforcer};
 use err::*;
+use events::{forward_event, AssignmentEvents, EventKind};
+use export::{check_format, export, pin_repos};
+use gitserver::*;
+use gitlab::GitLabAPI;
+use gitea::GiteaAPI;
+use idempotency::{IdempotencyKey, StoredResponse};
+use jobs::{Accepted, Job, JobContext, JobQueue, JobQueueConfig, JobStatus};
+use outbox::{DeadLetter, Forwarder, Outbox, OutboxConfig};
+use rollback::with_rollback;
+use staff::{add_staff, change_staff, list_staff, remove_staff, StaffMember, StaffRole};
+use submission::SubmissionTags;
+use template::Template;
 use err::Error::NotFound;
 
 struct Uuid<'a> {
@@ -76,9 +120,14 @@ impl<'a> FromParam<'a> for Uuid<'a> {
     type Error = Error;
 
     fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
-        let decoded = param.percent_decode().map_err(|_| Error::NotFound)?;
-        let parsed = UuidRaw::parse_str(&decoded).map_err(|_| Error::NotFound)?;
-        Ok(Uuid { parsed, original: decoded })
+        Uuid::parse(param.percent_decode().map_err(|_| Error::NotFound)?)
+    }
+}
+
+impl<'a> Uuid<'a> {
+    fn parse(original: Cow<'a, str>) -> GMResult<Self> {
+        let parsed = UuidRaw::parse_str(&original).map_err(|_| Error::NotFound)?;
+        Ok(Uuid { parsed, original })
     }
 }
 
@@ -88,8 +137,28 @@ struct ForwardedWebHookRequest<'a> {
     // course_uid: &'a str,
     assignment_uid: &'a str,
     upstream: &'a str,
+    /// `None` if the repo is no longer known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repo_name: Option<String>,
+    #[serde(rename = "ref")]
+    git_ref: &'a str,
+    /// `None` unless a branch is pushed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    branch: Option<&'a str>,
+    /// The commit to grade
+    after: &'a str,
+    /// `None` if the pusher wasn't created through the middleware, e.g. an admin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pusher_email: Option<String>,
+    /// When the push was received, RFC 3339 in UTC
+    pushed_at: String,
+    /// Received after the deadline of the pusher
+    late: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     additional_data: Option<String>,
+    /// `None` unless a submission tag is pushed, making it an explicit submission
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
 }
 
 impl<'a> APIFunction for ForwardedWebHookRequest<'a> {
@@ -98,46 +167,146 @@ impl<'a> APIFunction for ForwardedWebHookRequest<'a> {
     }
 }
 
-#[post("/hooks/<_course>/<assignment>?<data>", data = "<message>")]
+/// `data` of a hook url, as given when the repo was created.
+fn decode_data(data: Option<String>) -> Option<String> {
+    data.map(|d| ::percent_encoding::percent_decode(d.as_bytes()).decode_utf8().unwrap().into_owned())
+}
+
+/// Queue `push` to the backend as a submission, explicit if it is a submission `tag`.
+fn forward_submission(forwarder: &mut Forwarder, assignment_uid: &str, push: &PushEvent, data: Option<String>, tag: Option<&str>)
+                      -> GMResult<u64> {
+    let now = time::now_utc();
+    let pushed_at = now.rfc3339().to_string();
+    let ddl = optional(forwarder.db.owner_deadline(push.repo_id, push.pusher_id))?.flatten();
+    let request = ForwardedWebHookRequest {
+        assignment_uid,
+        upstream: push.upstream,
+        repo_name: optional(forwarder.db.repo_name(push.repo_id))?,
+        git_ref: push.git_ref,
+        branch: push.git_ref.strip_prefix("refs/heads/"),
+        after: push.after,
+        pusher_email: optional(forwarder.db.user_email(push.pusher_id))?,
+        pushed_at,
+        late: ddl.map_or(false, |ddl| now.to_timespec().sec > ddl as i64),
+        additional_data: decode_data(data),
+        tag,
+    };
+    forwarder.forward(&request)
+}
+
+#[post("/hooks/<_course>/<assignment>?<data>", data = "<message>", rank = 1)]
 fn webhook(_course: Uuid, assignment: Uuid, message: Json<JsonValue>, data: Option<String>,
            _event: Push,
-           backend: State<BackendAPI>)
+           mut forwarder: Forwarder, git_server: State<GitServerAPI>)
            -> GMResult<()> {
-    trace!("Forwarding webhook");
-    let upstream = message["project"]["git_ssh_url"].as_str().expect("Schema changed");
-    let additional_data = data.map(|d| ::percent_encoding::percent_decode(d.as_bytes()).decode_utf8().unwrap().into_owned());
-    let request = ForwardedWebHookRequest { assignment_uid: &assignment.original, upstream, additional_data };
-    backend.call(&request)?.error_for_status()?;
-    info!("Forwarded webhook for {}", upstream);
+    trace!("Queueing webhook");
+    let push = git_server.parse_push(&message).ok_or(Error::BadRequest("Push without a repo"))?;
+    // gitea sends tags as pushes too, they are left to `tag_webhook`
+    if push.git_ref.starts_with("refs/tags/") {
+        trace!("Ignored push of {} to {}", push.git_ref, push.upstream);
+        return Ok(());
+    }
+    let id = forward_submission(&mut forwarder, &assignment.original, &push, data, None)?;
+    info!("Queued webhook {} for {} at {}", id, push.upstream, push.after);
     Ok(())
 }
 
-#[derive(Deserialize)]
-struct CreateUser<'a> {
-    email: &'a str,
-    password: &'a str,
+/// Only submission tags are forwarded, unless the assignment turned them off.
+#[allow(clippy::too_many_arguments)]
+#[post("/hooks/<_course>/<assignment>?<data>", data = "<message>", rank = 2)]
+fn tag_webhook(_course: Uuid, assignment: Uuid, message: Json<JsonValue>, data: Option<String>,
+               _event: TagPush, submission_tags: State<SubmissionTags>,
+               mut forwarder: Forwarder, git_server: State<GitServerAPI>)
+               -> GMResult<()> {
+    if !forwarder.db.hook_events(&assignment.parsed)?.tag_push {
+        trace!("Submission tags of {} are off", &assignment.original);
+        return Ok(());
+    }
+    // e.g. a branch created on gitea
+    let tag = match git_server.parse_tag_push(&message) {
+        Some(tag) => tag,
+        None => return Ok(()),
+    };
+    let sha = match tag.sha {
+        Some(sha) if submission_tags.matches(tag.tag) => sha,
+        _ => {
+            trace!("Ignored tag {} of {}", tag.tag, tag.upstream);
+            return Ok(());
+        }
+    };
+    let git_ref = format!("refs/tags/{}", tag.tag);
+    let push = PushEvent { upstream: tag.upstream, repo_id: tag.repo_id, pusher_id: tag.pusher_id, git_ref: &git_ref, after: sha };
+    let id = forward_submission(&mut forwarder, &assignment.original, &push, data, Some(tag.tag))?;
+    info!("Queued submission tag {} as webhook {} for {} at {}", tag.tag, id, tag.upstream, sha);
+    Ok(())
 }
 
-#[derive(Serialize)]
-struct CreateUserGitLab<'a> {
+/// Queue an event the assignment opted into, see `events`.
+fn webhook_event(kind: EventKind, assignment: &Uuid, message: &JsonValue, data: Option<String>,
+                 forwarder: &mut Forwarder, git_server: &dyn GitServer) -> GMResult<()> {
+    match forward_event(kind, forwarder, git_server, &assignment.parsed, &assignment.original, message, decode_data(data))? {
+        Some(id) => info!("Queued {:?} event as webhook {} for assignment {}", kind, id, &assignment.original),
+        None => trace!("Ignored {:?} event for assignment {}", kind, &assignment.original),
+    }
+    Ok(())
+}
+
+#[post("/hooks/<_course>/<assignment>?<data>", data = "<message>", rank = 3)]
+fn merge_request_webhook(_course: Uuid, assignment: Uuid, message: Json<JsonValue>, data: Option<String>,
+                         _event: MergeRequest,
+                         mut forwarder: Forwarder, git_server: State<GitServerAPI>)
+                         -> GMResult<()> {
+    webhook_event(EventKind::MergeRequest, &assignment, &message, data, &mut forwarder, &**git_server)
+}
+
+#[post("/hooks/<_course>/<assignment>?<data>", data = "<message>", rank = 4)]
+fn note_webhook(_course: Uuid, assignment: Uuid, message: Json<JsonValue>, data: Option<String>,
+                _event: Note,
+                mut forwarder: Forwarder, git_server: State<GitServerAPI>)
+                -> GMResult<()> {
+    webhook_event(EventKind::Note, &assignment, &message, data, &mut forwarder, &**git_server)
+}
+
+#[post("/hooks/<_course>/<assignment>?<data>", data = "<message>", rank = 5)]
+fn pipeline_webhook(_course: Uuid, assignment: Uuid, message: Json<JsonValue>, data: Option<String>,
+                    _event: Pipeline,
+                    mut forwarder: Forwarder, git_server: State<GitServerAPI>)
+                    -> GMResult<()> {
+    webhook_event(EventKind::Pipeline, &assignment, &message, data, &mut forwarder, &**git_server)
+}
+
+/// Any other event the git server sends.
+#[post("/hooks/<_course>/<_assignment>", rank = 6)]
+fn unknown_webhook(_course: Uuid, _assignment: Uuid) -> GMResult<()> {
+    Err(Error::BadRequest("Unsupported event"))
+}
+
+#[get("/webhooks/dead")]
+fn dead_webhooks(mut db: DBAccess) -> GMResult<Json<Vec<DeadLetter>>> {
+    Ok(Json(db.dead_letters()?))
+}
+
+#[post("/webhooks/dead/replay?<id>")]
+fn replay_webhooks(id: Option<u64>, mut db: DBAccess, outbox: State<Outbox>) -> GMResult<JsonValue> {
+    let replayed = outbox.replay(&mut db, id)?;
+    info!("Replaying {} dead webhooks", replayed);
+    Ok(json!({"replayed": replayed}))
+}
+
+#[derive(Deserialize, Serialize)]
+struct CreateUser<'a> {
     email: &'a str,
-    username: &'a str,
     password: &'a str,
-    name: &'a str,
 }
 
-impl<'a> CreateUserGitLab<'a> {
-    fn from(inbound: &'a CreateUser<'a>) -> Result<Self, ()> {
-        if let Some(at_pos) = inbound.email.find('@') {
-            if &inbound.email[0..at_pos] == "admin" {
+impl<'a> CreateUser<'a> {
+    /// Username on git server is the local part of email.
+    fn username(&self) -> Result<&'a str, ()> {
+        if let Some(at_pos) = self.email.find('@') {
+            if &self.email[0..at_pos] == "admin" {
                 Err(())
             } else {
-                Ok(CreateUserGitLab {
-                    email: inbound.email,
-                    username: &inbound.email[0..at_pos],
-                    password: inbound.password,
-                    name: &inbound.email[0..at_pos],
-                })
+                Ok(&self.email[0..at_pos])
             }
         } else {
             Err(())
@@ -145,35 +314,24 @@ impl<'a> CreateUserGitLab<'a> {
     }
 }
 
-impl<'a> APIFunction for CreateUserGitLab<'a> {
-    fn path(&self) -> Cow<str> {
-        Cow::Borrowed("users")
-    }
-}
-
 #[post("/users", data = "<user>")]
-fn create_user<'a>(user: Json<CreateUser>,
-                   mut db: DBAccess, gitlab_api: State<'a, GitLabAPI>)
-                   -> GMResult<Response<'a>> {
+fn create_user(user: Json<CreateUser>, idempotency_key: IdempotencyKey,
+               mut db: DBAccess, git_server: State<GitServerAPI>)
+               -> GMResult<StoredResponse> {
     trace!("Creating user {}", &user.email);
-    let mut builder = Response::build();
     if user.password.len() < 8 {
-        return Ok(builder.status(Status::BadRequest)
-            .header(ContentType::JSON)
-            .sized_body(Cursor::new(r#"{"cause":"Password too short (len<8)"}"#))
-            .finalize());
-    }
-    if let Ok(outbound) = CreateUserGitLab::from(&*user) {
-        let response: Value = gitlab_api.call(&outbound)?.json()?;
-        db.remember_uid(&user.email, response["id"].as_u64().expect("Gitlab schema changed"))?;
-    } else {
-        return Ok(builder.status(Status::BadRequest)
-            .header(ContentType::JSON)
-            .sized_body(Cursor::new(r#"{"cause":"Invalid email"}"#))
-            .finalize());
+        return Err(Error::BadRequest("Password too short (len<8)"));
     }
-    info!("Created user {}", &user.email);
-    Ok(builder.status(Status::Created).finalize())
+    let username = match user.username() {
+        Ok(username) => username,
+        Err(_) => return Err(Error::BadRequest("Invalid email")),
+    };
+    idempotency_key.run(&*user, &mut db, |db| {
+        let id = git_server.create_user(user.email, username, user.password)?;
+        db.remember_uid(&user.email, id)?;
+        info!("Created user {}", &user.email);
+        Ok(StoredResponse::new(Status::Created))
+    })
 }
 
 #[get("/users/<email>")]
@@ -191,27 +349,6 @@ struct UpdateKey {
     key: String,
 }
 
-#[derive(Serialize)]
-struct AddKeyGitlab<'a> {
-    #[serde(skip)]
-    id: u64,
-    title: &'static str,
-    // we are only giving one PK, so use default
-    key: &'a str,
-}
-
-impl<'a> AddKeyGitlab<'a> {
-    fn new(id: u64, key: &'a str) -> Self {
-        AddKeyGitlab { id, title: "key", key }
-    }
-}
-
-impl<'a> APIFunction for AddKeyGitlab<'a> {
-    fn path(&self) -> Cow<str> {
-        Cow::Owned(format!("users/{}/keys", self.id))
-    }
-}
-
 struct StrInUri<'a>(Cow<'a, str>);
 
 impl<'a> Deref for StrInUri<'a> {
@@ -246,85 +383,52 @@ impl<'a> FromFormValue<'a> for StrInUri<'a> {
 
 #[post("/users/<user_email>/key", data = "<message>")]
 fn update_key(user_email: StrInUri, message: Json<UpdateKey>,
-              mut db: DBAccess, gitlab_api: State<GitLabAPI>)
+              mut db: DBAccess, git_server: State<GitServerAPI>)
               -> GMResult<Status> {
     trace!("Updating private key for user {}", &*user_email);
     let id = db.translate_uid(&user_email)?;
-    gitlab_api.remove_keys(id)?;
-    gitlab_api.call(&AddKeyGitlab::new(id, &message.key))?;
+    git_server.replace_key(id, &message.key)?;
     info!("Updated private key for user {}", &*user_email);
     Ok(Status::Ok)
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct CreateGroup<'a> {
     name: &'a str,
     uuid: UuidRaw,
 }
 
-#[derive(Serialize)]
-struct CreateGroupGitlab<'a> {
-    name: &'a str,
-    path: &'a str,
-    visibility: &'static str,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    parent_id: Option<u64>,
-}
-
-impl<'a> From<&'a CreateGroup<'a>> for CreateGroupGitlab<'a> {
-    fn from(inbound: &'a CreateGroup) -> Self {
-        CreateGroupGitlab {
-            name: inbound.name,
-            path: inbound.name,
-            visibility: "private",
-            parent_id: None,
-        }
-    }
-}
-
-impl<'a> APIFunction for CreateGroupGitlab<'a> {
-    fn path(&self) -> Cow<str> {
-        Cow::Borrowed("groups")
-    }
-}
-
 #[post("/courses", data = "<message>")]
-fn create_course(message: Json<CreateGroup>,
-                 mut db: DBAccess, gitlab_api: State<GitLabAPI>)
-                 -> GMResult<Status> {
+fn create_course(message: Json<CreateGroup>, idempotency_key: IdempotencyKey,
+                 mut db: DBAccess, git_server: State<GitServerAPI>)
+                 -> GMResult<StoredResponse> {
     trace!("Creating course {}({})", message.name, &message.uuid);
-    let r: Value = gitlab_api.call(&CreateGroupGitlab::from(&*message))?.json()?;
-    let ret = db.remember_uuid(&message.uuid, r["id"].as_u64().expect("Gitlab schema changed")).map(|_| Status::Created);
-    info!("Created course {}({})", message.name, &message.uuid);
-    ret
+    idempotency_key.run(&*message, &mut db, |db| {
+        let id = git_server.create_course(message.name)?;
+        db.remember_uuid(&message.uuid, id)?;
+        info!("Created course {}({})", message.name, &message.uuid);
+        Ok(StoredResponse::new(Status::Created))
+    })
 }
 
 #[delete("/courses/<course_uid>")]
 fn delete_course(course_uid: Uuid,
-                 mut db: DBAccess, gitlab_api: State<GitLabAPI>) -> GMResult<()> {
-    let course_id = db.translate_uuid(&course_uid.parsed)?;
+                 mut db: DBAccess, jobs: State<JobQueue>) -> GMResult<Accepted> {
+    db.translate_uuid(&course_uid.parsed)?;
 
-    let res: JsonValue = gitlab_api.call_no_body(Method::GET, &format!("groups/{}/subgroups", course_id))?.json()?;
+    let id = jobs.submit(&mut db, &Job::DeleteCourse { course_uid: course_uid.original.clone() })?;
 
-    for assignment in res.as_array().expect("Gitlab schema changed") {
-        db.forget_uuid_by_id(assignment["id"].as_u64().expect("Gitlab schema changed"))?;
-    }
+    info!("Deleting course {} as job {}", &course_uid.original, id);
 
-    gitlab_api.call_no_body(Method::DELETE, &format!("groups/{}", course_id))?;
-
-    db.forget_uuid_by_id(course_id)?;
-
-    info!("Deleted course {}", &course_uid.original);
-
-    Ok(())
+    Ok(Accepted(id))
 }
 
 #[get("/courses/<course_uid>")]
 fn get_course(course_uid: Uuid,
-              mut db: DBAccess, gitlab_api: State<GitLabAPI>) -> GMResult<()> {
+              mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
     let course_id = db.translate_uuid(&course_uid.parsed)?;
 
-    match gitlab_api.call_no_body(Method::GET, &format!("groups/{}", course_id)) {
+    match git_server.get_group(course_id) {
         Err(NotFound) => {
             db.forget_uuid_by_id(course_id)?;
             warn!("Course {} recognized but not found in gitlab. Forgetting...", &course_uid.original);
@@ -335,56 +439,45 @@ fn get_course(course_uid: Uuid,
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct CreateAssignment<'a> {
     name: &'a str,
     uuid: UuidRaw,
 }
 
-impl<'a> CreateGroupGitlab<'a> {
-    fn assignment(inbound: &'a CreateAssignment<'a>, parent: u64) -> Self {
-        CreateGroupGitlab {
-            name: inbound.name,
-            path: inbound.name,
-            visibility: "private",
-            parent_id: Some(parent),
-        }
-    }
-}
-
 #[post("/courses/<parent_uid>/assignments", data = "<message>")]
-fn create_assignment(parent_uid: Uuid, message: Json<CreateAssignment>,
-                     mut db: DBAccess, gitlab_api: State<GitLabAPI>)
-                     -> GMResult<Status> {
-    let parent_id = db.translate_uuid(&parent_uid.parsed)?;
-    let response: Value = gitlab_api.call(&CreateGroupGitlab::assignment(&*message, parent_id))?.json()?;
-    let ret = db.remember_uuid(&message.uuid, response["id"].as_u64().expect("Gitlab schema changed"))
-        .map(|_| Status::Created);
-    info!("Created assignment {}({}) for {}", message.name, &message.uuid, &parent_uid.original);
-    ret
+fn create_assignment(parent_uid: Uuid, message: Json<CreateAssignment>, idempotency_key: IdempotencyKey,
+                     mut db: DBAccess, git_server: State<GitServerAPI>)
+                     -> GMResult<StoredResponse> {
+    idempotency_key.run(&*message, &mut db, |db| {
+        let parent_id = db.translate_uuid(&parent_uid.parsed)?;
+        let id = git_server.create_assignment(parent_id, message.name)?;
+        db.remember_uuid(&message.uuid, id)?;
+        info!("Created assignment {}({}) for {}", message.name, &message.uuid, &parent_uid.original);
+        Ok(StoredResponse::new(Status::Created))
+    })
 }
 
 #[delete("/courses/<course_uid>/assignments/<assignment_uid>")]
 fn delete_assignment(course_uid: Uuid, assignment_uid: Uuid,
-                     mut db: DBAccess, gitlab_api: State<GitLabAPI>) -> GMResult<()> {
-    let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
-
-    gitlab_api.call_no_body(Method::DELETE, &format!("groups/{}", assignment_id))?;
+                     mut db: DBAccess, jobs: State<JobQueue>) -> GMResult<Accepted> {
+    db.translate_uuid(&assignment_uid.parsed)?;
 
-    db.forget_uuid_by_id(assignment_id)?;
+    let job = Job::DeleteAssignment { course_uid: course_uid.original.clone(), assignment_uid: assignment_uid.original.clone() };
+    let id = jobs.submit(&mut db, &job)?;
 
-    info!("Deleted assignment {} from {}", &assignment_uid.original, &course_uid.original);
+    info!("Deleting assignment {} from {} as job {}", &assignment_uid.original, &course_uid.original, id);
 
-    Ok(())
+    Ok(Accepted(id))
 }
 
 #[get("/courses/<course_uid>/assignments/<assignment_uid>")]
 fn get_assignment(course_uid: Uuid, assignment_uid: Uuid,
-                  mut db: DBAccess, gitlab_api: State<GitLabAPI>) -> GMResult<()> {
+                  mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
     let course_id = db.translate_uuid(&course_uid.parsed)?;
     let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
 
-    match gitlab_api.call_no_body(Method::GET, &format!("groups/{}", course_id)) {
+    match git_server.get_group(course_id) {
         Err(NotFound) => {
             db.forget_uuid_by_id(course_id)?;
             warn!("Course {} recognized but not found in gitlab. Forgetting...", &course_uid.original);
@@ -396,7 +489,7 @@ fn get_assignment(course_uid: Uuid, assignment_uid: Uuid,
         Ok(_) => Ok(())
     }?;
 
-    match gitlab_api.call_no_body(Method::GET, &format!("groups/{}", assignment_id)) {
+    match git_server.get_group(assignment_id) {
         Err(NotFound) => {
             db.forget_uuid_by_id(course_id)?;
             warn!("Assignment {} recognized but not found in gitlab. Forgetting...", &assignment_uid.original);
@@ -412,121 +505,194 @@ struct AddInstructorToCourse<'a> {
     instructor_name: &'a str
 }
 
-#[derive(Serialize)]
-struct AddUserToGroupGitlab {
-    #[serde(skip)]
-    group_id: u64,
-    user_id: u64,
-    access_level: u8,
-}
-
-impl AddUserToGroupGitlab {
-    fn new(user_id: u64, course: u64, access_level: u8) -> Self {
-        AddUserToGroupGitlab { group_id: course, user_id, access_level }
-    }
-}
-
-impl APIFunction for AddUserToGroupGitlab {
-    fn path(&self) -> Cow<str> {
-        Cow::Owned(format!("groups/{}/members", self.group_id))
-    }
-}
-
+/// Same as adding staff with role `instructor`.
 #[post("/courses/<course_uuid>/instructors", data = "<message>")]
 fn add_instructor_to_course<'r>(course_uuid: Uuid, message: Json<AddInstructorToCourse>,
-                                mut db: DBAccess, gitlab_api: State<'r, GitLabAPI>)
+                                mut db: DBAccess, git_server: State<'r, GitServerAPI>)
                                 -> GMResult<()> {
     let course_id = db.translate_uuid(&course_uuid.parsed)?;
     let user_id = db.translate_uid(message.instructor_name)?;
-    gitlab_api.call(&AddUserToGroupGitlab::new(user_id, course_id, 50))?;
+    add_staff(&mut db, &**git_server, course_id, user_id, StaffRole::Instructor, AccessLevel::Maintainer)?;
     info!("Instructor {} added to course {}", &message.instructor_name, &course_uuid.original);
     Ok(())
 }
 
 #[derive(Deserialize)]
-struct CreateRepo<'a> {
-    owners: Vec<&'a str>,
-    repo_name: &'a str,
-    ddl: &'a str,
+struct AddStaff<'a> {
+    email: &'a str,
+    role: StaffRole,
+    /// Only TAs have a choice
     #[serde(default)]
-    additional_data: Option<Cow<'a, str>>,
+    access_level: Option<AccessLevel>,
 }
 
-#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
-#[serde(rename_all = "lowercase")]
-enum Visibility { Public, Internal, Private }
+#[derive(Deserialize)]
+struct ChangeStaff {
+    role: StaffRole,
+    #[serde(default)]
+    access_level: Option<AccessLevel>,
+}
 
-#[derive(Serialize)]
-struct CreateRepoGitlab<'a> {
-    name: &'a str,
-    namespace_id: u64,
-    visibility: Visibility,
+#[get("/courses/<course_uid>/staff")]
+fn list_course_staff(course_uid: Uuid,
+                     mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<Json<Vec<StaffMember>>> {
+    let course_id = db.translate_uuid(&course_uid.parsed)?;
+    Ok(Json(list_staff(&mut db, &**git_server, course_id)?))
 }
 
-impl<'a> CreateRepoGitlab<'a> {
-    fn new(name: &'a str, assignment_id: u64) -> Self {
-        CreateRepoGitlab { name, namespace_id: assignment_id, visibility: Visibility::Private }
-    }
+#[post("/courses/<course_uid>/staff", data = "<message>")]
+fn add_course_staff(course_uid: Uuid, message: Json<AddStaff>,
+                    mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<Status> {
+    let access_level = message.role.access_level(message.access_level)?;
+    let course_id = db.translate_uuid(&course_uid.parsed)?;
+    let user_id = db.translate_uid(message.email)?;
+    add_staff(&mut db, &**git_server, course_id, user_id, message.role, access_level)?;
+    info!("{} added to course {} as {:?}", message.email, &course_uid.original, message.role);
+    Ok(Status::Created)
 }
 
-impl<'a> APIFunction for CreateRepoGitlab<'a> {
-    fn path(&self) -> Cow<str> {
-        Cow::Borrowed("projects/")
-    }
+#[put("/courses/<course_uid>/staff/<user_email>", data = "<message>")]
+fn change_course_staff(course_uid: Uuid, user_email: StrInUri, message: Json<ChangeStaff>,
+                       mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
+    let access_level = message.role.access_level(message.access_level)?;
+    let course_id = db.translate_uuid(&course_uid.parsed)?;
+    let user_id = db.translate_uid(&user_email)?;
+    change_staff(&mut db, &**git_server, course_id, user_id, message.role, access_level)?;
+    info!("{} is now {:?} of course {}", &*user_email, message.role, &course_uid.original);
+    Ok(())
 }
 
-#[derive(Serialize)]
-struct CreateWebhookGitlab<'a> {
-    #[serde(skip)]
-    project_id: u64,
-    url: &'a str,
-    push_events: bool,
-    token: &'a str,
+#[delete("/courses/<course_uid>/staff/<user_email>")]
+fn remove_course_staff(course_uid: Uuid, user_email: StrInUri,
+                       mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
+    let course_id = db.translate_uuid(&course_uid.parsed)?;
+    let user_id = db.translate_uid(&user_email)?;
+    remove_staff(&mut db, &**git_server, course_id, user_id)?;
+    info!("{} removed from course {}", &*user_email, &course_uid.original);
+    Ok(())
 }
 
-impl<'a> CreateWebhookGitlab<'a> {
-    fn new(project_id: u64, url: &'a str, token: &'a str) -> Self {
-        CreateWebhookGitlab { project_id, url, push_events: true, token }
-    }
+#[get("/courses/<_course_uid>/assignments/<assignment_uid>/events")]
+fn get_events(_course_uid: Uuid, assignment_uid: Uuid,
+              mut db: DBAccess) -> GMResult<Json<AssignmentEvents>> {
+    let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
+    Ok(Json(db.assignment_events(assignment_id)?))
 }
 
-impl<'a> APIFunction for CreateWebhookGitlab<'a> {
-    fn path(&self) -> Cow<str> {
-        Cow::Owned(format!("projects/{}/hooks", self.project_id))
-    }
+/// Applies to existing repos of the assignment too. Events left out are set to their defaults.
+#[put("/courses/<_course_uid>/assignments/<assignment_uid>/events", data = "<message>")]
+fn set_events(_course_uid: Uuid, assignment_uid: Uuid, message: Json<AssignmentEvents>,
+              mut db: DBAccess) -> GMResult<()> {
+    let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
+    db.set_assignment_events(assignment_id, &message)?;
+    info!("Events of assignment {} set to {:?}", &assignment_uid.original, *message);
+    Ok(())
 }
 
-#[derive(Serialize)]
-struct AddUserToProjectGitlab<'a> {
-    #[serde(skip)]
-    project_id: u64,
-    user_id: u64,
-    access_level: u8,
-    expires_at: &'a str,
+#[get("/courses/<_course_uid>/assignments/<assignment_uid>/template")]
+fn get_template(_course_uid: Uuid, assignment_uid: Uuid,
+                mut db: DBAccess) -> GMResult<Json<Template>> {
+    let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
+    Ok(Json(db.assignment_template(assignment_id)?.ok_or(NotFound)?))
 }
 
-impl<'a> AddUserToProjectGitlab<'a> {
-    fn new(project_id: u64, user_id: u64, expires_at: &'a str) -> Self {
-        // 40 is maintainer access, so users can push
-        AddUserToProjectGitlab { project_id, user_id, access_level: 40, expires_at }
-    }
+/// Repos created afterwards are seeded from the template, existing ones are left alone.
+#[put("/courses/<_course_uid>/assignments/<assignment_uid>/template", data = "<message>")]
+fn set_template(_course_uid: Uuid, assignment_uid: Uuid, message: Json<Template>,
+                mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
+    let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
+    let mut template = message.into_inner();
+    template.repo_id = git_server.find_repo(&template.path)?;
+    db.set_assignment_template(assignment_id, &template)?;
+    info!("Template of assignment {} set to {}", &assignment_uid.original, &template.path);
+    Ok(())
 }
 
-impl<'a> APIFunction for AddUserToProjectGitlab<'a> {
-    fn path(&self) -> Cow<str> {
-        Cow::Owned(format!("projects/{}/members", self.project_id))
+#[delete("/courses/<_course_uid>/assignments/<assignment_uid>/template")]
+fn delete_template(_course_uid: Uuid, assignment_uid: Uuid,
+                   mut db: DBAccess) -> GMResult<()> {
+    let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
+    db.forget_assignment_template(assignment_id)?;
+    info!("Template of assignment {} removed", &assignment_uid.original);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct TemplateFix<'a> {
+    #[serde(rename = "ref", borrow)]
+    git_ref: Cow<'a, str>,
+    #[serde(borrow)]
+    base: Option<Cow<'a, str>>,
+    #[serde(default = "default_merge_request")]
+    merge_request: bool,
+}
+
+fn default_merge_request() -> bool {
+    true
+}
+
+/// Push a fix to the template to every repo in the assignment, on a new branch.
+#[post("/courses/<course_uid>/assignments/<assignment_uid>/template/fix", data = "<message>")]
+fn push_template_fix(course_uid: Uuid, assignment_uid: Uuid, message: Json<TemplateFix>,
+                     mut db: DBAccess, jobs: State<JobQueue>) -> GMResult<Accepted> {
+    let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
+    db.assignment_template(assignment_id)?.ok_or(NotFound)?;
+    let TemplateFix { git_ref, base, merge_request } = message.into_inner();
+    let job = Job::PushTemplateFix {
+        course_uid: course_uid.original.clone(),
+        assignment_uid: assignment_uid.original.clone(),
+        git_ref,
+        base,
+        merge_request,
+        repos: db.repo_names(&course_uid.parsed, &assignment_uid.parsed)?.into_iter().map(Cow::Owned).collect(),
+    };
+    let id = jobs.submit(&mut db, &job)?;
+    info!("Pushing template fix to assignment {} in course {} as job {}", &assignment_uid.original, &course_uid.original, id);
+    Ok(Accepted(id))
+}
+
+#[derive(Deserialize, Serialize)]
+struct CreateRepo<'a> {
+    owners: Vec<&'a str>,
+    repo_name: &'a str,
+    ddl: &'a str,
+    #[serde(default)]
+    additional_data: Option<Cow<'a, str>>,
+}
+
+/// What provisioning a repo takes from config: where its webhook points to, how it is signed,
+/// and the timezone of a `ddl` without one.
+struct RepoConfig<'r> {
+    token_salt: &'r str,
+    middleware_base: &'r str,
+    safe_network: bool,
+    timezone: FixedOffset,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RepoConfig<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let token_salt = request.guard::<State<TokenSalt>>()?.inner();
+        let middleware_base = request.guard::<State<MiddlewareBase>>()?.inner();
+        let safe_network = request.guard::<State<SafeNetwork>>()?.inner();
+        let timezone = request.guard::<State<Timezone>>()?.inner();
+        Outcome::Success(RepoConfig {
+            token_salt: &token_salt.0,
+            middleware_base: &middleware_base.0,
+            safe_network: safe_network.0,
+            timezone: timezone.0,
+        })
     }
 }
 
-#[post("/courses/<course_uid>/assignments/<assignment_uid>/repos", data = "<message>")]
-fn create_repo(course_uid: Uuid, assignment_uid: Uuid, message: Json<CreateRepo>,
-               token_salt: State<TokenSalt>, middleware_base: State<MiddlewareBase>, safe_network: State<SafeNetwork>,
-               mut db: DBAccess, gitlab_api: State<GitLabAPI>)
-               -> GMResult<String> {
+/// Create a repo with webhook, protected branches and owners. Returns its ssh url.
+fn provision_repo(course_uid: &Uuid, assignment_uid: &Uuid, message: &CreateRepo, config: &RepoConfig,
+                  db: &mut DBAccess, git_server: &dyn GitServer) -> GMResult<String> {
     if db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &message.repo_name).is_ok() {
         return Err(Error::AlreadyExists);
-    }// expires_at add one day
-    let ddl = time::strftime("%Y-%m-%d", &(time::strptime(message.ddl, "%Y-%m-%d")? + time::Duration::days(1)))?;
+    }
+    let (deadline, ddl) = parse_ddl(message.ddl, config.timezone)?;
     let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
     let owners: Vec<u64> = {
         let mut ret: Vec<u64> = Vec::with_capacity(message.owners.len());
@@ -543,40 +709,81 @@ fn create_repo(course_uid: Uuid, assignment_uid: Uuid, message: Json<CreateRepo>
         ret
     };
 
-    // create repo
-    let response: Value = gitlab_api.call(&CreateRepoGitlab::new(message.repo_name, assignment_id))?.json()?;
-    let repo_id = response["id"].as_u64().expect("Gitlab schema changed");
-    let repo_url = response["ssh_url_to_repo"].as_str().expect("Gitlab schema changed");
-    db.remember_repo_id(&course_uid.parsed, &assignment_uid.parsed, message.repo_name, repo_id)?;
-    trace!("Repo {} created", repo_url);
-    // setup webhook
-    let mut webhook = if let Some(d) = &message.additional_data {
-        let data = ::percent_encoding::percent_encode(d.as_bytes(), percent_encoding::USERINFO_ENCODE_SET);
-        format!("/hooks/{}/{}?data={}", &course_uid.original, &assignment_uid.original, data)
-    } else {
-        format!("/hooks/{}/{}", &course_uid.original, &assignment_uid.original)
-    };
-    let token = if safe_network.0 { String::new() } else { calc_token(&webhook, &*token_salt) };
-    webhook.insert_str(0, &middleware_base.0);
-    gitlab_api.call(&CreateWebhookGitlab::new(repo_id, &webhook, &token))?;
-    trace!("Webhook for {} created as {}", repo_url, &webhook);
-    // set all branches as protected branch to prevent force push
-    gitlab_api.call_no_body(Method::POST, &format!("projects/{}/protected_branches?name=*", repo_id))?;
-    // setup student permission
-    for owner in owners {
-        gitlab_api.call(&AddUserToProjectGitlab::new(repo_id, owner, &ddl))?;
-        trace!("Limited permission for user {} on {} added", owner, repo_url);
-    }
+    let template = db.assignment_template(assignment_id)?;
+
+    let repo_url = with_rollback(db, git_server, |db, rollback| {
+        // create repo
+        let Repo { id: repo_id, ssh_url: repo_url } = match template {
+            Some(template) => git_server.create_repo_from(assignment_id, message.repo_name, template.repo_id, !template.strip_history)?,
+            None => git_server.create_repo(assignment_id, message.repo_name)?,
+        };
+        // webhook, protected branches and members are gone along with the repo
+        rollback.push(format!("repo {}", repo_url), move |_, git_server| git_server.delete_repo(repo_id));
+        db.remember_repo_id(&course_uid.parsed, &assignment_uid.parsed, message.repo_name, repo_id, deadline)?;
+        rollback.push(format!("repo id {}", repo_id), move |db, _| db.forget_repo_id(repo_id));
+        trace!("Repo {} created", repo_url);
+        // setup webhook
+        let mut webhook = if let Some(d) = &message.additional_data {
+            let data = ::percent_encoding::percent_encode(d.as_bytes(), percent_encoding::USERINFO_ENCODE_SET);
+            format!("/hooks/{}/{}?data={}", &course_uid.original, &assignment_uid.original, data)
+        } else {
+            format!("/hooks/{}/{}", &course_uid.original, &assignment_uid.original)
+        };
+        let token = if config.safe_network { String::new() } else { calc_token(&webhook, config.token_salt) };
+        webhook.insert_str(0, config.middleware_base);
+        git_server.create_webhook(repo_id, &webhook, &token)?;
+        trace!("Webhook for {} created as {}", repo_url, &webhook);
+        // set all branches as protected branch to prevent force push
+        git_server.protect_branches(repo_id)?;
+        // setup student permission
+        for &owner in &owners {
+            // maintainer access, so users can push
+            git_server.add_repo_member(repo_id, owner, AccessLevel::Maintainer, Some(&ddl))?;
+            trace!("Limited permission for user {} on {} added", owner, repo_url);
+        }
+        Ok(repo_url)
+    })?;
     info!("Created repo {}", repo_url);
-    Ok(format!(r#"{{"ssh_url_to_repo":"{}"}}"#, repo_url))
+    Ok(repo_url)
+}
+
+#[post("/courses/<course_uid>/assignments/<assignment_uid>/repos", data = "<message>")]
+fn create_repo(course_uid: Uuid, assignment_uid: Uuid, message: Json<CreateRepo>, idempotency_key: IdempotencyKey, config: RepoConfig,
+               mut db: DBAccess, git_server: State<GitServerAPI>)
+               -> GMResult<StoredResponse> {
+    idempotency_key.run(&*message, &mut db, |db| {
+        let repo_url = provision_repo(&course_uid, &assignment_uid, &message, &config, db, &**git_server)?;
+        Ok(StoredResponse::json(Status::Ok, format!(r#"{{"ssh_url_to_repo":"{}"}}"#, repo_url)))
+    })
+}
+
+#[post("/courses/<course_uid>/assignments/<assignment_uid>/repos/bulk", data = "<message>")]
+fn bulk_create_repos(course_uid: Uuid, assignment_uid: Uuid, message: Json<Vec<CreateRepo>>,
+                     mut db: DBAccess, jobs: State<JobQueue>)
+                     -> GMResult<Accepted> {
+    db.translate_uuid(&assignment_uid.parsed)?;
+    let job = Job::BulkCreateRepos {
+        course_uid: course_uid.original.clone(),
+        assignment_uid: assignment_uid.original.clone(),
+        repos: message.into_inner(),
+    };
+    let id = jobs.submit(&mut db, &job)?;
+    info!("Creating repos for assignment {} in course {} as job {}", &assignment_uid.original, &course_uid.original, id);
+    Ok(Accepted(id))
+}
+
+#[get("/jobs/<id>")]
+fn get_job(id: u64,
+           mut db: DBAccess) -> GMResult<Json<JobStatus>> {
+    Ok(Json(db.load_job(id)?))
 }
 
 #[delete("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>")]
 fn delete_repo(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri,
-               mut db: DBAccess, gitlab_api: State<GitLabAPI>) -> GMResult<()> {
+               mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
     let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
 
-    gitlab_api.call_no_body(Method::DELETE, &format!("projects/{}", repo_id))?;
+    git_server.delete_repo(repo_id)?;
 
     db.forget_repo_id(repo_id)?;
 
@@ -586,12 +793,12 @@ fn delete_repo(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri,
 
 #[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>")]
 fn get_repo(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri,
-            mut db: DBAccess, gitlab_api: State<GitLabAPI>) -> GMResult<()> {
+            mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
     let course_id = db.translate_uuid(&course_uid.parsed)?;
     let assignment_id = db.translate_uuid(&assignment_uid.parsed)?;
     let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
 
-    match gitlab_api.call_no_body(Method::GET, &format!("groups/{}", course_id)) {
+    match git_server.get_group(course_id) {
         Err(NotFound) => {
             db.forget_uuid_by_id(course_id)?;
             warn!("Course {} recognized but not found in gitlab. Forgetting...", &course_uid.original);
@@ -603,7 +810,7 @@ fn get_repo(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri,
         Ok(_) => Ok(())
     }?;
 
-    match gitlab_api.call_no_body(Method::GET, &format!("groups/{}", assignment_id)) {
+    match git_server.get_group(assignment_id) {
         Err(NotFound) => {
             db.forget_uuid_by_id(course_id)?;
             warn!("Assignment {} recognized but not found in gitlab. Forgetting...", &course_uid.original);
@@ -613,7 +820,7 @@ fn get_repo(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri,
         Ok(_) => Ok(())
     }?;
 
-    match gitlab_api.call_no_body(Method::GET, &format!("groups/{}", assignment_id)) {
+    match git_server.get_group(assignment_id) {
         Err(NotFound) => {
             db.forget_uuid_by_id(course_id)?;
             warn!("Assignment {} recognized but not found in gitlab. Forgetting...", &assignment_uid.original);
@@ -623,7 +830,7 @@ fn get_repo(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri,
         Ok(_) => Ok(())
     }?;
 
-    match gitlab_api.call_no_body(Method::GET, &format!("projects/{}", repo_id)) {
+    match git_server.get_repo(repo_id) {
         Err(NotFound) => {
             db.forget_repo_id(repo_id)?;
             warn!("Repo {} recognized but not found in gitlab. Forgetting...", repo_id);
@@ -634,34 +841,123 @@ fn get_repo(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri,
     }
 }
 
-struct DownloadFormat<'a>(Cow<'a, str>);
-
-impl<'a> Deref for DownloadFormat<'a> {
-    type Target = str;
+/// Emails of members, leaving out those unknown to middleware, e.g. admins.
+#[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/members")]
+fn list_members(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri,
+                mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<Json<Vec<String>>> {
+    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
+    let mut emails = Vec::new();
+    for member in git_server.list_repo_members(repo_id)? {
+        if let Some(email) = optional(db.user_email(member))? {
+            emails.push(email);
+        }
+    }
+    Ok(Json(emails))
+}
 
-    fn deref(&self) -> &Self::Target {
-        &*self.0
+#[post("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/members/<user_email>")]
+fn add_member(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, user_email: StrInUri, timezone: State<Timezone>,
+              mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
+    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
+    let user_id = db.translate_uid(&user_email)?;
+    if git_server.list_repo_members(repo_id)?.contains(&user_id) {
+        return Err(Error::AlreadyExists);
     }
+    let (access_level, expires_at) = owner_access(&mut db, repo_id, timezone.inner().0)?;
+    git_server.add_repo_member(repo_id, user_id, access_level, expires_at.as_deref())?;
+    info!("User {} added to repo {}", &*user_email, &*repo_name);
+    Ok(())
 }
 
-impl<'a> FromFormValue<'a> for DownloadFormat<'a> {
-    type Error = Utf8Error;
+#[delete("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/members/<user_email>")]
+fn remove_member(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, user_email: StrInUri,
+                 mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
+    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
+    let user_id = db.translate_uid(&user_email)?;
+    git_server.remove_repo_member(repo_id, user_id)?;
+    db.forget_extension(repo_id, user_id)?;
+    info!("User {} removed from repo {}", &*user_email, &*repo_name);
+    Ok(())
+}
 
-    fn from_form_value(form_value: &'a RawStr) -> Result<Self, Self::Error> {
-        form_value.percent_decode().map(DownloadFormat)
+#[derive(Deserialize)]
+struct SetDeadline<'a> {
+    ddl: &'a str,
+    /// Extend only these owners
+    #[serde(borrow)]
+    owners: Option<Vec<&'a str>>,
+}
+
+#[put("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/deadline", data = "<message>")]
+fn set_repo_deadline(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, message: Json<SetDeadline>, timezone: State<Timezone>,
+                     mut db: DBAccess, git_server: State<GitServerAPI>) -> GMResult<()> {
+    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
+    let (deadline, expires_at) = parse_ddl(message.ddl, timezone.inner().0)?;
+    let owners = match message.owners {
+        Some(ref owners) => Some(owners.iter().map(|owner| db.translate_uid(owner)).collect::<GMResult<Vec<u64>>>()?),
+        None => None,
+    };
+    set_deadline(&mut db, &**git_server, repo_id, deadline, &expires_at, owners.as_deref())?;
+    info!("Deadline of repo {} set to {}", &*repo_name, message.ddl);
+    Ok(())
+}
+
+#[put("/courses/<course_uid>/assignments/<assignment_uid>/deadline", data = "<message>")]
+fn set_assignment_deadline(course_uid: Uuid, assignment_uid: Uuid, message: Json<SetDeadline>, timezone: State<Timezone>,
+                           mut db: DBAccess, jobs: State<JobQueue>) -> GMResult<Accepted> {
+    if message.owners.is_some() {
+        return Err(Error::BadRequest("Owners can only be extended per repo"));
     }
+    db.translate_uuid(&assignment_uid.parsed)?;
+    parse_ddl(message.ddl, timezone.inner().0)?;
+    let job = Job::SetAssignmentDeadline {
+        course_uid: course_uid.original.clone(),
+        assignment_uid: assignment_uid.original.clone(),
+        ddl: Cow::Borrowed(message.ddl),
+        repos: db.repo_names(&course_uid.parsed, &assignment_uid.parsed)?.into_iter().map(Cow::Owned).collect(),
+    };
+    let id = jobs.submit(&mut db, &job)?;
+    info!("Setting deadline of assignment {} in course {} as job {}", &assignment_uid.original, &course_uid.original, id);
+    Ok(Accepted(id))
+}
 
-    fn default() -> Option<Self> {
-        Some(DownloadFormat(Cow::Borrowed("tar.gz")))
+/// `tar.gz` unless given, 400 if unknown.
+fn archive_format(format: Option<&str>) -> GMResult<ArchiveFormat> {
+    match format {
+        Some(format) => ArchiveFormat::from_extension(format).ok_or(Error::BadRequest("Unknown archive format")),
+        None => Ok(ArchiveFormat::TarGz),
     }
 }
 
-#[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/download?<format>")]
-fn download_repo<'r>(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, format: DownloadFormat,
-                     mut db: DBAccess, gitlab_api: State<'r, GitLabAPI>)
+/// What to download, the default branch unless given.
+#[derive(FromForm)]
+struct DownloadAt<'a> {
+    format: Option<StrInUri<'a>>,
+    sha: Option<StrInUri<'a>>,
+    /// Branch or tag
+    #[form(field = "ref")]
+    git_ref: Option<StrInUri<'a>>,
+    /// Last commit no later than this, as `ddl`
+    before: Option<StrInUri<'a>>,
+}
+
+#[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/download?<at..>")]
+fn download_repo<'r>(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, at: LenientForm<DownloadAt>, timezone: State<Timezone>,
+                     mut db: DBAccess, git_server: State<'r, GitServerAPI>)
                      -> GMResult<Response<'r>> {
+    let format = archive_format(at.format.as_deref())?;
     let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
-    let response = gitlab_api.call_no_body(Method::GET, &format!("projects/{}/repository/archive.{}", repo_id, &*format))?;
+    let git_ref = match (&at.sha, &at.git_ref, &at.before) {
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) => return Err(Error::BadRequest("sha can't be combined with ref or before")),
+        (Some(sha), None, None) => Some(sha.to_string()),
+        (None, git_ref, Some(before)) => {
+            let (before, _) = parse_ddl(before, timezone.inner().0)?;
+            // nothing committed by then
+            Some(git_server.last_commit(repo_id, git_ref.as_deref(), Some(before))?.ok_or(NotFound)?)
+        }
+        (None, git_ref, None) => git_ref.as_ref().map(|git_ref| git_ref.to_string()),
+    };
+    let response = git_server.archive(repo_id, format, git_ref.as_deref())?;
     let mut ret = Response::build();
     {
         if let Some(Ok(desposition)) = response.headers().get("Content-Disposition").map(HeaderValue::to_str) {
@@ -672,46 +968,225 @@ fn download_repo<'r>(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri
         }
     }
     ret.header(Header::new("Content-Transfer-Encoding", "binary"));
-    ret.header(ContentType::Binary);
+    ret.header(format.content_type());
     ret.streamed_body(response);
     Ok(ret.finalize())
 }
 
-#[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/commits?<page>")]
-fn commits<'r>(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, page: Option<StrInUri>,
-               mut db: DBAccess, gitlab_api: State<'r, GitLabAPI>)
-               -> GMResult<Response<'r>> {
-    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
-    let response = if let Some(next_page) = page {
-        gitlab_api.call_no_body(Method::GET, &next_page)
-    } else {
-        gitlab_api.call_no_body(Method::GET, &format!("projects/{}/repository/commits?per_page=100", repo_id))
-    }?;
+/// Every repo at its last commit before the deadline, one folder each, along with `manifest.json`.
+#[get("/courses/<course_uid>/assignments/<assignment_uid>/export?<format>")]
+fn export_assignment<'r>(course_uid: Uuid, assignment_uid: Uuid, format: Option<StrInUri>,
+                         mut db: DBAccess, git_server: State<'r, GitServerAPI>)
+                         -> GMResult<Response<'r>> {
+    let format = check_format(archive_format(format.as_deref())?)?;
+    db.translate_uuid(&assignment_uid.parsed)?;
+    let repos = pin_repos(&mut db, &**git_server, &course_uid.parsed, &assignment_uid.parsed)?;
+    info!("Exporting {} repos of assignment {} in course {}", repos.len(), &assignment_uid.original, &course_uid.original);
     let mut ret = Response::build();
-    {
-        if let Some(Ok(link)) = response.headers().get("Link").map(HeaderValue::to_str) {
-            if let Some(next) = link.split(",").find(|s| s.trim().ends_with(r#"rel="next"#)) {
-                let gitlab_link: &str = &next[next.find("<").expect("gitlab schema changed") + 1..next.find(">").expect("gitlab schema changed")];
-                let encoded = percent_encoding::percent_encode(gitlab_link.as_bytes(), percent_encoding::QUERY_ENCODE_SET);
-                ret.header(Header::new("Link", format!(r#"<commits?page={}>; rel="next""#, encoded)));// replace url TODO
+    ret.header(Header::new("Content-Disposition", format!(r#"attachment; filename="{}.{}""#, &assignment_uid.original, format.extension())));
+    ret.header(Header::new("Content-Transfer-Encoding", "binary"));
+    ret.header(format.content_type());
+    ret.streamed_body(export(git_server.inner().clone(), repos, format));
+    Ok(ret.finalize())
+}
+
+/// Filters of the commit listing, all optional.
+#[derive(FromForm)]
+struct CommitQuery<'a> {
+    /// Opaque cursor, as given in the `Link` header of the previous page
+    page: Option<StrInUri<'a>>,
+    /// Branch, tag or sha
+    #[form(field = "ref")]
+    git_ref: Option<StrInUri<'a>>,
+    path: Option<StrInUri<'a>>,
+    /// As `ddl`
+    since: Option<StrInUri<'a>>,
+    /// As `ddl`
+    until: Option<StrInUri<'a>>,
+    per_page: Option<StrInUri<'a>>,
+}
+
+impl<'a> CommitQuery<'a> {
+    /// The filters again, for the link to the next page.
+    fn filters(&self) -> String {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        let filters = [("ref", &self.git_ref), ("path", &self.path), ("since", &self.since), ("until", &self.until), ("per_page", &self.per_page)];
+        for (name, value) in filters.iter() {
+            if let Some(value) = value {
+                query.append_pair(name, value);
             }
         }
+        query.finish()
+    }
+}
+
+/// Commits per page unless asked otherwise, also the most allowed.
+const COMMITS_PER_PAGE: u32 = 100;
+
+#[allow(clippy::too_many_arguments)]
+#[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/commits?<query..>")]
+fn commits(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, query: LenientForm<CommitQuery>, token_salt: State<TokenSalt>,
+           timezone: State<Timezone>,
+           mut db: DBAccess, git_server: State<GitServerAPI>)
+           -> GMResult<Response<'static>> {
+    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
+    let page = match query.page {
+        Some(ref cursor) => cursor::verify(&token_salt, repo_id, cursor)?,
+        None => 1,
+    };
+    let per_page = match query.per_page {
+        Some(ref per_page) => match per_page.parse() {
+            Ok(per_page) if per_page > 0 && per_page <= COMMITS_PER_PAGE => per_page,
+            _ => return Err(Error::BadRequest("per_page must be between 1 and 100")),
+        },
+        None => COMMITS_PER_PAGE,
+    };
+    let date = |date: &Option<StrInUri>| -> GMResult<Option<u64>> {
+        match date {
+            Some(date) => Ok(Some(parse_ddl(date, timezone.inner().0)?.0)),
+            None => Ok(None),
+        }
+    };
+    let filter = CommitFilter {
+        git_ref: query.git_ref.as_deref(),
+        path: query.path.as_deref(),
+        since: date(&query.since)?,
+        until: date(&query.until)?,
+        per_page,
+    };
+    let CommitPage { mut commits, next } = git_server.commits(repo_id, &filter, page)?;
+    // an author usually has many commits
+    let mut known = HashMap::new();
+    for commit in &mut commits {
+        if !known.contains_key(&commit.author_email) {
+            let user = optional(db.translate_uid(&commit.author_email))?;
+            known.insert(commit.author_email.clone(), user.is_some());
+        }
+        if known[&commit.author_email] {
+            commit.author = Some(commit.author_email.clone());
+        }
+    }
+    let mut ret = Response::build();
+    if let Some(next) = next {
+        let mut link = query.filters();
+        if !link.is_empty() {
+            link.push('&');
+        }
+        link += &format!("page={}", cursor::sign(&token_salt, repo_id, next));
+        ret.header(Header::new("Link", format!(r#"<commits?{}>; rel="next""#, link)));
     }
     ret.header(ContentType::JSON);
-    ret.streamed_body(response);
+    ret.sized_body(Cursor::new(serde_json::to_vec(&commits)?));
+    Ok(ret.finalize())
+}
+
+/// Submission tags of a repo, newest first.
+#[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/submissions")]
+fn list_submissions(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, submission_tags: State<SubmissionTags>,
+                    mut db: DBAccess, git_server: State<GitServerAPI>)
+                    -> GMResult<Json<Vec<Tag>>> {
+    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
+    let tags = git_server.tags(repo_id)?;
+    Ok(Json(tags.into_iter().filter(|tag| submission_tags.matches(&tag.name)).collect()))
+}
+
+/// Branch, tag or sha to look at, the default branch if omitted.
+#[derive(FromForm)]
+struct AtRef<'a> {
+    #[form(field = "ref")]
+    git_ref: Option<StrInUri<'a>>,
+}
+
+/// A path within a repo mustn't climb out of it, even once decoded.
+fn check_repo_path(path: &str) -> GMResult<()> {
+    if path.split('/').any(|segment| segment == "." || segment == "..") {
+        return Err(Error::BadRequest("Path can't contain . or .."));
+    }
+    Ok(())
+}
+
+/// Entries of folder `path`, the top of the repo if omitted.
+#[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/tree?<path>&<at..>")]
+fn repo_tree(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, path: Option<StrInUri>, at: LenientForm<AtRef>,
+             mut db: DBAccess, git_server: State<GitServerAPI>)
+             -> GMResult<Json<Vec<TreeEntry>>> {
+    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
+    let path = path.as_deref().map(|path| path.trim_matches('/')).filter(|path| !path.is_empty());
+    if let Some(path) = path {
+        check_repo_path(path)?;
+    }
+    Ok(Json(git_server.tree(repo_id, at.git_ref.as_deref(), path)?))
+}
+
+/// Raw content of a file, typed after its extension.
+#[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/files/<path..>?<at..>")]
+fn repo_file<'r>(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, path: Segments, at: LenientForm<AtRef>,
+                 mut db: DBAccess, git_server: State<'r, GitServerAPI>)
+                 -> GMResult<Response<'r>> {
+    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
+    let path = path.map(|segment| RawStr::from_str(segment).percent_decode())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::BadRequest("Path is not UTF-8"))?
+        .join("/");
+    check_repo_path(&path)?;
+    let response = git_server.file(repo_id, &path, at.git_ref.as_deref())?;
+    let content_type = path.rsplit('/').next().and_then(|name| name.rfind('.').map(|dot| &name[dot + 1..]))
+        .and_then(ContentType::from_extension)
+        .unwrap_or(ContentType::Binary);
+    let mut ret = Response::build();
+    ret.header(content_type);
+    match response.content_length() {
+        Some(size) => ret.raw_body(Body::Sized(response, size)),
+        None => ret.streamed_body(response),
+    };
+    Ok(ret.finalize())
+}
+
+/// Revisions to compare, each a sha, branch or tag.
+#[derive(FromForm)]
+struct CompareQuery<'a> {
+    from: Option<StrInUri<'a>>,
+    to: Option<StrInUri<'a>>,
+    /// `json` unless given, or `patch`
+    format: Option<StrInUri<'a>>,
+}
+
+#[get("/courses/<course_uid>/assignments/<assignment_uid>/repos/<repo_name>/compare?<query..>")]
+fn compare_repo(course_uid: Uuid, assignment_uid: Uuid, repo_name: StrInUri, query: LenientForm<CompareQuery>,
+                mut db: DBAccess, git_server: State<GitServerAPI>)
+                -> GMResult<Response<'static>> {
+    let (from, to) = match (&query.from, &query.to) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Err(Error::BadRequest("from and to are required")),
+    };
+    let patch_format = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("patch") => true,
+        Some(_) => return Err(Error::BadRequest("Unknown compare format")),
+    };
+    let repo_id = db.translate_repo_id(&course_uid.parsed, &assignment_uid.parsed, &repo_name)?;
+    let diffs = git_server.compare(repo_id, from, to)?;
+    let mut ret = Response::build();
+    if patch_format {
+        ret.header(ContentType::new("text", "x-patch"));
+        ret.sized_body(Cursor::new(patch(&diffs)));
+    } else {
+        ret.header(ContentType::JSON);
+        ret.sized_body(Cursor::new(serde_json::to_vec(&diffs)?));
+    }
     Ok(ret.finalize())
 }
 
 //================================================================================
 #[get("/healthcheck")]
-fn healthcheck(mut db: DBAccess, gitlab_api: State<GitLabAPI>/*, backend: State<BackendAPI>*/) -> Response {
-//fn healthcheck<'r>(mut db: DBAccess, gitlab_api: State<'r, GitLabAPI>, backend: State<BackendAPI>) -> Response<'r> {
+fn healthcheck(mut db: DBAccess, git_server: State<GitServerAPI>/*, backend: State<BackendAPI>*/) -> Response {
+//fn healthcheck<'r>(mut db: DBAccess, git_server: State<'r, GitServerAPI>, backend: State<BackendAPI>) -> Response<'r> {
     if !db.0.ping() {
         error!("DB not responding to ping");
         Response::build().status(Status::InternalServerError).sized_body(Cursor::new("db offline")).finalize()
-    } else if gitlab_api.call_no_body(Method::GET, "../../-/health").is_err() {
-        error!("GitLab seems down");
-        Response::build().status(Status::InternalServerError).sized_body(Cursor::new("gitlab offline")).finalize()
+    } else if git_server.health().is_err() {
+        error!("Git server seems down");
+        Response::build().status(Status::InternalServerError).sized_body(Cursor::new("git server offline")).finalize()
     } else {
         Response::build().sized_body(Cursor::new("OK")).finalize()
     }
@@ -727,6 +1202,11 @@ impl DBAccess {
             ?.ok_or(Error::NotFound)
     }
 
+    fn user_email(&mut self, uid: u64) -> GMResult<String> {
+        self.0.first_exec(r"SELECT username FROM uid WHERE uid=?", (uid, ))
+            ?.ok_or(Error::NotFound)
+    }
+
     fn remember_uid(&mut self, username: &str, id: u64) -> GMResult<()> {
         self.0.prep_exec(r"INSERT INTO uid(uid, username) VALUES (?, ?)", (id, username))?;
 
@@ -755,8 +1235,22 @@ impl DBAccess {
             ?.ok_or(Error::NotFound)
     }
 
-    fn remember_repo_id(&mut self, course_uid: &UuidRaw, assignment_uid: &UuidRaw, name: &str, id: u64) -> GMResult<()> {
-        self.0.prep_exec(r"INSERT INTO repo_ids(repo_id, course_uid, assignment_uid, name) VALUES (?, ?, ?, ?)", (id, course_uid, assignment_uid, name))?;
+    fn repo_name(&mut self, id: u64) -> GMResult<String> {
+        self.0.first_exec(r"SELECT name FROM repo_ids WHERE repo_id=?", (id, ))
+            ?.ok_or(Error::NotFound)
+    }
+
+    fn repo_names(&mut self, course_uid: &UuidRaw, assignment_uid: &UuidRaw) -> GMResult<Vec<String>> {
+        let rows = self.0.prep_exec(r"SELECT name FROM repo_ids WHERE course_uid=? AND assignment_uid=? ORDER BY name", (course_uid, assignment_uid))?;
+        let mut names = Vec::new();
+        for row in rows {
+            names.push(mysql::from_row(row?));
+        }
+        Ok(names)
+    }
+
+    fn remember_repo_id(&mut self, course_uid: &UuidRaw, assignment_uid: &UuidRaw, name: &str, id: u64, ddl: u64) -> GMResult<()> {
+        self.0.prep_exec(r"INSERT INTO repo_ids(repo_id, course_uid, assignment_uid, name, ddl) VALUES (?, ?, ?, ?, ?)", (id, course_uid, assignment_uid, name, ddl))?;
 
         Ok(())
     }
@@ -772,11 +1266,20 @@ struct MiddlewareBase(String);
 
 struct SafeNetwork(bool);
 
-fn main() {
-    log4rs::init_file("log4rs.yml", Default::default()).unwrap();
+/// Of a `ddl` without one.
+struct Timezone(FixedOffset);
 
-    rocket::ignite()
+impl DBAccessPool {
+    /// A connection outside of a request guard.
+    fn connect(&self) -> GMResult<DBAccess> {
+        self.0.get().map(DBAccess).map_err(|_| Error::new("No DB connection available"))
+    }
+}
+
+fn setup(rocket: Rocket) -> Rocket {
+    rocket
         .attach(DBAccess::fairing())
+        .attach(AdHoc::on_response("RequestId", |req, res| { res.set_header(RequestId::header(req)); }))
         .attach(AdHoc::on_attach("BackendAPI", |r| {
             let c = r.config().get_string("backend_url").expect("backend_url not set");
             let url = Url::options().parse(&c).expect("backend_url invalid");
@@ -789,12 +1292,22 @@ fn main() {
             let backend = BackendAPI::new(url, &c);
             Ok(r.manage(backend))
         }))
-        .attach(AdHoc::on_attach("GitlabAPI", |r| {
-            let api = {
-                let base_url_str = r.config().get_str("gitlab_base_url").expect("gitlab_base_url not set");
-                let base_url = Url::options().parse(base_url_str).expect("Please properly set gitlab_base_url");
-                let token = r.config().get_str("gitlab_auth_token").expect("gitlab_auth_token not set");
-                GitLabAPI::new(token, base_url)
+        .attach(AdHoc::on_attach("GitServerAPI", |r| {
+            let api = match r.config().get_str("git_server").unwrap_or("gitlab") {
+                "gitlab" => {
+                    let base_url_str = r.config().get_str("gitlab_base_url").expect("gitlab_base_url not set");
+                    let base_url = Url::options().parse(base_url_str).expect("Please properly set gitlab_base_url");
+                    let token = r.config().get_str("gitlab_auth_token").expect("gitlab_auth_token not set");
+                    GitServerAPI::new(GitLabAPI::new(token, base_url))
+                }
+                "gitea" => {
+                    let base_url_str = r.config().get_str("gitea_base_url").expect("gitea_base_url not set");
+                    let base_url = Url::options().parse(base_url_str).expect("Please properly set gitea_base_url");
+                    let token = r.config().get_str("gitea_auth_token").expect("gitea_auth_token not set");
+                    let pool = DBAccessPool(r.state::<DBAccessPool>().expect("DB not set up").0.clone());
+                    GitServerAPI::new(GiteaAPI::new(token, base_url, pool))
+                }
+                other => panic!("git_server {} not supported (only gitlab/gitea)", other),
             };
             Ok(r.manage(api))
         }))
@@ -806,6 +1319,15 @@ fn main() {
             let token = r.config().get_string("gitlab_webhook_token_salt").unwrap_or("CAFEDEAD".to_string());
             Ok(r.manage(TokenSalt(token)))
         }))
+        .attach(AdHoc::on_attach("SubmissionTagsRetriever", |r| {
+            let pattern = r.config().get_string("submission_tag_pattern").unwrap_or("submit-*".to_string());
+            Ok(r.manage(SubmissionTags(pattern)))
+        }))
+        .attach(AdHoc::on_attach("TimezoneRetriever", |r| {
+            let timezone = r.config().get_str("default_timezone").unwrap_or("Z");
+            let timezone = parse_timezone(timezone).expect("default_timezone invalid, expecting e.g. +08:00");
+            Ok(r.manage(Timezone(timezone)))
+        }))
         .attach(AdHoc::on_attach("MiddlewareBaseRetriever", |r| {
             let mut token: String = r.config().get_string("middleware_base").unwrap_or(String::new());
             if let Some('/') = token.chars().last() { token.pop(); }
@@ -828,11 +1350,55 @@ fn main() {
                 r.manage(Domain::new(None))
             })
         }))
+        .attach(AdHoc::on_attach("JobQueue", |r| {
+            let config = JobQueueConfig {
+                workers: r.config().get_int("job_workers").unwrap_or(2) as usize,
+                poll_interval: Duration::from_secs(r.config().get_int("job_poll_interval").unwrap_or(5) as u64),
+                timeout: Duration::from_secs(r.config().get_int("job_timeout").unwrap_or(600) as u64),
+            };
+            let context = JobContext {
+                pool: DBAccessPool(r.state::<DBAccessPool>().expect("DB not set up").0.clone()),
+                git_server: r.state::<GitServerAPI>().expect("Git server not set up").clone(),
+                token_salt: r.state::<TokenSalt>().expect("Token salt not set up").0.clone(),
+                middleware_base: r.state::<MiddlewareBase>().expect("Middleware base not set up").0.clone(),
+                safe_network: r.state::<SafeNetwork>().expect("Safe network not set up").0,
+                timezone: r.state::<Timezone>().expect("Timezone not set up").0,
+                bulk_concurrency: r.config().get_int("bulk_concurrency").unwrap_or(4) as usize,
+            };
+            Ok(r.manage(JobQueue::start(config, context)))
+        }))
+        .attach(AdHoc::on_attach("Outbox", |r| {
+            let config = OutboxConfig {
+                poll_interval: Duration::from_secs(r.config().get_int("webhook_poll_interval").unwrap_or(5) as u64),
+                max_attempts: r.config().get_int("webhook_max_attempts").unwrap_or(10) as u32,
+                retry_base: Duration::from_secs(r.config().get_int("webhook_retry_base").unwrap_or(10) as u64),
+                retry_max: Duration::from_secs(r.config().get_int("webhook_retry_max").unwrap_or(3600) as u64),
+            };
+            let pool = DBAccessPool(r.state::<DBAccessPool>().expect("DB not set up").0.clone());
+            let backend = r.state::<BackendAPI>().expect("Backend not set up").clone();
+            Ok(r.manage(Outbox::start(config, pool, backend)))
+        }))
+        .attach(AdHoc::on_attach("DeadlineEnforcer", |r| {
+            let poll_interval = Duration::from_secs(r.config().get_int("deadline_poll_interval").unwrap_or(60) as u64);
+            let pool = DBAccessPool(r.state::<DBAccessPool>().expect("DB not set up").0.clone());
+            let git_server = r.state::<GitServerAPI>().expect("Git server not set up").clone();
+            Ok(r.manage(DeadlineEnforcer::start(poll_interval, pool, git_server)))
+        }))
         .mount("/", routes![
-            webhook,create_user, get_user, update_key,create_course,create_assignment,
-            add_instructor_to_course,create_repo,download_repo,healthcheck,commits,
+            webhook,tag_webhook,merge_request_webhook,note_webhook,pipeline_webhook,unknown_webhook,dead_webhooks,replay_webhooks,create_user, get_user, update_key,create_course,create_assignment,
+            add_instructor_to_course,create_repo,bulk_create_repos,get_job,download_repo,export_assignment,healthcheck,commits,repo_tree,repo_file,compare_repo,list_submissions,
             delete_course, delete_assignment, delete_repo,
-            get_course, get_assignment, get_repo
+            get_course, get_assignment, get_repo, set_repo_deadline, set_assignment_deadline,
+            list_members, add_member, remove_member,
+            list_course_staff, add_course_staff, change_course_staff, remove_course_staff,
+            get_template, set_template, delete_template, push_template_fix,
+            get_events, set_events
         ])
-        .launch();
+        .register(err::catchers())
+}
+
+fn main() {
+    log4rs::init_file("log4rs.yml", Default::default()).unwrap();
+
+    setup(rocket::ignite()).launch();
 }
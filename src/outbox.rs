@@ -0,0 +1,266 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Outbox of webhooks to forward to the backend.
+//!
+//! A webhook is written to DB and acknowledged right away, so a backend being down or slow never loses a push.
+//! The dispatcher delivers due events, retrying failures with exponential backoff.
+//! An event failing `webhook_max_attempts` times is dead, and stays so until replayed by an admin.
+//! So is an event whose payload can't be read back, which no retry would fix.
+//! Delivery is at least once: the backend may see an event again if an instance dies mid-delivery.
+//! Delivered events are forgotten after `KEEP`.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ::{DBAccess, DBAccessPool, Error, GMResult};
+use apis::{APIAccessor, APIFunction, BackendAPI};
+use jobs::{now, Wakeup};
+
+use reqwest::Method;
+use rocket::{Outcome, Request, State};
+use rocket::request::{self, FromRequest};
+use serde_json::Value;
+
+/// How long a claimed event is hidden from other dispatchers. Well above the backend request timeout.
+const LEASE: u64 = 120;
+/// Events delivered per poll.
+const BATCH: u64 = 16;
+const KEEP: u64 = 7 * 24 * 3600;
+
+pub struct OutboxConfig {
+    pub poll_interval: Duration,
+    pub max_attempts: u32,
+    pub retry_base: Duration,
+    pub retry_max: Duration,
+}
+
+impl OutboxConfig {
+    /// Delay before the next try, after `attempts` failed ones.
+    fn backoff(&self, attempts: u32) -> u64 {
+        let factor = 1u64.checked_shl(attempts.saturating_sub(1)).unwrap_or(u64::MAX);
+        self.retry_base.as_secs().saturating_mul(factor).min(self.retry_max.as_secs())
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeadLetter {
+    id: u64,
+    path: String,
+    payload: Value,
+    attempts: u32,
+    last_error: Option<Value>,
+    created_at: u64,
+}
+
+pub struct Outbox {
+    wakeup: Arc<Wakeup>,
+}
+
+impl Outbox {
+    pub(crate) fn start(config: OutboxConfig, pool: DBAccessPool, backend: BackendAPI) -> Outbox {
+        let wakeup = Wakeup::new();
+        let shared = wakeup.clone();
+        thread::Builder::new()
+            .name("webhook-dispatcher".to_string())
+            .spawn(move || dispatch(&shared, &config, &pool, &backend))
+            .expect("Failed to start webhook dispatcher");
+        Outbox { wakeup }
+    }
+
+    /// Keep `request` for delivery. Returns once it is safely in DB.
+    pub(crate) fn enqueue<T: APIFunction>(&self, db: &mut DBAccess, request: &T) -> GMResult<u64> {
+        let id = db.insert_outbox(&request.path(), &serde_json::to_string(request)?)?;
+        self.wakeup.notify();
+        Ok(id)
+    }
+
+    /// Give dead events, or only event `id`, a fresh set of attempts. Number of events replayed.
+    pub(crate) fn replay(&self, db: &mut DBAccess, id: Option<u64>) -> GMResult<u64> {
+        let replayed = db.replay_outbox(id)?;
+        if id.is_some() && replayed == 0 {
+            return Err(Error::NotFound);
+        }
+        self.wakeup.notify();
+        Ok(replayed)
+    }
+}
+
+/// Guard for webhook routes, queueing what they forward.
+pub struct Forwarder<'r> {
+    pub(crate) db: DBAccess,
+    outbox: State<'r, Outbox>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Forwarder<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let db = match request.guard::<DBAccess>() {
+            Outcome::Success(db) => db,
+            Outcome::Failure(f) => return Outcome::Failure(f),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+        request.guard::<State<Outbox>>().map(|outbox| Forwarder { db, outbox })
+    }
+}
+
+impl<'r> Forwarder<'r> {
+    pub(crate) fn forward<T: APIFunction>(&mut self, request: &T) -> GMResult<u64> {
+        self.outbox.enqueue(&mut self.db, request)
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.wakeup.stop();
+    }
+}
+
+fn dispatch(wakeup: &Wakeup, config: &OutboxConfig, pool: &DBAccessPool, backend: &BackendAPI) {
+    while let Some(mark) = wakeup.mark() {
+        let delivered = pool.connect().and_then(|mut db| {
+            db.purge_outbox(now().saturating_sub(KEEP))?;
+            let due = db.due_outbox(now())?;
+            let count = due.len();
+            for (id, attempts, path, payload) in due {
+                if db.claim_outbox(id, now())? {
+                    deliver(&mut db, config, backend, id, attempts, &path, &payload)?;
+                }
+            }
+            Ok(count)
+        });
+        match delivered {
+            Ok(0) => {}
+            Ok(_) => continue,
+            Err(e) => warn!("Failed to dispatch webhooks: {:?}", e),
+        }
+        wakeup.wait(mark, config.poll_interval);
+    }
+}
+
+fn deliver(db: &mut DBAccess, config: &OutboxConfig, backend: &BackendAPI,
+           id: u64, attempts: u32, path: &str, payload: &str) -> GMResult<()> {
+    let body: Value = match serde_json::from_str(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            let e = Error::from(e);
+            error!("Webhook {} dead, its payload is broken: {:?}", id, e);
+            return db.outbox_failed(id, attempts, "dead", now(), &serde_json::to_string(&e.summary()).expect("What?"));
+        }
+    };
+    match backend.execute(Method::POST, path, &body, None) {
+        Ok(_) => {
+            info!("Forwarded webhook {} to {}", id, path);
+            db.outbox_delivered(id, now())
+        }
+        Err(e) => {
+            let attempts = attempts + 1;
+            let error = serde_json::to_string(&e.summary()).expect("What?");
+            if attempts >= config.max_attempts {
+                error!("Webhook {} dead after {} attempts: {:?}", id, attempts, e);
+                db.outbox_failed(id, attempts, "dead", now(), &error)
+            } else {
+                let delay = config.backoff(attempts);
+                warn!("Webhook {} failed {} times, retrying in {}s: {:?}", id, attempts, delay, e);
+                db.outbox_failed(id, attempts, "pending", now().saturating_add(delay), &error)
+            }
+        }
+    }
+}
+
+/// id, attempts, path, payload
+type OutboxRow = (u64, u32, String, String);
+/// id, path, payload, attempts, last_error, created_at
+type DeadLetterRow = (u64, String, String, u32, Option<String>, u64);
+
+impl DBAccess {
+    fn insert_outbox(&mut self, path: &str, payload: &str) -> GMResult<u64> {
+        let now = now();
+        let result = self.0.prep_exec(r"INSERT INTO webhook_outbox(path, payload, status, attempts, next_attempt_at, created_at) VALUES (?, ?, 'pending', 0, ?, ?)",
+                                      (path, payload, now, now))?;
+        Ok(result.last_insert_id())
+    }
+
+    fn due_outbox(&mut self, now: u64) -> GMResult<Vec<OutboxRow>> {
+        let rows = self.0.prep_exec(
+            r"SELECT id, attempts, path, payload FROM webhook_outbox WHERE status='pending' AND next_attempt_at<=? ORDER BY id LIMIT ?",
+            (now, BATCH))?;
+        let mut due = Vec::new();
+        for row in rows {
+            due.push(::mysql::from_row(row?));
+        }
+        Ok(due)
+    }
+
+    /// Hide the event from other dispatchers while delivering it. `false` if another one was faster.
+    fn claim_outbox(&mut self, id: u64, now: u64) -> GMResult<bool> {
+        let claimed = self.0.prep_exec(
+            r"UPDATE webhook_outbox SET next_attempt_at=? WHERE id=? AND status='pending' AND next_attempt_at<=?",
+            (now + LEASE, id, now))?.affected_rows();
+        Ok(claimed == 1)
+    }
+
+    fn outbox_delivered(&mut self, id: u64, now: u64) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE webhook_outbox SET status='delivered', delivered_at=? WHERE id=?", (now, id))?;
+
+        Ok(())
+    }
+
+    fn purge_outbox(&mut self, delivered_before: u64) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM webhook_outbox WHERE status='delivered' AND delivered_at<?", (delivered_before, ))?;
+
+        Ok(())
+    }
+
+    fn outbox_failed(&mut self, id: u64, attempts: u32, status: &str, next_attempt_at: u64, error: &str) -> GMResult<()> {
+        self.0.prep_exec(r"UPDATE webhook_outbox SET status=?, attempts=?, next_attempt_at=?, last_error=? WHERE id=?",
+                         (status, attempts, next_attempt_at, error, id))?;
+
+        Ok(())
+    }
+
+    fn replay_outbox(&mut self, id: Option<u64>) -> GMResult<u64> {
+        let result = match id {
+            Some(id) => self.0.prep_exec(
+                r"UPDATE webhook_outbox SET status='pending', attempts=0, next_attempt_at=? WHERE status='dead' AND id=?", (now(), id))?,
+            None => self.0.prep_exec(
+                r"UPDATE webhook_outbox SET status='pending', attempts=0, next_attempt_at=? WHERE status='dead'", (now(), ))?,
+        };
+        Ok(result.affected_rows())
+    }
+
+    pub(crate) fn dead_letters(&mut self) -> GMResult<Vec<DeadLetter>> {
+        let rows = self.0.prep_exec(
+            r"SELECT id, path, payload, attempts, last_error, created_at FROM webhook_outbox WHERE status='dead' ORDER BY id", ())?;
+        let mut dead = Vec::new();
+        for row in rows {
+            let (id, path, payload, attempts, last_error, created_at): DeadLetterRow = ::mysql::from_row(row?);
+            dead.push(DeadLetter {
+                id,
+                path,
+                // as stored, if it is what killed the event
+                payload: serde_json::from_str(&payload).unwrap_or_else(|_| Value::String(payload.clone())),
+                attempts,
+                last_error: last_error.map(|e| serde_json::from_str(&e)).transpose()?,
+                created_at,
+            });
+        }
+        Ok(dead)
+    }
+}
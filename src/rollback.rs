@@ -0,0 +1,64 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Compensating steps for operations spanning several git server calls and DB writes.
+//!
+//! Neither side is transactional, so every completed step registers how to undo itself.
+//! If a later step fails, the registered steps are undone in reverse.
+
+use ::{DBAccess, GMResult};
+use gitserver::GitServer;
+
+type Undo<'a> = Box<dyn FnOnce(&mut DBAccess, &dyn GitServer) -> GMResult<()> + 'a>;
+
+pub(crate) struct Rollback<'a> {
+    steps: Vec<(String, Undo<'a>)>,
+}
+
+impl<'a> Rollback<'a> {
+    fn new() -> Self {
+        Rollback { steps: Vec::new() }
+    }
+
+    /// Register how to undo a step that just completed.
+    pub(crate) fn push<F>(&mut self, what: String, undo: F)
+        where F: FnOnce(&mut DBAccess, &dyn GitServer) -> GMResult<()> + 'a {
+        self.steps.push((what, Box::new(undo)));
+    }
+
+    /// Undo in reverse. A failing step is logged and does not stop the rest.
+    fn run(self, db: &mut DBAccess, git_server: &dyn GitServer) {
+        for (what, undo) in self.steps.into_iter().rev() {
+            match undo(db, git_server) {
+                Ok(()) => trace!("Rolled back: {}", what),
+                Err(e) => error!("Failed to roll back {}: {:?}", what, e),
+            }
+        }
+    }
+}
+
+/// Run `f`, undoing whatever it registered if it fails. The original error is returned.
+pub(crate) fn with_rollback<'a, T, F>(db: &mut DBAccess, git_server: &dyn GitServer, f: F) -> GMResult<T>
+    where F: FnOnce(&mut DBAccess, &mut Rollback<'a>) -> GMResult<T> {
+    let mut rollback = Rollback::new();
+    let result = f(db, &mut rollback);
+    if let Err(ref e) = result {
+        warn!("Rolling back after {:?}", e);
+        rollback.run(db, git_server);
+    }
+    result
+}
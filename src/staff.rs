@@ -0,0 +1,166 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Course staff, i.e. instructors, TAs and observers.
+//!
+//! Staff are members of the course group, so they see every assignment and repo in it.
+//! None of them is an owner of the group, deleting the course is left to middleware.
+//! Roles are kept in `course_staff`, since a read only TA and an observer look the same to git server.
+//! Instructors added before roles were kept are only owners of the course group, so they are taken in as
+//! instructors with owner access the first time staff of their course is looked at.
+
+use ::{DBAccess, Error, GMResult};
+use err::optional;
+use gitserver::{AccessLevel, GitServer};
+use rollback::with_rollback;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StaffRole {
+    Instructor,
+    Ta,
+    Observer,
+}
+
+impl StaffRole {
+    /// Access on the course group. Only TAs have a choice, between developer (the default) and reporter.
+    pub fn access_level(self, access_level: Option<AccessLevel>) -> GMResult<AccessLevel> {
+        match (self, access_level) {
+            (StaffRole::Instructor, None) | (StaffRole::Instructor, Some(AccessLevel::Maintainer)) => Ok(AccessLevel::Maintainer),
+            (StaffRole::Ta, None) => Ok(AccessLevel::Developer),
+            (StaffRole::Ta, Some(level @ AccessLevel::Developer)) | (StaffRole::Ta, Some(level @ AccessLevel::Reporter)) => Ok(level),
+            (StaffRole::Observer, None) | (StaffRole::Observer, Some(AccessLevel::Reporter)) => Ok(AccessLevel::Reporter),
+            _ => Err(Error::BadRequest("access_level not allowed for this role")),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            StaffRole::Instructor => "instructor",
+            StaffRole::Ta => "ta",
+            StaffRole::Observer => "observer",
+        }
+    }
+
+    fn from_name(name: &str) -> GMResult<StaffRole> {
+        match name {
+            "instructor" => Ok(StaffRole::Instructor),
+            "ta" => Ok(StaffRole::Ta),
+            "observer" => Ok(StaffRole::Observer),
+            _ => {
+                error!("Unknown staff role {} in DB", name);
+                Err(Error::new("Unknown staff role in DB"))
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StaffMember {
+    email: String,
+    role: StaffRole,
+    access_level: AccessLevel,
+}
+
+/// Remember owners of the course group as instructors, unless staff already.
+/// Users the middleware doesn't know, e.g. whoever created the group, are left out.
+fn adopt_instructors(db: &mut DBAccess, git_server: &dyn GitServer, course: u64) -> GMResult<()> {
+    for user in git_server.list_group_members(course, AccessLevel::Owner)? {
+        if optional(db.user_email(user))?.is_some() && db.staff_role(course, user)?.is_none() {
+            info!("Owner {} of course {} taken in as instructor", user, course);
+            db.remember_staff(course, user, StaffRole::Instructor, AccessLevel::Owner)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn list_staff(db: &mut DBAccess, git_server: &dyn GitServer, course: u64) -> GMResult<Vec<StaffMember>> {
+    adopt_instructors(db, git_server, course)?;
+    db.staff_members(course)
+}
+
+/// `Err(Error::AlreadyExists)` if the user is staff already, whatever the role.
+pub(crate) fn add_staff(db: &mut DBAccess, git_server: &dyn GitServer, course: u64, user: u64,
+                        role: StaffRole, access_level: AccessLevel) -> GMResult<()> {
+    adopt_instructors(db, git_server, course)?;
+    if db.staff_role(course, user)?.is_some() {
+        return Err(Error::AlreadyExists);
+    }
+    with_rollback(db, git_server, |db, rollback| {
+        git_server.add_group_member(course, user, access_level)?;
+        rollback.push(format!("staff {} of course {}", user, course), move |_, git_server| git_server.remove_group_member(course, user));
+        db.remember_staff(course, user, role, access_level)
+    })
+}
+
+pub(crate) fn change_staff(db: &mut DBAccess, git_server: &dyn GitServer, course: u64, user: u64,
+                           role: StaffRole, access_level: AccessLevel) -> GMResult<()> {
+    adopt_instructors(db, git_server, course)?;
+    db.staff_role(course, user)?.ok_or(Error::NotFound)?;
+    git_server.update_group_member(course, user, access_level)?;
+    db.forget_staff(course, user)?;
+    db.remember_staff(course, user, role, access_level)
+}
+
+pub(crate) fn remove_staff(db: &mut DBAccess, git_server: &dyn GitServer, course: u64, user: u64) -> GMResult<()> {
+    adopt_instructors(db, git_server, course)?;
+    db.staff_role(course, user)?.ok_or(Error::NotFound)?;
+    match git_server.remove_group_member(course, user) {
+        // removed on git server already
+        Ok(()) | Err(Error::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    db.forget_staff(course, user)
+}
+
+impl DBAccess {
+    fn staff_role(&mut self, course: u64, user: u64) -> GMResult<Option<StaffRole>> {
+        let role: Option<String> = self.0.first_exec(r"SELECT role FROM course_staff WHERE course_id=? AND uid=?", (course, user))?;
+        role.map(|role| StaffRole::from_name(&role)).transpose()
+    }
+
+    fn remember_staff(&mut self, course: u64, user: u64, role: StaffRole, access_level: AccessLevel) -> GMResult<()> {
+        self.0.prep_exec(r"INSERT INTO course_staff(course_id, uid, role, access_level) VALUES (?, ?, ?, ?)",
+                         (course, user, role.name(), access_level.name()))?;
+
+        Ok(())
+    }
+
+    fn forget_staff(&mut self, course: u64, user: u64) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM course_staff WHERE course_id=? AND uid=?", (course, user))?;
+
+        Ok(())
+    }
+
+    pub(crate) fn forget_course_staff(&mut self, course: u64) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM course_staff WHERE course_id=?", (course, ))?;
+
+        Ok(())
+    }
+
+    fn staff_members(&mut self, course: u64) -> GMResult<Vec<StaffMember>> {
+        let rows = self.0.prep_exec(
+            r"SELECT uid.username, course_staff.role, course_staff.access_level FROM course_staff JOIN uid ON uid.uid=course_staff.uid WHERE course_id=? ORDER BY uid.username",
+            (course, ))?;
+        let mut staff = Vec::new();
+        for row in rows {
+            let (email, role, access_level): (String, String, String) = ::mysql::from_row(row?);
+            staff.push(StaffMember { email, role: StaffRole::from_name(&role)?, access_level: AccessLevel::from_name(&access_level)? });
+        }
+        Ok(staff)
+    }
+}
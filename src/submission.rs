@@ -0,0 +1,42 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Submission tags, a student's way to say which commit is final.
+//!
+//! A tag whose name matches `submission_tag_pattern`, e.g. `submit-1`, is forwarded to the backend as an explicit
+//! submission. Other tags are left alone.
+
+/// Pattern of submission tag names. `*` matches any run of characters, `?` a single one.
+pub struct SubmissionTags(pub String);
+
+impl SubmissionTags {
+    pub fn matches(&self, tag: &str) -> bool {
+        let pattern: Vec<char> = self.0.chars().collect();
+        let tag: Vec<char> = tag.chars().collect();
+        glob(&pattern, &tag)
+    }
+}
+
+fn glob(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => glob(&pattern[1..], name) || (!name.is_empty() && glob(pattern, &name[1..])),
+        (Some('?'), Some(_)) => glob(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
@@ -0,0 +1,64 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Starter code of an assignment.
+//!
+//! The template is any repo on git server, kept in `assignment_templates` by id so it survives renames.
+//! Repos created afterwards are seeded from it, see `GitServer::create_repo_from`.
+//! Fixes to it reach existing repos on `FIX_BRANCH`, see `Job::PushTemplateFix`.
+
+use ::{DBAccess, Error, GMResult};
+
+/// Branch fixes are pushed to. It is only ever created, never pushed over.
+pub const FIX_BRANCH: &str = "upstream-fix";
+
+#[derive(Serialize, Deserialize)]
+pub struct Template {
+    /// Path on git server, as registered
+    pub path: String,
+    #[serde(skip)]
+    pub repo_id: u64,
+    /// Seed repos with the files only, in a single commit
+    #[serde(default)]
+    pub strip_history: bool,
+}
+
+impl DBAccess {
+    pub(crate) fn assignment_template(&mut self, assignment: u64) -> GMResult<Option<Template>> {
+        let template: Option<(String, u64, bool)> = self.0.first_exec(
+            r"SELECT path, template_id, strip_history FROM assignment_templates WHERE assignment_id=?", (assignment, ))?;
+        Ok(template.map(|(path, repo_id, strip_history)| Template { path, repo_id, strip_history }))
+    }
+
+    pub(crate) fn set_assignment_template(&mut self, assignment: u64, template: &Template) -> GMResult<()> {
+        self.0.prep_exec(r"DELETE FROM assignment_templates WHERE assignment_id=?", (assignment, ))?;
+        self.0.prep_exec(r"INSERT INTO assignment_templates(assignment_id, template_id, path, strip_history) VALUES (?, ?, ?, ?)",
+                         (assignment, template.repo_id, &template.path, template.strip_history))?;
+
+        Ok(())
+    }
+
+    /// `Err(Error::NotFound)` if there was none.
+    pub(crate) fn forget_assignment_template(&mut self, assignment: u64) -> GMResult<()> {
+        let deleted = self.0.prep_exec(r"DELETE FROM assignment_templates WHERE assignment_id=?", (assignment, ))?.affected_rows();
+        if deleted == 0 {
+            return Err(Error::NotFound);
+        }
+
+        Ok(())
+    }
+}
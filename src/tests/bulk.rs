@@ -0,0 +1,80 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use rocket::http::Status;
+use serde_json::{json, Value};
+
+use super::*;
+use super::routes::REPO_PATH;
+
+fn entry(repo_name: &str, owner: &str) -> Value {
+    json!({"owners": [owner], "repo_name": repo_name, "ddl": "2099-10-22"})
+}
+
+#[test]
+fn bulk_create_repos() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let response = ctx.post_json("/courses/00000000-0000-0000-0000-000000000001/assignments/00000000-0000-0000-0000-000000000009/repos/bulk", json!([]));
+    assert_eq!(response.status(), Status::NotFound);
+    let students: Vec<String> = (0..6).map(|i| format!("student{}@shanghaitech.edu.cn", i)).collect();
+    for student in &students {
+        ctx.create_user(student);
+    }
+    let mut entries: Vec<Value> = students.iter().enumerate().map(|(i, s)| entry(&format!("repo{}", i), s)).collect();
+    entries.push(entry("wangdch", "wangdch@shanghaitech.edu.cn"));
+    entries.push(entry("repo0", "student0@shanghaitech.edu.cn"));
+    entries.push(entry("ghost", "nobody@shanghaitech.edu.cn"));
+
+    let job = ctx.wait_job(ctx.post_json(&format!("{}/bulk", REPO_PATH), Value::Array(entries)));
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(job["progress"], 9);
+    let report = job["result"].as_array().unwrap();
+    assert_eq!(report.len(), 9);
+    for (i, result) in report[..6].iter().enumerate() {
+        assert_eq!(result["repo_name"], format!("repo{}", i));
+        assert_eq!(result["status"], "created");
+        assert_eq!(result["ssh_url_to_repo"], format!("git@gitlab.test:2/repo{}.git", i));
+    }
+    assert_eq!(report[6], json!({"repo_name": "wangdch", "status": "skipped"}));
+    assert_eq!(report[7], json!({"repo_name": "repo0", "status": "skipped"}));
+    assert_eq!(report[8]["status"], "failed");
+    assert_eq!(report[8]["error"]["code"], "not_found");
+
+    // wangdch plus six new ones, each with its member and webhook
+    let state = ctx.gitlab.state();
+    assert_eq!(state.projects.len(), 7);
+    assert!(state.projects.keys().all(|p| state.project_members[p].len() == 1 && state.hooks[p].len() == 1));
+    drop(state);
+    assert_eq!(ctx.db.query("SELECT count(*) FROM repo_ids"), vec![vec!["7"]]);
+}
+
+#[test]
+fn bulk_failure_leaves_no_trace() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    ctx.gitlab.fail("POST", "projects/", 500);
+    let entries = json!([entry("wangdch", "wangdch@shanghaitech.edu.cn")]);
+    let report = ctx.wait_job(ctx.post_json(&format!("{}/bulk", REPO_PATH), entries.clone()))["result"].clone();
+    assert_eq!(report[0]["status"], "failed");
+    assert_eq!(report[0]["error"]["upstream_status"], 500);
+    assert!(ctx.db.query("SELECT * FROM repo_ids").is_empty());
+
+    let report = ctx.wait_job(ctx.post_json(&format!("{}/bulk", REPO_PATH), entries))["result"].clone();
+    assert_eq!(report[0]["status"], "created");
+}
@@ -0,0 +1,246 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rocket::http::{ContentType, Header, Status};
+use serde_json::{json, Value};
+
+use super::*;
+use super::routes::{hook, push_payload, ASSIGNMENT, COURSE, REPO_PATH};
+
+impl TestContext {
+    fn wait_closed(&self, project: u64) {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let query = format!("SELECT ddl_enforced FROM repo_ids WHERE repo_id={}", project);
+        while self.db.query(&query) != vec![vec!["1".to_string()]] {
+            assert!(Instant::now() < deadline, "repo {} never closed", project);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+#[test]
+fn closed_after_deadline() {
+    let ctx = TestContext::new();
+    let project = ctx.create_repo_with_ddl("2012-10-22");
+    ctx.wait_closed(project);
+    let state = ctx.gitlab.state();
+    let members = &state.project_members[&project];
+    assert_eq!(members.len(), 1);
+    assert_eq!(members[0]["access_level"], 20);
+    assert_eq!(members[0]["expires_at"], "2012-10-23");
+    assert_eq!(state.protected_branches[&project], vec![json!({"name": "*", "push_access_level": 0})]);
+}
+
+#[test]
+fn open_before_deadline() {
+    let ctx = TestContext::new();
+    let project = ctx.create_repo_with_ddl("2099-10-22T10:00:00Z");
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(ctx.db.query(&format!("SELECT ddl, ddl_enforced FROM repo_ids WHERE repo_id={}", project)),
+               vec![vec!["4096346400".to_string(), "0".to_string()]]);
+    let state = ctx.gitlab.state();
+    let members = &state.project_members[&project];
+    assert_eq!(members[0]["access_level"], 40);
+    assert_eq!(members[0]["expires_at"], "2099-10-23");
+    assert_eq!(state.protected_branches[&project][0]["push_access_level"], 40);
+}
+
+#[test]
+fn deadline_with_offset() {
+    let ctx = TestContext::new();
+    let project = ctx.create_repo_with_ddl("2099-10-22T18:00:00+08:00");
+    assert_eq!(ctx.db.query(&format!("SELECT ddl FROM repo_ids WHERE repo_id={}", project)),
+               vec![vec!["4096346400".to_string()]]);
+    assert_eq!(ctx.gitlab.state().project_members[&project][0]["expires_at"], "2099-10-23");
+}
+
+#[test]
+fn deadline_in_default_timezone() {
+    let ctx = TestContext::with_config(|c| c.extra("default_timezone", "+08:00"));
+    // end of the day in UTC+8
+    let project = ctx.create_repo_with_ddl("2099-10-22");
+    assert_eq!(ctx.db.query(&format!("SELECT ddl FROM repo_ids WHERE repo_id={}", project)),
+               vec![vec!["4096368000".to_string()]]);
+    assert_eq!(ctx.gitlab.state().project_members[&project][0]["expires_at"], "2099-10-23");
+}
+
+#[test]
+fn malformed_deadline() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    for ddl in &["2099-10-32", "22/10/2099", "2099-10-22T10:00:00+8"] {
+        let response = ctx.post_json(REPO_PATH, json!({
+            "owners": ["wangdch@shanghaitech.edu.cn"],
+            "repo_name": "wangdch",
+            "ddl": ddl,
+        }));
+        assert_eq!(response.status(), Status::BadRequest, "{}", ddl);
+    }
+    assert!(ctx.gitlab.state().projects.is_empty());
+}
+
+#[test]
+fn closing_retried() {
+    let ctx = TestContext::new();
+    ctx.gitlab.fail("PUT", "projects/4/members/3", 500);
+    let project = ctx.create_repo_with_ddl("2012-10-22");
+    assert_eq!(project, 4);
+    ctx.wait_closed(project);
+    let puts = ctx.gitlab.calls().iter().filter(|c| c.starts_with("PUT projects/4/members/3")).count();
+    assert_eq!(puts, 2);
+    assert_eq!(ctx.gitlab.state().project_members[&project][0]["access_level"], 20);
+}
+
+#[test]
+fn late_push_flagged() {
+    let ctx = TestContext::new();
+    ctx.create_repo_with_ddl("2012-10-22");
+    let (uri, token) = hook(&ctx);
+    let response = ctx.client.post(uri).header(ContentType::JSON)
+        .header(Header::new("X-Gitlab-Event", "Push Hook"))
+        .header(Header::new("X-Gitlab-Token", token))
+        .body(push_payload().to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(ctx.backend.wait_calls(1)[0].body["late"], true);
+}
+
+const A: &str = "a@shanghaitech.edu.cn";
+const B: &str = "b@shanghaitech.edu.cn";
+
+impl TestContext {
+    fn put_deadline(&self, uri: &str, body: Value) -> Status {
+        self.client.put(uri.to_string()).header(ContentType::JSON).body(body.to_string()).dispatch().status()
+    }
+
+    /// Repo `ab` owned by `A` and `B`, in an existing assignment. Returns gitlab project id.
+    fn create_group_repo(&self, ddl: &str) -> u64 {
+        self.create_user(A);
+        self.create_user(B);
+        let response = self.post_json(REPO_PATH, json!({"owners": [A, B], "repo_name": "ab", "ddl": ddl}));
+        assert_eq!(response.status(), Status::Ok);
+        *self.gitlab.state().projects.keys().last().unwrap()
+    }
+
+    fn access_levels(&self, project: u64) -> Vec<Value> {
+        self.gitlab.state().project_members[&project].iter().map(|m| m["access_level"].clone()).collect()
+    }
+}
+
+#[test]
+fn extension_reopens_repo() {
+    let ctx = TestContext::new();
+    let project = ctx.create_repo_with_ddl("2012-10-22");
+    ctx.wait_closed(project);
+    assert_eq!(ctx.put_deadline(&format!("{}/wangdch/deadline", REPO_PATH), json!({"ddl": "2099-12-31"})), Status::Ok);
+    let state = ctx.gitlab.state();
+    let members = &state.project_members[&project];
+    assert_eq!(members[0]["access_level"], 40);
+    assert_eq!(members[0]["expires_at"], "2100-01-01");
+    assert_eq!(state.protected_branches[&project], vec![json!({"name": "*", "push_access_level": 40})]);
+    drop(state);
+    assert_eq!(ctx.db.query(&format!("SELECT ddl_enforced FROM repo_ids WHERE repo_id={}", project)), vec![vec!["0".to_string()]]);
+}
+
+#[test]
+fn extension_for_one_owner() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    let project = ctx.create_group_repo("2099-10-22");
+    let uri = format!("{}/ab/deadline", REPO_PATH);
+    assert_eq!(ctx.put_deadline(&uri, json!({"ddl": "2012-10-22"})), Status::Ok);
+    assert_eq!(ctx.put_deadline(&uri, json!({"ddl": "2099-12-31", "owners": [A]})), Status::Ok);
+    ctx.wait_closed(project);
+    // closing runs once more if it raced with the extension
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(ctx.access_levels(project), vec![json!(40), json!(20)]);
+    assert_eq!(ctx.gitlab.state().project_members[&project][0]["expires_at"], "2100-01-01");
+    assert_eq!(ctx.gitlab.state().protected_branches[&project][0]["push_access_level"], 40);
+
+    // only the extended owner is on time
+    let (uri, token) = hook(&ctx);
+    for user_id in &[3, 4] {
+        let mut payload = push_payload();
+        payload["user_id"] = json!(user_id);
+        payload["project"]["id"] = json!(project);
+        let response = ctx.client.post(uri.clone()).header(ContentType::JSON)
+            .header(Header::new("X-Gitlab-Event", "Push Hook"))
+            .header(Header::new("X-Gitlab-Token", token.clone()))
+            .body(payload.to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+    let calls = ctx.backend.wait_calls(2);
+    let late = |email: &str| calls.iter().find(|c| c.body["pusher_email"] == email).unwrap().body["late"].clone();
+    assert_eq!(late(A), false);
+    assert_eq!(late(B), true);
+}
+
+#[test]
+fn extension_for_stranger() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    ctx.create_user(A);
+    let uri = format!("{}/wangdch/deadline", REPO_PATH);
+    assert_eq!(ctx.put_deadline(&uri, json!({"ddl": "2099-12-31", "owners": [A]})), Status::NotFound);
+    assert_eq!(ctx.put_deadline(&uri, json!({"ddl": "2099-12-31", "owners": ["nobody@shanghaitech.edu.cn"]})), Status::NotFound);
+    assert!(ctx.db.query("SELECT * FROM deadline_extensions").is_empty());
+}
+
+#[test]
+fn extension_for_assignment() {
+    let ctx = TestContext::new();
+    let first = ctx.create_repo();
+    let second = ctx.create_group_repo("2099-10-22");
+    let uri = format!("/courses/{}/assignments/{}/deadline", COURSE, ASSIGNMENT);
+    assert_eq!(ctx.put_deadline(&uri, json!({"ddl": "2099-12-31", "owners": [A]})), Status::BadRequest);
+    let response = ctx.client.put(uri).header(ContentType::JSON).body(json!({"ddl": "2099-12-31"}).to_string()).dispatch();
+    let job = ctx.wait_job(response);
+    assert_eq!(job["kind"], "set_assignment_deadline");
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(job["result"], json!([
+        {"repo_name": "ab", "status": "updated"},
+        {"repo_name": "wangdch", "status": "updated"},
+    ]));
+    let state = ctx.gitlab.state();
+    for project in &[first, second] {
+        assert!(state.project_members[project].iter().all(|m| m["expires_at"] == "2100-01-01"));
+    }
+}
+
+#[test]
+fn deadline_waits_for_closing() {
+    let ctx = TestContext::new();
+    let project = ctx.create_repo_with_ddl("2099-10-22");
+    // another instance is closing the repo
+    ctx.db.query(&format!("UPDATE repo_ids SET ddl_claimed_at=strftime('%s','now') WHERE repo_id={}", project));
+    let started = Instant::now();
+    let status = thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_secs(1));
+            ctx.db.query(&format!("UPDATE repo_ids SET ddl_claimed_at=NULL WHERE repo_id={}", project));
+        });
+        ctx.put_deadline(&format!("{}/wangdch/deadline", REPO_PATH), json!({"ddl": "2099-12-31"}))
+    });
+    assert_eq!(status, Status::Ok);
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert_eq!(ctx.db.query(&format!("SELECT ddl_claimed_at FROM repo_ids WHERE repo_id={}", project)), vec![vec!["NULL".to_string()]]);
+    assert_eq!(ctx.gitlab.state().project_members[&project][0]["expires_at"], "2100-01-01");
+}
@@ -0,0 +1,113 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::LocalResponse;
+use serde_json::{json, Value};
+
+use super::*;
+use super::routes::{COURSE, REPO_PATH};
+
+fn envelope(response: &mut LocalResponse) -> Value {
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let request_id = response.headers().get_one("X-Request-Id").unwrap();
+    assert_eq!(body["request_id"], request_id);
+    body
+}
+
+#[test]
+fn bad_request() {
+    let ctx = TestContext::new();
+    let mut response = ctx.post_json("/users", json!({"email": "wangdch", "password": "password"}));
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = envelope(&mut response);
+    assert_eq!(body["code"], "bad_request");
+    assert_eq!(body["message"], "Invalid email");
+    assert!(body.get("upstream_status").is_none());
+}
+
+#[test]
+fn not_found_from_route_and_router() {
+    let ctx = TestContext::new();
+    let mut response = ctx.client.get(format!("/courses/{}", COURSE)).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(envelope(&mut response)["code"], "not_found");
+    let mut response = ctx.client.get("/nowhere").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(envelope(&mut response)["code"], "not_found");
+}
+
+#[test]
+fn conflict() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let mut response = ctx.post_json(REPO_PATH, json!({
+        "owners": ["wangdch@shanghaitech.edu.cn"],
+        "repo_name": "wangdch",
+        "ddl": "2099-10-22",
+    }));
+    assert_eq!(response.status(), Status::Conflict);
+    assert_eq!(envelope(&mut response)["code"], "already_exists");
+}
+
+#[test]
+fn upstream_error() {
+    let ctx = TestContext::new();
+    ctx.gitlab.fail("POST", "groups", 400);
+    let mut response = ctx.post_json("/courses", json!({"name": "SI100c", "uuid": COURSE}));
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = envelope(&mut response);
+    assert_eq!(body["code"], "upstream_error");
+    assert_eq!(body["message"], "Injected failure");
+    assert_eq!(body["upstream_status"], 400);
+}
+
+#[test]
+fn malformed_body() {
+    let ctx = TestContext::new();
+    let mut response = ctx.client.post("/courses").header(ContentType::JSON).body("{").dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(envelope(&mut response)["code"], "bad_request");
+}
+
+#[test]
+fn request_id_from_backend() {
+    let ctx = TestContext::new();
+    let response = ctx.client.get("/users/nobody@shanghaitech.edu.cn")
+        .header(Header::new("X-Request-Id", "backend-42"))
+        .dispatch();
+    assert_eq!(response.headers().get_one("X-Request-Id"), Some("backend-42"));
+    let mut response = ctx.client.get(format!("/courses/{}", COURSE))
+        .header(Header::new("X-Request-Id", "backend-43"))
+        .dispatch();
+    assert_eq!(envelope(&mut response)["request_id"], "backend-43");
+}
+
+#[test]
+fn guard_failure_explained() {
+    let ctx = TestContext::new();
+    let mut response = ctx.client.post("/courses")
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", ""))
+        .body(json!({"name": "SI100c", "uuid": COURSE}).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = envelope(&mut response);
+    assert_eq!(body["code"], "bad_request");
+    assert_eq!(body["message"], "Idempotency-Key must be 1 to 255 characters");
+}
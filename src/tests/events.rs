@@ -0,0 +1,117 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use rocket::http::{ContentType, Header, Status};
+use serde_json::{json, Value};
+
+use super::*;
+use super::routes::{hook, ASSIGNMENT, COURSE};
+
+fn merge_request_payload() -> Value {
+    json!({
+        "object_kind": "merge_request",
+        "user": {"id": 3, "username": "wangdch"},
+        "project": {"id": 4, "git_ssh_url": "git@gitlab.test:3/wangdch.git"},
+        "object_attributes": {"iid": 1, "action": "open"},
+    })
+}
+
+impl TestContext {
+    fn events_uri(&self) -> String {
+        format!("/courses/{}/assignments/{}/events", COURSE, ASSIGNMENT)
+    }
+
+    fn set_events(&self, events: Value) {
+        let response = self.client.put(self.events_uri()).header(ContentType::JSON).body(events.to_string()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    fn send_event(&self, event: &str, payload: &Value) -> Status {
+        let (uri, token) = hook(self);
+        self.client.post(uri).header(ContentType::JSON)
+            .header(Header::new("X-Gitlab-Event", event.to_string()))
+            .header(Header::new("X-Gitlab-Token", token))
+            .body(payload.to_string())
+            .dispatch()
+            .status()
+    }
+}
+
+#[test]
+fn events_ignored_by_default() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let mut response = ctx.client.get(ctx.events_uri()).dispatch();
+    let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body, json!({"tag_push": true, "merge_request": false, "note": false, "pipeline": false}));
+    for event in &["Merge Request Hook", "Note Hook", "Pipeline Hook"] {
+        assert_eq!(ctx.send_event(event, &merge_request_payload()), Status::Ok);
+    }
+    assert!(ctx.db.query("SELECT * FROM webhook_outbox").is_empty());
+    assert_eq!(ctx.send_event("Issue Hook", &merge_request_payload()), Status::BadRequest);
+}
+
+#[test]
+fn event_forwarded_once_enabled() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    ctx.set_events(json!({"merge_request": true, "pipeline": true}));
+    let mut response = ctx.client.get(ctx.events_uri()).dispatch();
+    let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body, json!({"tag_push": true, "merge_request": true, "note": false, "pipeline": true}));
+
+    assert_eq!(ctx.send_event("Note Hook", &merge_request_payload()), Status::Ok);
+    assert_eq!(ctx.send_event("Merge Request Hook", &merge_request_payload()), Status::Ok);
+    let calls = ctx.backend.wait_calls(1);
+    assert_eq!(calls[0].url, "/internal/merge_request");
+    let body = &calls[0].body;
+    assert_eq!(body["assignment_uid"], ASSIGNMENT);
+    assert_eq!(body["repo_name"], "wangdch");
+    assert_eq!(body["sender_email"], "wangdch@shanghaitech.edu.cn");
+    assert_eq!(body["payload"], merge_request_payload());
+
+    let mut pipeline = merge_request_payload();
+    pipeline["object_kind"] = json!("pipeline");
+    assert_eq!(ctx.send_event("Pipeline Hook", &pipeline), Status::Ok);
+    let calls = ctx.backend.wait_calls(2);
+    assert_eq!(calls[1].url, "/internal/pipeline");
+    assert_eq!(calls[1].body["payload"]["object_kind"], "pipeline");
+}
+
+#[test]
+fn event_without_repo_rejected() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    ctx.set_events(json!({"merge_request": true}));
+    let mut payload = merge_request_payload();
+    payload.as_object_mut().unwrap().remove("project");
+    assert_eq!(ctx.send_event("Merge Request Hook", &payload), Status::BadRequest);
+    assert!(ctx.db.query("SELECT * FROM webhook_outbox").is_empty());
+}
+
+#[test]
+fn submission_tags_turned_off() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    ctx.set_events(json!({"tag_push": false}));
+    let mut payload = super::routes::push_payload();
+    payload["ref"] = json!("refs/tags/submit-1");
+    assert_eq!(ctx.send_event("Tag Push Hook", &payload), Status::Ok);
+    assert!(ctx.db.query("SELECT * FROM webhook_outbox").is_empty());
+    // pushes still are
+    assert_eq!(ctx.send_event("Push Hook", &super::routes::push_payload()), Status::Ok);
+    assert_eq!(ctx.backend.wait_calls(1)[0].url, "/internal/submission");
+}
@@ -0,0 +1,108 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read};
+
+use flate2::read::GzDecoder;
+use rocket::http::Status;
+use serde_json::{json, Value};
+use tar::Archive;
+use zip::ZipArchive;
+
+use super::*;
+use super::routes::{ASSIGNMENT, COURSE, REPO_PATH};
+
+const A: &str = "a@shanghaitech.edu.cn";
+const B: &str = "b@shanghaitech.edu.cn";
+const ON_TIME: &str = "1111111111111111111111111111111111111111";
+const LATE: &str = "2222222222222222222222222222222222222222";
+
+impl TestContext {
+    /// Repos `ab`, submitted on time and again late, `solo` still open, and `empty`.
+    fn create_submissions(&self) {
+        self.create_assignment();
+        self.create_user(A);
+        self.create_user(B);
+        for (name, owners, ddl) in &[("ab", vec![A, B], "2012-10-22"), ("solo", vec![A], "2099-10-22"), ("empty", vec![B], "2012-10-22")] {
+            let response = self.post_json(REPO_PATH, json!({"owners": owners, "repo_name": name, "ddl": ddl}));
+            assert_eq!(response.status(), Status::Ok);
+        }
+        let projects: Vec<u64> = self.gitlab.state().projects.keys().cloned().collect();
+        let (ab, solo) = (projects[0], projects[1]);
+        self.gitlab.push_commit_at(ab, ON_TIME, "Done", "2012-10-22T12:00:00Z");
+        self.gitlab.push_commit_at(ab, LATE, "Oops", "2012-10-23T12:00:00Z");
+        self.gitlab.push_commit_at(solo, "3333333333333333333333333333333333333333", "WIP", "2020-01-01T00:00:00Z");
+        let mut state = self.gitlab.state();
+        state.files.insert(ab, vec![("src/main.c".to_string(), b"int main() {}\n".to_vec())].into_iter().collect());
+        state.files.insert(solo, vec![("README.md".to_string(), b"# solo\n".to_vec())].into_iter().collect());
+    }
+
+    fn export(&self, query: &str) -> Vec<u8> {
+        let uri = format!("/courses/{}/assignments/{}/export{}", COURSE, ASSIGNMENT, query);
+        let mut response = self.client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.body_bytes().unwrap()
+    }
+}
+
+fn expected_manifest() -> Value {
+    json!([
+        {"name": "ab", "sha": ON_TIME, "owners": [A, B], "deadline": "2012-10-23T00:00:00Z"},
+        {"name": "empty", "sha": null, "owners": [B], "deadline": "2012-10-23T00:00:00Z"},
+        {"name": "solo", "sha": "3333333333333333333333333333333333333333", "owners": [A], "deadline": "2099-10-23T00:00:00Z"},
+    ])
+}
+
+fn expected_files() -> Vec<String> {
+    vec!["ab/src/main.c".to_string(), "manifest.json".to_string(), "solo/README.md".to_string()]
+}
+
+#[test]
+fn export_tar_gz() {
+    let ctx = TestContext::new();
+    ctx.create_submissions();
+    let body = ctx.export("");
+    let mut files = BTreeMap::new();
+    for entry in Archive::new(GzDecoder::new(&body[..])).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        files.insert(entry.path().unwrap().to_string_lossy().into_owned(), content);
+    }
+    assert_eq!(files.keys().cloned().collect::<Vec<_>>(), expected_files());
+    assert_eq!(files["ab/src/main.c"], "int main() {}\n");
+    assert_eq!(serde_json::from_str::<Value>(&files["manifest.json"]).unwrap(), expected_manifest());
+    // pinned before the deadline
+    assert!(ctx.gitlab.calls().iter().any(|c| c.ends_with(&format!("archive.tar.gz?sha={}", ON_TIME))));
+}
+
+#[test]
+fn export_zip() {
+    let ctx = TestContext::new();
+    ctx.create_submissions();
+    let body = ctx.export("?format=zip");
+    let mut zip = ZipArchive::new(Cursor::new(body)).unwrap();
+    let mut names: Vec<String> = zip.file_names().map(str::to_string).collect();
+    names.sort();
+    assert_eq!(names, expected_files());
+    let mut manifest = String::new();
+    zip.by_name("manifest.json").unwrap().read_to_string(&mut manifest).unwrap();
+    assert_eq!(serde_json::from_str::<Value>(&manifest).unwrap(), expected_manifest());
+
+    let uri = format!("/courses/{}/assignments/{}/export?format=tar.bz2", COURSE, ASSIGNMENT);
+    assert_eq!(ctx.client.get(uri).dispatch().status(), Status::BadRequest);
+}
@@ -0,0 +1,234 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `GiteaAPI` on its own, against a fake gitea, and how it reads webhook payloads.
+
+use serde_json::{json, Value};
+use url::Url;
+
+use ::Error;
+use gitea::GiteaAPI;
+use gitserver::{ArchiveFormat, CommitFilter, GitServer};
+use super::mock_gitea::MockGitea;
+use super::mock_mysql::MockMySQL;
+
+const REPO: u64 = 7;
+const FULL_NAME: &str = "SI100c-hw0/wangdch";
+
+fn gitea() -> (MockGitea, MockMySQL, GiteaAPI) {
+    let mock = MockGitea::start();
+    mock.reply("GET", &format!("repositories/{}", REPO),
+               json!({"id": REPO, "full_name": FULL_NAME, "default_branch": "master", "empty": false}));
+    let db = MockMySQL::start();
+    let api = GiteaAPI::new("token", Url::parse(&mock.url()).unwrap(), db.pool());
+    (mock, db, api)
+}
+
+/// For parsing webhook payloads only.
+fn offline() -> (MockMySQL, GiteaAPI) {
+    let db = MockMySQL::start();
+    let api = GiteaAPI::new("token", Url::parse("http://gitea.test/api/v1/").unwrap(), db.pool());
+    (db, api)
+}
+
+fn commit(sha: &str) -> Value {
+    json!({
+        "sha": sha,
+        "commit": {
+            "message": "Initial commit",
+            "author": {"name": "wangdch", "email": "wangdch@shanghaitech.edu.cn", "date": "2012-10-22T14:13:35+08:00"},
+            "committer": {"date": "2012-10-22T14:13:35+08:00"},
+        },
+        "parents": [{"sha": "1111111111111111111111111111111111111111"}],
+    })
+}
+
+#[test]
+fn archive_ref_kept_in_its_segment() {
+    let (mock, _db, api) = gitea();
+    let archive = format!("repos/{}/archive/fix%2F1.zip", FULL_NAME);
+    mock.reply("GET", &archive, json!("archive"));
+    assert!(api.archive(REPO, ArchiveFormat::Zip, Some("fix/1")).is_ok());
+    assert!(api.archive(REPO, ArchiveFormat::TarGz, None).is_err());
+    for git_ref in &["../../../admin/users", "master?token=x", "master#x", "/master"] {
+        assert!(matches!(api.archive(REPO, ArchiveFormat::Zip, Some(git_ref)), Err(Error::BadRequest(_))), "{}", git_ref);
+    }
+    assert!(matches!(api.archive(REPO, ArchiveFormat::TarBz2, None), Err(Error::BadRequest(_))));
+    let archives: Vec<String> = mock.calls().into_iter().filter(|c| c.contains("/archive/")).collect();
+    assert_eq!(archives, vec![format!("GET {}", archive), format!("GET repos/{}/archive/master.tar.gz", FULL_NAME)]);
+}
+
+#[test]
+fn commits_filtered() {
+    let (mock, _db, api) = gitea();
+    let sha = "0123456789abcdef0123456789abcdef01234567";
+    mock.reply("GET", &format!("repos/{}/commits", FULL_NAME), json!([commit(sha)]));
+    let filter = CommitFilter {
+        git_ref: Some("fix&sha=master"),
+        path: Some("src/main.c"),
+        since: Some(1_350_864_000),
+        until: None,
+        per_page: 2,
+    };
+    let page = api.commits(REPO, &filter, 1).unwrap();
+    assert_eq!(mock.calls().last().unwrap(), &format!("GET repos/{}/commits?limit=2&page=1&stat=false&sha=fix%26sha%3Dmaster\
+                                                       &path=src%2Fmain.c&since=2012-10-22T00%3A00%3A00Z", FULL_NAME));
+    assert!(page.next.is_none());
+    let commit = &page.commits[0];
+    assert_eq!(commit.sha, sha);
+    assert_eq!(commit.author_email, "wangdch@shanghaitech.edu.cn");
+    assert_eq!(commit.authored_at, "2012-10-22T06:13:35Z");
+    assert_eq!(commit.parents, vec!["1111111111111111111111111111111111111111"]);
+
+    mock.reply("GET", &format!("repos/{}/commits", FULL_NAME), json!([{"sha": sha}]));
+    assert!(matches!(api.commits(REPO, &filter, 1), Err(Error::UpstreamError(502, _))));
+}
+
+#[test]
+fn paths_encoded_per_folder() {
+    let (mock, _db, api) = gitea();
+    assert!(api.file(REPO, "src/a b#?.c", Some("x&ref=y")).is_err());
+    assert!(api.tree(REPO, None, Some("src/lib")).is_err());
+    let calls = mock.calls();
+    assert!(calls.contains(&format!("GET repos/{}/raw/src/a%20b%23%3F.c?ref=x%26ref%3Dy", FULL_NAME)), "{:?}", calls);
+    assert!(calls.contains(&format!("GET repos/{}/contents/src/lib", FULL_NAME)), "{:?}", calls);
+}
+
+#[test]
+fn compared_refs_kept_in_their_segments() {
+    let (mock, _db, api) = gitea();
+    let compare = format!("repos/{}/compare/master...fix%2F1", FULL_NAME);
+    mock.reply("GET", &compare, json!({"commits": [{"files": [{"filename": "README.md"}]}]}));
+    mock.reply("GET", &format!("repos/{}/raw/README.md", FULL_NAME), json!("# hw0"));
+    let changes = api.changed_files(REPO, None, "fix/1").unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].path, "README.md");
+    assert!(mock.calls().contains(&format!("GET {}", compare)));
+    for git_ref in &["../../../admin/users", "master?token=x", "master#x", "/master", "a...b"] {
+        assert!(matches!(api.changed_files(REPO, Some(git_ref), "fix/1"), Err(Error::BadRequest(_))), "{}", git_ref);
+        assert!(matches!(api.changed_files(REPO, None, git_ref), Err(Error::BadRequest(_))), "{}", git_ref);
+    }
+}
+
+#[test]
+fn template_flag_left_as_found() {
+    let (mock, _db, api) = gitea();
+    mock.reply("GET", "admin/orgs", json!([{"id": 3, "username": "SI100c-hw0"}]));
+    mock.reply("GET", "repositories/9", json!({"id": 9, "full_name": "SI100c/hw0-template"}));
+    mock.reply("GET", "repos/SI100c/hw0-template", json!({"id": 9, "template": false}));
+    mock.reply("PATCH", "repos/SI100c/hw0-template", json!({}));
+    mock.reply("POST", "repos/SI100c/hw0-template/generate", json!({"id": 11, "ssh_url": "git@gitea.test:SI100c-hw0/wangdch.git"}));
+    let repo = api.create_repo_from(3, "wangdch", 9, false).unwrap();
+    assert_eq!(repo.id, 11);
+    let calls: Vec<String> = mock.calls().into_iter().filter(|c| c.contains("hw0-template") && !c.starts_with("GET")).collect();
+    assert_eq!(calls, vec!["PATCH repos/SI100c/hw0-template", "POST repos/SI100c/hw0-template/generate", "PATCH repos/SI100c/hw0-template"]);
+
+    // a template already
+    mock.reply("GET", "repos/SI100c/hw0-template", json!({"id": 9, "template": true}));
+    let before = mock.calls().len();
+    api.create_repo_from(3, "wangdch", 9, false).unwrap();
+    assert!(!mock.calls()[before..].iter().any(|c| c.starts_with("PATCH")));
+}
+
+#[test]
+fn org_names_kept() {
+    let (mock, db, api) = gitea();
+    mock.reply("POST", "orgs", json!({"id": 3, "username": "SI100c"}));
+    mock.reply("GET", "admin/orgs", json!([{"id": 3, "username": "SI100c"}, {"id": 5, "username": "SI100c-hw0"}]));
+    mock.reply("POST", "orgs/SI100c-hw0/repos", json!({"id": 11, "ssh_url": "git@gitea.test:SI100c-hw0/wangdch.git"}));
+    assert_eq!(api.create_course("SI100c").unwrap(), 3);
+    assert_eq!(db.query("SELECT org_id, name FROM gitea_orgs"), vec![vec!["3", "SI100c"]]);
+    // made before names were kept, looked up once
+    api.create_repo(5, "wangdch").unwrap();
+    api.create_repo(5, "wangdch").unwrap();
+    let listed = mock.calls().iter().filter(|c| c.starts_with("GET admin/orgs")).count();
+    assert_eq!(listed, 1);
+
+    mock.reply("GET", "orgs/SI100c-hw0", json!({"id": 5, "username": "SI100c-hw0"}));
+    assert!(api.get_group(5).is_ok());
+    // deleted behind our back
+    mock.reply("GET", "admin/orgs", json!([]));
+    assert!(matches!(api.get_group(3), Err(Error::NotFound)));
+    assert!(matches!(api.get_group(3), Err(Error::NotFound)));
+    assert_eq!(db.query("SELECT org_id FROM gitea_orgs"), vec![vec!["5"]]);
+}
+
+#[test]
+fn webhook_subscribes_to_workflow_runs() {
+    let (mock, _db, api) = gitea();
+    let hooks = format!("repos/{}/hooks", FULL_NAME);
+    mock.reply("POST", &hooks, json!({"id": 1}));
+    api.create_webhook(REPO, "http://middleware.test/hooks/x/y", "secret").unwrap();
+    let body = mock.body("POST", &hooks).unwrap();
+    assert_eq!(body["events"], json!(["push", "create", "pull_request", "issue_comment", "workflow_run"]));
+    assert_eq!(body["authorization_header"], "Bearer secret");
+}
+
+fn repository() -> Value {
+    json!({"id": REPO, "full_name": FULL_NAME, "ssh_url": "git@gitea.test:SI100c-hw0/wangdch.git"})
+}
+
+#[test]
+fn push_parsed() {
+    let (_db, api) = offline();
+    let payload = json!({
+        "ref": "refs/heads/master",
+        "after": "0123456789abcdef0123456789abcdef01234567",
+        "repository": repository(),
+        "pusher": {"id": 4},
+        "sender": {"id": 4},
+    });
+    let push = api.parse_push(&payload).unwrap();
+    assert_eq!(push.upstream, "git@gitea.test:SI100c-hw0/wangdch.git");
+    assert_eq!(push.repo_id, REPO);
+    assert_eq!(push.pusher_id, 4);
+    assert_eq!(push.git_ref, "refs/heads/master");
+    assert_eq!(push.after, "0123456789abcdef0123456789abcdef01234567");
+    assert!(api.parse_push(&json!({"ref": "refs/heads/master", "repository": repository()})).is_none());
+}
+
+#[test]
+fn tag_push_parsed() {
+    let (_db, api) = offline();
+    let mut payload = json!({
+        "ref": "submit-1",
+        "ref_type": "tag",
+        "sha": "0123456789abcdef0123456789abcdef01234567",
+        "repository": repository(),
+        "sender": {"id": 4},
+    });
+    let tag = api.parse_tag_push(&payload).unwrap();
+    assert_eq!(tag.upstream, "git@gitea.test:SI100c-hw0/wangdch.git");
+    assert_eq!(tag.repo_id, REPO);
+    assert_eq!(tag.pusher_id, 4);
+    assert_eq!(tag.tag, "submit-1");
+    assert_eq!(tag.sha, Some("0123456789abcdef0123456789abcdef01234567"));
+    // a new branch is a create event too
+    payload["ref_type"] = json!("branch");
+    assert!(api.parse_tag_push(&payload).is_none());
+}
+
+#[test]
+fn event_parsed() {
+    let (_db, api) = offline();
+    let source = api.parse_event(&json!({"action": "opened", "repository": repository(), "sender": {"id": 4}})).unwrap();
+    assert_eq!(source.repo_id, REPO);
+    assert_eq!(source.sender_id, Some(4));
+    let source = api.parse_event(&json!({"action": "opened", "repository": repository()})).unwrap();
+    assert_eq!(source.sender_id, None);
+    assert!(api.parse_event(&json!({"action": "opened"})).is_none());
+}
@@ -0,0 +1,130 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::LocalResponse;
+use serde_json::{json, Value};
+
+use super::*;
+use super::routes::{ASSIGNMENT, COURSE, REPO_PATH};
+
+impl TestContext {
+    fn post_with_key(&self, uri: &str, key: &str, body: Value) -> LocalResponse {
+        self.client.post(uri.to_string())
+            .header(ContentType::JSON)
+            .header(Header::new("Idempotency-Key", key.to_string()))
+            .body(body.to_string())
+            .dispatch()
+    }
+}
+
+#[test]
+fn replays_create_user() {
+    let ctx = TestContext::new();
+    let body = json!({"email": "wangdch@shanghaitech.edu.cn", "password": "password"});
+    assert_eq!(ctx.post_with_key("/users", "k1", body.clone()).status(), Status::Created);
+    assert_eq!(ctx.post_with_key("/users", "k1", body.clone()).status(), Status::Created);
+    assert_eq!(ctx.gitlab.calls(), vec!["POST users"]);
+    // without the key it is a plain duplicate
+    assert_eq!(ctx.post_json("/users", body).status(), Status::Conflict);
+}
+
+#[test]
+fn replays_create_course_and_assignment() {
+    let ctx = TestContext::new();
+    let course = json!({"name": "SI100c", "uuid": COURSE});
+    assert_eq!(ctx.post_with_key("/courses", "k1", course.clone()).status(), Status::Created);
+    assert_eq!(ctx.post_with_key("/courses", "k1", course).status(), Status::Created);
+    let uri = format!("/courses/{}/assignments", COURSE);
+    let assignment = json!({"name": "hw0", "uuid": ASSIGNMENT});
+    assert_eq!(ctx.post_with_key(&uri, "k2", assignment.clone()).status(), Status::Created);
+    assert_eq!(ctx.post_with_key(&uri, "k2", assignment).status(), Status::Created);
+    assert_eq!(ctx.gitlab.state().groups.len(), 2);
+    assert_eq!(ctx.db.query("SELECT count(*) FROM uuids"), vec![vec!["2"]]);
+}
+
+#[test]
+fn replays_create_repo() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    let body = json!({"owners": ["wangdch@shanghaitech.edu.cn"], "repo_name": "wangdch", "ddl": "2099-10-22"});
+    let mut first = ctx.post_with_key(REPO_PATH, "k1", body.clone());
+    assert_eq!(first.status(), Status::Ok);
+    let first = first.body_string().unwrap();
+    let mut second = ctx.post_with_key(REPO_PATH, "k1", body);
+    assert_eq!(second.status(), Status::Ok);
+    assert_eq!(second.content_type(), Some(ContentType::JSON));
+    assert_eq!(second.body_string().unwrap(), first);
+    assert_eq!(ctx.gitlab.state().projects.len(), 1);
+}
+
+#[test]
+fn rejects_key_reused_for_other_request() {
+    let ctx = TestContext::new();
+    assert_eq!(ctx.post_with_key("/users", "k1", json!({"email": "wangdch@shanghaitech.edu.cn", "password": "password"})).status(), Status::Created);
+    let response = ctx.post_with_key("/users", "k1", json!({"email": "zhangsan@shanghaitech.edu.cn", "password": "password"}));
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let response = ctx.post_with_key("/courses", "k1", json!({"name": "SI100c", "uuid": COURSE}));
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert!(ctx.gitlab.state().groups.is_empty());
+}
+
+#[test]
+fn failed_request_releases_key() {
+    let ctx = TestContext::new();
+    let body = json!({"name": "SI100c", "uuid": COURSE});
+    ctx.gitlab.fail("POST", "groups", 500);
+    assert_eq!(ctx.post_with_key("/courses", "k1", body.clone()).status(), Status::InternalServerError);
+    assert!(ctx.db.query("SELECT * FROM idempotency_keys").is_empty());
+    assert_eq!(ctx.post_with_key("/courses", "k1", body).status(), Status::Created);
+    assert_eq!(ctx.db.query("SELECT status FROM idempotency_keys WHERE idem_key='k1'"), vec![vec!["201"]]);
+}
+
+#[test]
+fn key_in_use_is_conflict() {
+    let ctx = TestContext::new();
+    // as left by a request still running
+    let body = json!({"name": "SI100c", "uuid": COURSE});
+    assert_eq!(ctx.post_with_key("/courses", "k1", body.clone()).status(), Status::Created);
+    ctx.db.query("UPDATE idempotency_keys SET status=NULL, body=NULL");
+    assert_eq!(ctx.post_with_key("/courses", "k1", body).status(), Status::Conflict);
+    assert_eq!(ctx.gitlab.calls(), vec!["POST groups"]);
+}
+
+#[test]
+fn abandoned_key_runs_again() {
+    let body = json!({"name": "SI100c", "uuid": COURSE});
+    let ctx = TestContext::new();
+    assert_eq!(ctx.post_with_key("/courses", "k1", body.clone()).status(), Status::Created);
+    let hash = ctx.db.query("SELECT request_hash FROM idempotency_keys")[0][0].clone();
+
+    // as left by an instance which died before reaching gitlab
+    let ctx = TestContext::new();
+    ctx.db.query(&format!("INSERT INTO idempotency_keys(idem_key, request_hash, reserved_at) VALUES ('k1', '{}', strftime('%s', 'now') - 700)", hash));
+    assert_eq!(ctx.post_with_key("/courses", "k1", body).status(), Status::Created);
+    assert_eq!(ctx.db.query("SELECT status FROM idempotency_keys WHERE idem_key='k1'"), vec![vec!["201"]]);
+}
+
+#[test]
+fn old_keys_forgotten() {
+    let ctx = TestContext::new();
+    assert_eq!(ctx.post_with_key("/users", "k1", json!({"email": "wangdch@shanghaitech.edu.cn", "password": "password"})).status(), Status::Created);
+    ctx.db.query("UPDATE idempotency_keys SET reserved_at=reserved_at-86401");
+    let response = ctx.post_with_key("/courses", "k1", json!({"name": "SI100c", "uuid": COURSE}));
+    assert_eq!(response.status(), Status::Created);
+}
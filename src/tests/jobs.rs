@@ -0,0 +1,115 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+use rocket::local::LocalResponse;
+use serde_json::{json, Value};
+
+use super::*;
+use super::routes::COURSE;
+
+impl TestContext {
+    pub fn job(&self, id: u64) -> Value {
+        let mut response = self.client.get(format!("/jobs/{}", id)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    }
+
+    /// Take the `202 Accepted` of a job, and wait for the job to finish.
+    pub fn wait_job(&self, mut response: LocalResponse) -> Value {
+        assert_eq!(response.status(), Status::Accepted);
+        let accepted: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let id = accepted["job_id"].as_u64().unwrap();
+        assert_eq!(response.headers().get_one("Location"), Some(&*format!("/jobs/{}", id)));
+        self.wait_job_id(id)
+    }
+
+    pub fn wait_job_id(&self, id: u64) -> Value {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            let job = self.job(id);
+            if job["status"] == "succeeded" || job["status"] == "failed" {
+                return job;
+            }
+            assert!(Instant::now() < deadline, "job {} never finished: {}", id, job);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+#[test]
+fn unknown_job() {
+    let ctx = TestContext::new();
+    assert_eq!(ctx.client.get("/jobs/42").dispatch().status(), Status::NotFound);
+}
+
+#[test]
+fn delete_unknown_course_is_not_a_job() {
+    let ctx = TestContext::new();
+    assert_eq!(ctx.client.delete(format!("/courses/{}", COURSE)).dispatch().status(), Status::NotFound);
+    assert!(ctx.db.query("SELECT * FROM jobs").is_empty());
+}
+
+#[test]
+fn failed_job() {
+    let ctx = TestContext::new();
+    ctx.create_course();
+    let course = *ctx.gitlab.state().groups.keys().next().unwrap();
+    ctx.gitlab.fail("DELETE", &format!("groups/{}", course), 500);
+    let job = ctx.wait_job(ctx.client.delete(format!("/courses/{}", COURSE)).dispatch());
+    assert_eq!(job["kind"], "delete_course");
+    assert_eq!(job["status"], "failed");
+    assert_eq!(job["progress"], 0);
+    assert_eq!(job["total"], 1);
+    assert_eq!(job["error"], json!({"code": "upstream_error", "message": "Injected failure", "upstream_status": 500}));
+    // still there, so it can be deleted again
+    assert_eq!(ctx.wait_job(ctx.client.delete(format!("/courses/{}", COURSE)).dispatch())["status"], "succeeded");
+}
+
+#[test]
+fn resumes_jobs_left_in_db() {
+    let ctx = TestContext::new();
+    ctx.create_course();
+    // as left by an instance which died before or while running them
+    let payload = json!({"kind": "delete_course", "course_uid": COURSE}).to_string();
+    ctx.db.query(&format!("INSERT INTO jobs(id, kind, payload, status, total, created_at, heartbeat) \
+                           VALUES (1, 'delete_course', '{0}', 'pending', 1, 0, 0), \
+                                  (2, 'delete_course', '{0}', 'running', 1, 0, 0)", payload));
+    let first = ctx.wait_job_id(1);
+    assert_eq!(first["status"], "succeeded");
+    assert_eq!(first["progress"], 1);
+    assert!(ctx.gitlab.state().groups.is_empty());
+    // already done by the first one
+    assert_eq!(ctx.wait_job_id(2)["status"], "succeeded");
+}
+
+#[test]
+fn abandoned_job_starts_over() {
+    let ctx = TestContext::new();
+    ctx.create_course();
+    let course = *ctx.gitlab.state().groups.keys().next().unwrap();
+    ctx.gitlab.fail("DELETE", &format!("groups/{}", course), 500);
+    let payload = json!({"kind": "delete_course", "course_uid": COURSE}).to_string();
+    ctx.db.query(&format!("INSERT INTO jobs(id, kind, payload, status, progress, total, created_at, heartbeat) \
+                           VALUES (1, 'delete_course', '{}', 'running', 1, 1, 0, 0)", payload));
+    let job = ctx.wait_job_id(1);
+    assert_eq!(job["status"], "failed");
+    assert_eq!(job["progress"], 0);
+}
@@ -0,0 +1,80 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A fake gitea REST API answering with canned replies, to check which calls `GiteaAPI` makes.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+
+use super::{serve, Call, Reply};
+
+#[derive(Default)]
+struct GiteaState {
+    /// By method and path relative to api base, without query
+    replies: BTreeMap<(String, String), Value>,
+    calls: Vec<Call>,
+}
+
+pub struct MockGitea {
+    base: String,
+    state: Arc<Mutex<GiteaState>>,
+}
+
+impl MockGitea {
+    pub fn start() -> MockGitea {
+        let state = Arc::new(Mutex::new(GiteaState::default()));
+        let shared = state.clone();
+        let base = serve(move |_, call| {
+            let mut state = shared.lock().unwrap();
+            state.calls.push(call.clone());
+            let path = call.url.trim_start_matches("/api/v1/");
+            let path = path.split('?').next().unwrap();
+            match state.replies.get(&(call.method.clone(), path.to_string())) {
+                Some(body) => Reply::json(200, body.clone()),
+                None => Reply::not_found(path),
+            }
+        });
+        MockGitea { base, state }
+    }
+
+    /// What goes into `gitea_base_url`.
+    pub fn url(&self) -> String {
+        format!("{}/api/v1/", self.base)
+    }
+
+    /// Answer `method` calls to `path` (relative to api base, without query) with `body`. Others are 404.
+    pub fn reply(&self, method: &str, path: &str, body: Value) {
+        self.state.lock().unwrap().replies.insert((method.to_string(), path.to_string()), body);
+    }
+
+    /// Body of the last `method` call to `path` (relative to api base, without query).
+    pub fn body(&self, method: &str, path: &str) -> Option<Value> {
+        let url = format!("/api/v1/{}", path);
+        self.state.lock().unwrap().calls.iter().rev()
+            .find(|c| c.method == method && c.url.split('?').next() == Some(url.as_str()))
+            .map(|c| c.body.clone())
+    }
+
+    /// Outbound calls made so far, as `"<METHOD> <path relative to api base>"`.
+    pub fn calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().calls.iter()
+            .map(|c| format!("{} {}", c.method, c.url.trim_start_matches("/api/v1/")))
+            .collect()
+    }
+}
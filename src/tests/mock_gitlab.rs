@@ -0,0 +1,704 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A fake gitlab REST API covering what `GitLabAPI` uses. Objects are kept as json, much like gitlab returns them.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde_json::{json, Value};
+
+use super::{serve, Call, Reply};
+
+#[derive(Default)]
+pub struct GitLabState {
+    next_id: u64,
+    pub users: BTreeMap<u64, Value>,
+    pub keys: BTreeMap<u64, Vec<Value>>,
+    pub groups: BTreeMap<u64, Value>,
+    pub group_members: BTreeMap<u64, Vec<Value>>,
+    pub projects: BTreeMap<u64, Value>,
+    pub project_members: BTreeMap<u64, Vec<Value>>,
+    pub hooks: BTreeMap<u64, Vec<Value>>,
+    pub protected_branches: BTreeMap<u64, Vec<Value>>,
+    pub commits: BTreeMap<u64, Vec<Value>>,
+    /// Files on the default branch by path
+    pub files: BTreeMap<u64, BTreeMap<String, Vec<u8>>>,
+    /// Files on other branches, by branch then path
+    pub branches: BTreeMap<u64, BTreeMap<String, BTreeMap<String, Vec<u8>>>>,
+    pub merge_requests: BTreeMap<u64, Vec<Value>>,
+    /// Newest first
+    pub tags: BTreeMap<u64, Vec<Value>>,
+    failures: Vec<(String, String, u16)>,
+    pub calls: Vec<Call>,
+}
+
+impl GitLabState {
+    fn id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn delete_group(&mut self, id: u64) {
+        let subgroups: Vec<u64> = self.groups.values()
+            .filter(|g| g["parent_id"].as_u64() == Some(id))
+            .map(|g| g["id"].as_u64().unwrap())
+            .collect();
+        for sub in subgroups {
+            self.delete_group(sub);
+        }
+        let projects: Vec<u64> = self.projects.values()
+            .filter(|p| p["namespace_id"].as_u64() == Some(id))
+            .map(|p| p["id"].as_u64().unwrap())
+            .collect();
+        for project in projects {
+            self.projects.remove(&project);
+        }
+        self.groups.remove(&id);
+        self.group_members.remove(&id);
+    }
+
+    fn new_project(&mut self, namespace: u64, name: &Value, visibility: &Value) -> Result<Value, Reply> {
+        if !self.groups.contains_key(&namespace) {
+            return Err(Reply::not_found("Namespace"));
+        }
+        let taken = self.projects.values().any(|p| p["name"] == *name && p["namespace_id"] == namespace);
+        if taken {
+            return Err(Reply::json(400, json!({"message": {"name": ["has already been taken"]}})));
+        }
+        let id = self.id();
+        let project = json!({
+            "id": id,
+            "name": name,
+            "namespace_id": namespace,
+            "visibility": visibility,
+            "ssh_url_to_repo": format!("git@gitlab.test:{}/{}.git", namespace, name.as_str().unwrap_or("")),
+            "default_branch": "master",
+        });
+        self.projects.insert(id, project.clone());
+        Ok(project)
+    }
+
+    /// Files of `project` at `git_ref`, a branch name.
+    fn tree(&self, project: u64, git_ref: &str) -> Option<&BTreeMap<String, Vec<u8>>> {
+        if self.projects.get(&project)?["default_branch"] == git_ref {
+            self.files.get(&project)
+        } else {
+            self.branches.get(&project)?.get(git_ref)
+        }
+    }
+
+    fn handle(&mut self, base: &str, method: &str, url: &str, body: &Value) -> Reply {
+        let (path, query) = match url.find('?') {
+            Some(i) => (&url[..i], &url[i + 1..]),
+            None => (url, ""),
+        };
+        let query: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        let query: BTreeMap<&str, &str> = query.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        if let Some(i) = self.failures.iter().position(|(m, p, _)| m == method && p == path) {
+            let (_, _, status) = self.failures.remove(i);
+            return Reply::json(status, json!({"message": "Injected failure"}));
+        }
+        if path == "/-/health" {
+            return Reply::json(200, json!({}));
+        }
+        let segments: Vec<&str> = path.trim_start_matches("/api/v4/").split('/').filter(|s| !s.is_empty()).collect();
+        let num = |i: usize| segments.get(i).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+        match (method, &segments[..]) {
+            ("POST", ["users"]) => {
+                let taken = self.users.values().any(|u| u["username"] == body["username"] || u["email"] == body["email"]);
+                if taken {
+                    return Reply::json(409, json!({"message": "Email has already been taken"}));
+                }
+                let id = self.id();
+                let user = json!({"id": id, "username": body["username"], "email": body["email"], "name": body["name"]});
+                self.users.insert(id, user.clone());
+                Reply::json(201, user)
+            }
+            ("GET", ["users"]) => {
+                let found: Vec<Value> = self.users.values()
+                    .filter(|u| u["username"].as_str() == query.get("username").cloned())
+                    .cloned().collect();
+                Reply::json(200, Value::Array(found))
+            }
+            ("GET", ["users", _, "keys"]) => {
+                Reply::json(200, Value::Array(self.keys.get(&num(1)).cloned().unwrap_or_default()))
+            }
+            ("POST", ["users", _, "keys"]) => {
+                if !self.users.contains_key(&num(1)) {
+                    return Reply::not_found("User");
+                }
+                let id = self.id();
+                let key = json!({"id": id, "title": body["title"], "key": body["key"]});
+                self.keys.entry(num(1)).or_default().push(key.clone());
+                Reply::json(201, key)
+            }
+            ("DELETE", ["users", _, "keys", _]) => {
+                let key = num(3);
+                self.keys.entry(num(1)).or_default().retain(|k| k["id"].as_u64() != Some(key));
+                Reply::json(204, Value::Null)
+            }
+            ("POST", ["groups"]) => {
+                let taken = self.groups.values().any(|g| g["path"] == body["path"] && g["parent_id"] == body["parent_id"]);
+                if taken {
+                    return Reply::json(400, json!({"message": "Failed to save group {:path=>[\"has already been taken\"]}"}));
+                }
+                if let Some(parent) = body["parent_id"].as_u64() {
+                    if !self.groups.contains_key(&parent) {
+                        return Reply::not_found("Group");
+                    }
+                }
+                let id = self.id();
+                let group = json!({"id": id, "name": body["name"], "path": body["path"],
+                                   "visibility": body["visibility"], "parent_id": body["parent_id"]});
+                self.groups.insert(id, group.clone());
+                Reply::json(201, group)
+            }
+            ("GET", ["groups", _]) => match self.groups.get(&num(1)) {
+                Some(group) => Reply::json(200, group.clone()),
+                None => Reply::not_found("Group"),
+            },
+            ("DELETE", ["groups", _]) => {
+                if !self.groups.contains_key(&num(1)) {
+                    return Reply::not_found("Group");
+                }
+                self.delete_group(num(1));
+                Reply::json(202, json!({"message": "202 Accepted"}))
+            }
+            ("GET", ["groups", _, "subgroups"]) => {
+                if !self.groups.contains_key(&num(1)) {
+                    return Reply::not_found("Group");
+                }
+                let parent = num(1);
+                let found: Vec<Value> = self.groups.values()
+                    .filter(|g| g["parent_id"].as_u64() == Some(parent))
+                    .cloned().collect();
+                Reply::json(200, Value::Array(found))
+            }
+            ("GET", ["groups", _, "members"]) => {
+                paged(base, path, &query, &self.group_members.get(&num(1)).cloned().unwrap_or_default())
+            }
+            ("POST", ["groups", _, "members"]) => {
+                if !self.groups.contains_key(&num(1)) {
+                    return Reply::not_found("Group");
+                }
+                let user = match body["user_id"].as_u64().and_then(|id| self.users.get(&id)) {
+                    Some(user) => user.clone(),
+                    None => return Reply::not_found("User"),
+                };
+                let member = json!({"id": user["id"], "username": user["username"], "access_level": body["access_level"]});
+                self.group_members.entry(num(1)).or_default().push(member.clone());
+                Reply::json(201, member)
+            }
+            ("PUT", ["groups", _, "members", _]) => {
+                let member = self.group_members.get_mut(&num(1))
+                    .and_then(|members| members.iter_mut().find(|m| m["id"].as_u64() == Some(num(3))));
+                match member {
+                    Some(member) => {
+                        member["access_level"] = json!(query["access_level"].parse::<u64>().unwrap());
+                        Reply::json(200, member.clone())
+                    }
+                    None => Reply::not_found("Member"),
+                }
+            }
+            ("DELETE", ["groups", _, "members", _]) => {
+                let members = self.group_members.entry(num(1)).or_default();
+                match members.iter().position(|m| m["id"].as_u64() == Some(num(3))) {
+                    Some(i) => {
+                        members.remove(i);
+                        Reply::json(204, Value::Null)
+                    }
+                    None => Reply::not_found("Member"),
+                }
+            }
+            ("POST", ["projects"]) => {
+                match self.new_project(body["namespace_id"].as_u64().unwrap_or(0), &body["name"], &body["visibility"]) {
+                    Ok(project) => Reply::json(201, project),
+                    Err(reply) => reply,
+                }
+            }
+            ("GET", ["projects", id]) => {
+                // by id, or by url encoded path
+                let path = id.replace("%2F", "/");
+                match self.projects.values_mut().find(|p| p["id"].as_u64() == Some(num(1)) || p["path_with_namespace"] == path.as_str()) {
+                    Some(stored) => {
+                        let mut project = stored.clone();
+                        // forks are done the next time they are looked at
+                        if stored["import_status"] == "started" {
+                            stored["import_status"] = json!("finished");
+                        }
+                        project["empty_repo"] = json!(!self.commits.contains_key(&project["id"].as_u64().unwrap()));
+                        Reply::json(200, project)
+                    }
+                    None => Reply::not_found("Project"),
+                }
+            }
+            ("POST", ["projects", _, "fork"]) => {
+                if !self.projects.contains_key(&num(1)) {
+                    return Reply::not_found("Project");
+                }
+                let mut project = match self.new_project(body["namespace_id"].as_u64().unwrap_or(0), &body["name"], &body["visibility"]) {
+                    Ok(project) => project,
+                    Err(reply) => return reply,
+                };
+                let id = project["id"].as_u64().unwrap();
+                project["forked_from_project"] = json!({"id": num(1)});
+                project["import_status"] = json!("started");
+                self.projects.insert(id, project.clone());
+                let commits = self.commits.get(&num(1)).cloned().unwrap_or_default();
+                let files = self.files.get(&num(1)).cloned().unwrap_or_default();
+                self.commits.insert(id, commits);
+                self.files.insert(id, files);
+                Reply::json(201, project)
+            }
+            ("DELETE", ["projects", _, "fork"]) => {
+                match self.projects.get_mut(&num(1)).and_then(|p| p.as_object_mut().unwrap().remove("forked_from_project")) {
+                    Some(_) => Reply::json(204, Value::Null),
+                    None => Reply::not_found("Project"),
+                }
+            }
+            ("GET", ["projects", _, "repository", "tree"]) => {
+                let default_branch = match self.projects.get(&num(1)) {
+                    Some(project) => project["default_branch"].as_str().unwrap().to_string(),
+                    None => return Reply::not_found("Project"),
+                };
+                let files = self.tree(num(1), query.get("ref").unwrap_or(&default_branch.as_str())).cloned().unwrap_or_default();
+                if query.get("recursive") == Some(&"true") {
+                    let tree: Vec<Value> = files.keys().map(|path| json!({"path": path, "type": "blob"})).collect();
+                    return Reply::json(200, Value::Array(tree));
+                }
+                let prefix = match query.get("path") {
+                    Some(path) => format!("{}/", path.replace("%2F", "/")),
+                    None => String::new(),
+                };
+                let mut tree: Vec<Value> = Vec::new();
+                for path in files.keys().filter(|path| path.starts_with(&prefix)) {
+                    let entry = match path[prefix.len()..].find('/') {
+                        Some(i) => json!({"name": &path[prefix.len()..prefix.len() + i], "path": &path[..prefix.len() + i], "type": "tree", "mode": "040000"}),
+                        None => json!({"name": &path[prefix.len()..], "path": path, "type": "blob", "mode": "100644"}),
+                    };
+                    if !tree.contains(&entry) {
+                        tree.push(entry);
+                    }
+                }
+                if tree.is_empty() {
+                    return Reply::not_found("Tree");
+                }
+                Reply::json(200, Value::Array(tree))
+            }
+            ("GET", ["projects", _, "repository", "files", path, "raw"]) => {
+                match self.tree(num(1), query.get("ref").unwrap_or(&"master")).and_then(|files| files.get(&path.replace("%2F", "/"))) {
+                    Some(content) => Reply { status: 200, headers: Vec::new(), body: content.clone() },
+                    None => Reply::not_found("File"),
+                }
+            }
+            ("GET", ["projects", _, "repository", "files", path]) => {
+                let path = path.replace("%2F", "/");
+                match self.tree(num(1), query.get("ref").unwrap_or(&"master")).and_then(|files| files.get(&path)) {
+                    Some(content) => Reply::json(200, json!({"file_path": path, "size": content.len()})),
+                    None => Reply::not_found("File"),
+                }
+            }
+            ("GET", ["projects", _, "repository", "branches", name]) => match self.tree(num(1), name) {
+                Some(_) => Reply::json(200, json!({"name": name})),
+                None => Reply::not_found("Branch"),
+            },
+            ("GET", ["projects", _, "repository", "compare"]) => {
+                let empty = BTreeMap::new();
+                let from = self.tree(num(1), query.get("from").unwrap_or(&"")).unwrap_or(&empty);
+                let to = match self.tree(num(1), query.get("to").unwrap_or(&"")) {
+                    Some(to) => to,
+                    None => return Reply::not_found("Ref"),
+                };
+                let diffs: Vec<Value> = from.keys().chain(to.keys())
+                    .filter(|path| from.get(*path) != to.get(*path))
+                    .collect::<::std::collections::BTreeSet<_>>().into_iter()
+                    .map(|path| json!({
+                        "old_path": path,
+                        "new_path": path,
+                        "a_mode": if from.contains_key(path) { "100644" } else { "0" },
+                        "b_mode": if to.contains_key(path) { "100644" } else { "0" },
+                        "new_file": !from.contains_key(path),
+                        "renamed_file": false,
+                        "deleted_file": !to.contains_key(path),
+                        "diff": hunk(from.get(path), to.get(path)),
+                    }))
+                    .collect();
+                Reply::json(200, json!({"diffs": diffs}))
+            }
+            ("POST", ["projects", _, "repository", "commits"]) => {
+                let branch = body["branch"].as_str().unwrap();
+                let empty = self.commits.get(&num(1)).map_or(true, Vec::is_empty);
+                let default_branch = match self.projects.get_mut(&num(1)) {
+                    // the first commit names the default branch
+                    Some(project) if empty => {
+                        project["default_branch"] = json!(branch);
+                        branch.to_string()
+                    }
+                    Some(project) => project["default_branch"].as_str().unwrap().to_string(),
+                    None => return Reply::not_found("Project"),
+                };
+                if let Some(start) = body["start_branch"].as_str() {
+                    if self.tree(num(1), branch).is_some() {
+                        return Reply::json(400, json!({"message": format!("A branch called '{}' already exists", branch)}));
+                    }
+                    let files = self.tree(num(1), start).cloned().unwrap_or_default();
+                    self.branches.entry(num(1)).or_default().insert(branch.to_string(), files);
+                }
+                let files = if branch == default_branch {
+                    self.files.entry(num(1)).or_default()
+                } else {
+                    match self.branches.get_mut(&num(1)).and_then(|b| b.get_mut(branch)) {
+                        Some(files) => files,
+                        None => return Reply::json(400, json!({"message": "You can only create or edit files when you are on a branch"})),
+                    }
+                };
+                for action in body["actions"].as_array().unwrap() {
+                    let path = action["file_path"].as_str().unwrap().to_string();
+                    match action["action"].as_str().unwrap() {
+                        "create" | "update" => {
+                            files.insert(path, ::base64::decode(action["content"].as_str().unwrap()).unwrap());
+                        }
+                        "delete" => {
+                            files.remove(&path);
+                        }
+                        other => panic!("Unknown commit action {}", other),
+                    }
+                }
+                let sha = format!("{:040x}", self.id());
+                let message = body["commit_message"].as_str().unwrap();
+                let parents: Vec<&str> = self.commits.get(&num(1)).and_then(|c| c.first()).and_then(|c| c["id"].as_str()).into_iter().collect();
+                let commit = commit(&sha, message, "root@gitlab.test", "2012-10-22T14:13:35.000+08:00", &parents);
+                if branch == default_branch {
+                    self.commits.entry(num(1)).or_default().insert(0, commit.clone());
+                }
+                Reply::json(201, commit)
+            }
+            ("POST", ["projects", _, "merge_requests"]) => {
+                let source = body["source_branch"].as_str().unwrap();
+                if self.tree(num(1), source).is_none() {
+                    return Reply::not_found("Branch");
+                }
+                let requests = self.merge_requests.entry(num(1)).or_default();
+                let iid = requests.len() + 1;
+                let mut request = body.clone();
+                request["iid"] = json!(iid);
+                request["web_url"] = json!(format!("https://gitlab.test/{}/merge_requests/{}", num(1), iid));
+                requests.push(request.clone());
+                Reply::json(201, request)
+            }
+            ("DELETE", ["projects", _]) => match self.projects.remove(&num(1)) {
+                Some(_) => Reply::json(202, json!({"message": "202 Accepted"})),
+                None => Reply::not_found("Project"),
+            },
+            ("POST", ["projects", _, "hooks"]) => {
+                if !self.projects.contains_key(&num(1)) {
+                    return Reply::not_found("Project");
+                }
+                let id = self.id();
+                let mut hook = body.clone();
+                hook["id"] = json!(id);
+                self.hooks.entry(num(1)).or_default().push(hook.clone());
+                Reply::json(201, hook)
+            }
+            ("POST", ["projects", _, "protected_branches"]) => {
+                if !self.projects.contains_key(&num(1)) {
+                    return Reply::not_found("Project");
+                }
+                let name = query.get("name").cloned().unwrap_or("");
+                let branches = self.protected_branches.entry(num(1)).or_default();
+                if branches.iter().any(|b| b["name"] == name) {
+                    return Reply::json(409, json!({"message": "Protected branch already exists"}));
+                }
+                let branch = json!({"name": name, "push_access_level": query.get("push_access_level").map_or(40, |l| l.parse().unwrap())});
+                branches.push(branch.clone());
+                Reply::json(201, branch)
+            }
+            ("DELETE", ["projects", _, "protected_branches", name]) => {
+                let branches = self.protected_branches.entry(num(1)).or_default();
+                match branches.iter().position(|b| b["name"] == *name) {
+                    Some(i) => {
+                        branches.remove(i);
+                        Reply::json(204, Value::Null)
+                    }
+                    None => Reply::not_found("Protected branch"),
+                }
+            }
+            ("GET", ["projects", _, "members"]) => {
+                paged(base, path, &query, &self.project_members.get(&num(1)).cloned().unwrap_or_default())
+            }
+            ("POST", ["projects", _, "members"]) => {
+                if !self.projects.contains_key(&num(1)) {
+                    return Reply::not_found("Project");
+                }
+                let user = match body["user_id"].as_u64().and_then(|id| self.users.get(&id)) {
+                    Some(user) => user.clone(),
+                    None => return Reply::not_found("User"),
+                };
+                let member = json!({"id": user["id"], "username": user["username"],
+                                    "access_level": body["access_level"], "expires_at": body["expires_at"]});
+                self.project_members.entry(num(1)).or_default().push(member.clone());
+                Reply::json(201, member)
+            }
+            ("PUT", ["projects", _, "members", _]) => {
+                let member = self.project_members.get_mut(&num(1))
+                    .and_then(|members| members.iter_mut().find(|m| m["id"].as_u64() == Some(num(3))));
+                match member {
+                    Some(member) => {
+                        member["access_level"] = body["access_level"].clone();
+                        if !body["expires_at"].is_null() {
+                            member["expires_at"] = body["expires_at"].clone();
+                        }
+                        Reply::json(200, member.clone())
+                    }
+                    None => Reply::not_found("Member"),
+                }
+            }
+            ("DELETE", ["projects", _, "members", _]) => {
+                let members = self.project_members.entry(num(1)).or_default();
+                match members.iter().position(|m| m["id"].as_u64() == Some(num(3))) {
+                    Some(i) => {
+                        members.remove(i);
+                        Reply::json(204, Value::Null)
+                    }
+                    None => Reply::not_found("Member"),
+                }
+            }
+            ("GET", ["projects", _, "repository", archive]) if archive.starts_with("archive.") => {
+                if !self.projects.contains_key(&num(1)) {
+                    return Reply::not_found("Project");
+                }
+                // a commit, otherwise the default branch
+                let commit = match query.get("sha") {
+                    Some(sha) if self.projects[&num(1)]["default_branch"] != *sha =>
+                        self.commits.get(&num(1)).and_then(|c| c.iter().find(|c| c["id"] == *sha)),
+                    _ => self.commits.get(&num(1)).and_then(|c| c.first()),
+                };
+                match commit {
+                    Some(commit) => {
+                        let sha = commit["id"].as_str().unwrap();
+                        let format = &archive["archive.".len()..];
+                        let disposition = format!(r#"attachment; filename="repo-master-{}.{}""#, sha, format);
+                        // only tar.gz is a real archive, of the files as they are now
+                        let body = if format == "tar.gz" {
+                            let files = self.files.get(&num(1)).cloned().unwrap_or_default();
+                            tar_gz(&format!("repo-{}-{}", sha, sha), &files)
+                        } else {
+                            format!("archive of {}", sha).into_bytes()
+                        };
+                        Reply {
+                            status: 200,
+                            headers: vec![("Content-Disposition".to_string(), disposition),
+                                          ("Etag".to_string(), r#"W/"archive""#.to_string())],
+                            body,
+                        }
+                    }
+                    None => Reply::not_found("File"),
+                }
+            }
+            ("GET", ["projects", _, "repository", "tags"]) => {
+                if !self.projects.contains_key(&num(1)) {
+                    return Reply::not_found("Project");
+                }
+                Reply::json(200, Value::Array(self.tags.get(&num(1)).cloned().unwrap_or_default()))
+            }
+            ("GET", ["projects", _, "repository", "commits"]) => {
+                let commits = match self.commits.get(&num(1)) {
+                    Some(commits) => commits,
+                    None => return Reply::not_found("Tree"),
+                };
+                let per_page = query.get("per_page").and_then(|p| p.parse().ok()).unwrap_or(20usize);
+                let page = query.get("page").and_then(|p| p.parse().ok()).unwrap_or(1usize);
+                if let Some(git_ref) = query.get("ref_name") {
+                    let known = self.projects[&num(1)]["default_branch"] == *git_ref || self.tree(num(1), git_ref).is_some()
+                        || commits.iter().any(|c| c["id"] == *git_ref);
+                    if !known {
+                        return Reply::not_found("Ref");
+                    }
+                }
+                let commits: Vec<&Value> = commits.iter()
+                    .filter(|c| match (query.get("until"), c["committed_date"].as_str()) {
+                        (Some(until), Some(date)) => date <= *until,
+                        _ => true,
+                    })
+                    .filter(|c| match (query.get("since"), c["committed_date"].as_str()) {
+                        (Some(since), Some(date)) => date >= *since,
+                        _ => true,
+                    })
+                    .collect();
+                let items: Vec<Value> = commits.iter().skip((page - 1) * per_page).take(per_page).cloned().cloned().collect();
+                let mut reply = Reply::json(200, Value::Array(items));
+                if page * per_page < commits.len() {
+                    let next = format!("{}projects/{}/repository/commits?page={}&per_page={}", base, num(1), page + 1, per_page);
+                    reply.headers.push(("Link".to_string(), format!(r#"<{}>; rel="next""#, next)));
+                }
+                reply
+            }
+            _ => Reply::json(404, json!({"error": "404 Not Found"})),
+        }
+    }
+}
+
+/// A page of `items` as asked for by `query`, linking to the next one if any.
+fn paged(base: &str, path: &str, query: &BTreeMap<&str, &str>, items: &[Value]) -> Reply {
+    let per_page = query.get("per_page").and_then(|p| p.parse().ok()).unwrap_or(20usize);
+    let page = query.get("page").and_then(|p| p.parse().ok()).unwrap_or(1usize);
+    let mut reply = Reply::json(200, Value::Array(items.iter().skip((page - 1) * per_page).take(per_page).cloned().collect()));
+    if page * per_page < items.len() {
+        let next = format!("{}{}?page={}&per_page={}", base, path.trim_start_matches("/api/v4/"), page + 1, per_page);
+        reply.headers.push(("Link".to_string(), format!(r#"<{}>; rel="next""#, next)));
+    }
+    reply
+}
+
+/// A file rewritten as a whole, as a single hunk.
+fn hunk(old: Option<&Vec<u8>>, new: Option<&Vec<u8>>) -> String {
+    let lines = |content: Option<&Vec<u8>>| content.map(|c| String::from_utf8(c.clone()).unwrap().lines().map(str::to_string).collect()).unwrap_or_default();
+    let (old, new) = (lines(old), lines(new));
+    let range = |lines: &Vec<String>| if lines.is_empty() { "0,0".to_string() } else { format!("1,{}", lines.len()) };
+    let mut hunk = format!("@@ -{} +{} @@\n", range(&old), range(&new));
+    for line in &old {
+        hunk += &format!("-{}\n", line);
+    }
+    for line in &new {
+        hunk += &format!("+{}\n", line);
+    }
+    hunk
+}
+
+/// A commit as gitlab lists it.
+fn commit(sha: &str, message: &str, email: &str, date: &str, parents: &[&str]) -> Value {
+    json!({
+        "id": sha,
+        "short_id": &sha[..8],
+        "title": message,
+        "message": message,
+        "author_name": email.split('@').next().unwrap(),
+        "author_email": email,
+        "authored_date": date,
+        "committed_date": date,
+        "parent_ids": parents,
+    })
+}
+
+/// `files` under folder `root`, as gitlab archives a repo.
+fn tar_gz(root: &str, files: &BTreeMap<String, Vec<u8>>) -> Vec<u8> {
+    let mut tar = ::tar::Builder::new(::flate2::write::GzEncoder::new(Vec::new(), ::flate2::Compression::default()));
+    for (path, content) in files {
+        let mut header = ::tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(content.len() as u64);
+        tar.append_data(&mut header, format!("{}/{}", root, path), &content[..]).unwrap();
+    }
+    tar.into_inner().unwrap().finish().unwrap()
+}
+
+pub struct MockGitLab {
+    base: String,
+    state: Arc<Mutex<GitLabState>>,
+}
+
+impl MockGitLab {
+    pub fn start() -> MockGitLab {
+        let state = Arc::new(Mutex::new(GitLabState::default()));
+        let shared = state.clone();
+        let base = serve(move |base, call| {
+            let mut state = shared.lock().unwrap();
+            state.calls.push(call.clone());
+            state.handle(&format!("{}/api/v4/", base), &call.method, &call.url, &call.body)
+        });
+        MockGitLab { base, state }
+    }
+
+    /// What goes into `gitlab_base_url`.
+    pub fn url(&self) -> String {
+        format!("{}/api/v4/", self.base)
+    }
+
+    pub fn state(&self) -> MutexGuard<GitLabState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Outbound calls made so far, as `"<METHOD> <path relative to api base>"`.
+    pub fn calls(&self) -> Vec<String> {
+        self.state().calls.iter()
+            .map(|c| format!("{} {}", c.method, c.url.trim_start_matches("/api/v4/")))
+            .collect()
+    }
+
+    pub fn clear_calls(&self) {
+        self.state().calls.clear();
+    }
+
+    /// Make the next `method` call to `path` (relative to api base, without query) fail with `status`.
+    pub fn fail(&self, method: &str, path: &str, status: u16) {
+        self.state().failures.push((method.to_string(), format!("/api/v4/{}", path), status));
+    }
+
+    /// A project outside of any course, e.g. a template, with one commit adding `files`.
+    pub fn create_project(&self, path: &str, files: &[(&str, &str)]) -> u64 {
+        let id = {
+            let mut state = self.state();
+            let id = state.id();
+            state.projects.insert(id, json!({"id": id, "name": path.rsplit('/').next().unwrap(), "path_with_namespace": path, "default_branch": "master"}));
+            state.files.insert(id, files.iter().map(|(path, content)| (path.to_string(), content.as_bytes().to_vec())).collect());
+            id
+        };
+        self.push_commit(id, "0123456789abcdef0123456789abcdef01234567", "Starter code");
+        id
+    }
+
+    /// A branch off the default one, with `files` changed. `None` deletes the file.
+    pub fn create_branch(&self, project: u64, name: &str, files: &[(&str, Option<&str>)]) {
+        let mut state = self.state();
+        let mut tree = state.files[&project].clone();
+        for (path, content) in files {
+            match content {
+                Some(content) => tree.insert(path.to_string(), content.as_bytes().to_vec()),
+                None => tree.remove(*path),
+            };
+        }
+        state.branches.entry(project).or_default().insert(name.to_string(), tree);
+    }
+
+    /// Pretend someone pushed a tag of commit `sha`, annotated if `message` isn't empty.
+    pub fn push_tag(&self, project: u64, name: &str, sha: &str, message: &str) {
+        let mut state = self.state();
+        let commit = state.commits[&project].iter().find(|c| c["id"] == sha).unwrap().clone();
+        let tag = json!({"name": name, "message": message, "target": sha, "commit": commit});
+        state.tags.entry(project).or_default().insert(0, tag);
+    }
+
+    /// Pretend someone pushed a commit, newest first as gitlab lists them.
+    pub fn push_commit(&self, project: u64, sha: &str, message: &str) {
+        self.push_commit_as(project, sha, message, "wangdch@shanghaitech.edu.cn", "2012-10-22T14:13:35.000+08:00");
+    }
+
+    /// As `push_commit`, committed at `date`, e.g. `2012-10-22T14:13:35Z`.
+    pub fn push_commit_at(&self, project: u64, sha: &str, message: &str, date: &str) {
+        self.push_commit_as(project, sha, message, "wangdch@shanghaitech.edu.cn", date);
+    }
+
+    /// As `push_commit_at`, authored by `email`.
+    pub fn push_commit_as(&self, project: u64, sha: &str, message: &str, email: &str, date: &str) {
+        let mut state = self.state();
+        let commits = state.commits.entry(project).or_default();
+        let parent = commits.first().map(|c| c["id"].as_str().unwrap().to_string());
+        let commit = commit(sha, message, email, date, &parent.as_deref().into_iter().collect::<Vec<_>>());
+        commits.insert(0, commit);
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Integration tests. Every test gets its own fake gitlab, fake backend and fake mysql,
+//! and drives the routes through `rocket::local::Client`.
+//! `GiteaAPI` is tested on its own, against a fake gitea giving canned replies.
+
+mod mock_gitea;
+mod mock_gitlab;
+mod mock_mysql;
+mod bulk;
+mod deadline;
+mod errors;
+mod events;
+mod export;
+mod gitea;
+mod idempotency;
+mod jobs;
+mod outbox;
+mod routes;
+mod submission;
+mod template;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rocket::config::{Config, ConfigBuilder, Environment, LoggingLevel};
+use rocket::local::Client;
+use serde_json::{json, Value};
+use tiny_http::{Header, Response, Server};
+
+use self::mock_gitlab::MockGitLab;
+use self::mock_mysql::MockMySQL;
+
+/// An inbound request as seen by a fake server.
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Value,
+}
+
+impl Call {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| &**v)
+    }
+}
+
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn json(status: u16, body: Value) -> Reply {
+        Reply {
+            status,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: if body.is_null() { Vec::new() } else { body.to_string().into_bytes() },
+        }
+    }
+
+    pub fn not_found(what: &str) -> Reply {
+        Reply::json(404, json!({"message": format!("404 {} Not Found", what)}))
+    }
+}
+
+/// Start a http server on a random port. Returns its base url.
+pub fn serve<F>(handler: F) -> String
+    where F: Fn(&str, &Call) -> Reply + Send + Sync + 'static {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let base = format!("http://127.0.0.1:{}", server.server_addr().to_ip().unwrap().port());
+    let handler = Arc::new(handler);
+    let shared_base = base.clone();
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let handler = handler.clone();
+            let base = shared_base.clone();
+            thread::spawn(move || {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let call = Call {
+                    method: request.method().to_string(),
+                    url: request.url().to_string(),
+                    headers: request.headers().iter()
+                        .map(|h| (h.field.to_string(), h.value.to_string()))
+                        .collect(),
+                    body: serde_json::from_str(&body).unwrap_or(Value::Null),
+                };
+                let reply = handler(&base, &call);
+                let mut response = Response::from_data(reply.body).with_status_code(reply.status);
+                for (name, value) in reply.headers {
+                    response.add_header(Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap());
+                }
+                let _ = request.respond(response);
+            });
+        }
+    });
+    base
+}
+
+/// The oj backend. Records forwarded requests and answers with a configurable status.
+pub struct MockBackend {
+    base: String,
+    calls: Arc<Mutex<Vec<Call>>>,
+    status: Arc<Mutex<u16>>,
+}
+
+impl MockBackend {
+    pub fn start() -> MockBackend {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let status = Arc::new(Mutex::new(200));
+        let (shared_calls, shared_status) = (calls.clone(), status.clone());
+        let base = serve(move |_, call| {
+            shared_calls.lock().unwrap().push(call.clone());
+            Reply::json(*shared_status.lock().unwrap(), Value::Null)
+        });
+        MockBackend { base, calls, status }
+    }
+
+    pub fn url(&self) -> String {
+        format!("{}/", self.base)
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Calls once there are at least `count` of them.
+    pub fn wait_calls(&self, count: usize) -> Vec<Call> {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            let calls = self.calls();
+            if calls.len() >= count {
+                return calls;
+            }
+            assert!(Instant::now() < deadline, "backend got {} calls, expecting {}", calls.len(), count);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    pub fn set_status(&self, status: u16) {
+        *self.status.lock().unwrap() = status;
+    }
+}
+
+pub const MIDDLEWARE_BASE: &str = "http://middleware.test";
+pub const TOKEN_SALT: &str = "SALT";
+
+pub struct TestContext {
+    pub client: Client,
+    pub gitlab: MockGitLab,
+    pub backend: MockBackend,
+    pub db: MockMySQL,
+}
+
+impl Default for TestContext {
+    fn default() -> TestContext {
+        TestContext::with_config(|c| c)
+    }
+}
+
+impl TestContext {
+    pub fn new() -> TestContext {
+        TestContext::default()
+    }
+
+    /// Tweak the config on top of the defaults pointing at the fakes.
+    pub fn with_config<F: FnOnce(ConfigBuilder) -> ConfigBuilder>(f: F) -> TestContext {
+        let gitlab = MockGitLab::start();
+        let backend = MockBackend::start();
+        let db = MockMySQL::start();
+        let mut mysql = BTreeMap::new();
+        mysql.insert("url", ::rocket::config::Value::from(db.url()));
+        // job workers and bulk workers hold their own
+        mysql.insert("pool_size", ::rocket::config::Value::from(10));
+        let mut databases = BTreeMap::new();
+        databases.insert("mysql", mysql);
+        let config = Config::build(Environment::Development)
+            .log_level(LoggingLevel::Off)
+            .extra("databases", databases)
+            .extra("backend_url", backend.url())
+            .extra("backend_auth_header", "backend-secret")
+            .extra("middleware_base", MIDDLEWARE_BASE)
+            .extra("gitlab_base_url", gitlab.url())
+            .extra("gitlab_auth_token", "gitlab-secret")
+            .extra("gitlab_webhook_token_salt", TOKEN_SALT)
+            .extra("job_poll_interval", 1)
+            .extra("webhook_poll_interval", 1)
+            .extra("webhook_retry_base", 0)
+            .extra("webhook_max_attempts", 3)
+            .extra("deadline_poll_interval", 1);
+        let config = f(config).finalize().unwrap();
+        let client = Client::new(::setup(::rocket::custom(config))).unwrap();
+        TestContext { client, gitlab, backend, db }
+    }
+}
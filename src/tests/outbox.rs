@@ -0,0 +1,152 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rocket::http::{ContentType, Header, Status};
+use serde_json::{json, Value};
+
+use jobs::now;
+use super::*;
+use super::routes::{hook, push_payload};
+
+impl TestContext {
+    fn push(&self) {
+        let (uri, token) = hook(self);
+        let response = self.client.post(uri).header(ContentType::JSON)
+            .header(Header::new("X-Gitlab-Event", "Push Hook"))
+            .header(Header::new("X-Gitlab-Token", token))
+            .body(push_payload().to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    fn outbox_status(&self) -> Vec<String> {
+        self.db.query("SELECT status FROM webhook_outbox ORDER BY id").into_iter().map(|mut r| r.remove(0)).collect()
+    }
+
+    fn wait_outbox(&self, status: &str) {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while self.outbox_status().iter().any(|s| s != status) {
+            assert!(Instant::now() < deadline, "outbox never settled to {}: {:?}", status, self.outbox_status());
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn wait_outbox_len(&self, len: usize) {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while self.outbox_status().len() != len {
+            assert!(Instant::now() < deadline, "outbox never got {} events: {:?}", len, self.outbox_status());
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn dead_webhooks(&self) -> Value {
+        let mut response = self.client.get("/webhooks/dead").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    }
+}
+
+#[test]
+fn delivered_once() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    ctx.push();
+    ctx.wait_outbox("delivered");
+    assert_eq!(ctx.backend.calls().len(), 1);
+    assert_eq!(ctx.dead_webhooks(), json!([]));
+}
+
+#[test]
+fn retried_while_backend_down() {
+    let ctx = TestContext::with_config(|c| c.extra("webhook_max_attempts", 100));
+    ctx.create_repo();
+    ctx.backend.set_status(503);
+    ctx.push();
+    ctx.backend.wait_calls(2);
+    assert_eq!(ctx.outbox_status(), vec!["pending"]);
+    ctx.backend.set_status(200);
+    ctx.wait_outbox("delivered");
+    let calls = ctx.backend.calls();
+    assert!(calls.len() >= 3);
+    // the same event every time
+    assert!(calls.iter().all(|c| c.body == calls[0].body));
+}
+
+#[test]
+fn dead_letter_replayed() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    ctx.backend.set_status(500);
+    ctx.push();
+    ctx.wait_outbox("dead");
+    assert_eq!(ctx.backend.calls().len(), 3);
+    let dead = ctx.dead_webhooks();
+    assert_eq!(dead.as_array().unwrap().len(), 1);
+    assert_eq!(dead[0]["path"], "internal/submission");
+    assert_eq!(dead[0]["attempts"], 3);
+    assert_eq!(dead[0]["payload"]["upstream"], "git@gitlab.test:3/wangdch.git");
+    assert_eq!(dead[0]["last_error"]["upstream_status"], 500);
+
+    ctx.backend.set_status(200);
+    let mut response = ctx.client.post("/webhooks/dead/replay").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let replayed: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(replayed, json!({"replayed": 1}));
+    ctx.wait_outbox("delivered");
+    assert_eq!(ctx.backend.calls().len(), 4);
+    assert_eq!(ctx.dead_webhooks(), json!([]));
+}
+
+#[test]
+fn replay_unknown_dead_letter() {
+    let ctx = TestContext::new();
+    assert_eq!(ctx.client.post("/webhooks/dead/replay?id=42").dispatch().status(), Status::NotFound);
+    let mut response = ctx.client.post("/webhooks/dead/replay").dispatch();
+    assert_eq!(response.body_string().unwrap(), r#"{"replayed":0}"#);
+}
+
+#[test]
+fn broken_payload_dead_right_away() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    ctx.db.query("INSERT INTO webhook_outbox(path, payload, status, next_attempt_at, created_at) VALUES ('internal/submission', '{broken', 'pending', 0, 0)");
+    // the one after it still goes out
+    ctx.push();
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while ctx.outbox_status() != vec!["dead", "delivered"] {
+        assert!(Instant::now() < deadline, "outbox never settled: {:?}", ctx.outbox_status());
+        thread::sleep(Duration::from_millis(50));
+    }
+    let dead = ctx.dead_webhooks();
+    assert_eq!(dead[0]["payload"], "{broken");
+    assert_eq!(dead[0]["attempts"], 0);
+    assert_eq!(dead[0]["last_error"]["code"], "json_error");
+}
+
+#[test]
+fn delivered_forgotten_after_a_week() {
+    let ctx = TestContext::new();
+    let week_ago = now() - 7 * 24 * 3600;
+    for (status, delivered_at) in &[("delivered", week_ago - 1), ("delivered", week_ago + 60), ("dead", 0)] {
+        ctx.db.query(&format!("INSERT INTO webhook_outbox(path, payload, status, next_attempt_at, created_at, delivered_at) \
+                               VALUES ('internal/submission', '{{}}', '{}', 0, 0, {})", status, delivered_at));
+    }
+    ctx.wait_outbox_len(2);
+    assert_eq!(ctx.outbox_status(), vec!["delivered", "dead"]);
+}
@@ -0,0 +1,906 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::LocalResponse;
+use serde_json::{json, Value};
+
+use super::*;
+
+pub const COURSE: &str = "00000000-0000-0000-0000-000000000001";
+pub const ASSIGNMENT: &str = "00000000-0000-0000-0000-000000000002";
+pub const REPO_PATH: &str = "/courses/00000000-0000-0000-0000-000000000001/assignments/00000000-0000-0000-0000-000000000002/repos";
+
+impl TestContext {
+    pub fn post_json(&self, uri: &str, body: Value) -> LocalResponse {
+        self.client.post(uri.to_string()).header(ContentType::JSON).body(body.to_string()).dispatch()
+    }
+
+    pub fn create_user(&self, email: &str) {
+        let response = self.post_json("/users", json!({"email": email, "password": "password"}));
+        assert_eq!(response.status(), Status::Created);
+    }
+
+    pub fn create_course(&self) {
+        let response = self.post_json("/courses", json!({"name": "SI100c", "uuid": COURSE}));
+        assert_eq!(response.status(), Status::Created);
+    }
+
+    pub fn create_assignment(&self) {
+        self.create_course();
+        let response = self.post_json(&format!("/courses/{}/assignments", COURSE), json!({"name": "hw0", "uuid": ASSIGNMENT}));
+        assert_eq!(response.status(), Status::Created);
+    }
+
+    /// Course, assignment, owner and repo `wangdch`. Returns gitlab project id.
+    pub fn create_repo(&self) -> u64 {
+        self.create_repo_with_ddl("2099-10-22")
+    }
+
+    pub fn create_repo_with_ddl(&self, ddl: &str) -> u64 {
+        self.create_assignment();
+        self.create_user("wangdch@shanghaitech.edu.cn");
+        let response = self.post_json(REPO_PATH, json!({
+            "owners": ["wangdch@shanghaitech.edu.cn"],
+            "repo_name": "wangdch",
+            "ddl": ddl,
+        }));
+        assert_eq!(response.status(), Status::Ok);
+        *self.gitlab.state().projects.keys().last().unwrap()
+    }
+}
+
+#[test]
+fn healthcheck() {
+    let ctx = TestContext::new();
+    let mut response = ctx.client.get("/healthcheck").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.body_string().unwrap(), "OK");
+}
+
+#[test]
+fn create_user() {
+    let ctx = TestContext::new();
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    let state = ctx.gitlab.state();
+    let user = state.users.values().next().unwrap();
+    assert_eq!(user["username"], "wangdch");
+    assert_eq!(user["email"], "wangdch@shanghaitech.edu.cn");
+    assert_eq!(ctx.db.query("SELECT username FROM uid"), vec![vec!["wangdch@shanghaitech.edu.cn"]]);
+}
+
+#[test]
+fn create_user_rejects_bad_input() {
+    let ctx = TestContext::new();
+    let response = ctx.post_json("/users", json!({"email": "wangdch@shanghaitech.edu.cn", "password": "short"}));
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = ctx.post_json("/users", json!({"email": "wangdch", "password": "password"}));
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = ctx.post_json("/users", json!({"email": "admin@shanghaitech.edu.cn", "password": "password"}));
+    assert_eq!(response.status(), Status::BadRequest);
+    assert!(ctx.gitlab.calls().is_empty());
+}
+
+#[test]
+fn create_user_twice() {
+    let ctx = TestContext::new();
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    let response = ctx.post_json("/users", json!({"email": "wangdch@shanghaitech.edu.cn", "password": "password"}));
+    assert_eq!(response.status(), Status::Conflict);
+}
+
+#[test]
+fn get_user() {
+    let ctx = TestContext::new();
+    assert_eq!(ctx.client.get("/users/wangdch@shanghaitech.edu.cn").dispatch().status(), Status::NotFound);
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    assert_eq!(ctx.client.get("/users/wangdch@shanghaitech.edu.cn").dispatch().status(), Status::NoContent);
+}
+
+#[test]
+fn update_key() {
+    let ctx = TestContext::new();
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    let uri = "/users/wangdch%40shanghaitech.edu.cn/key";
+    assert_eq!(ctx.post_json(uri, json!({"key": "ssh-rsa AAAA1"})).status(), Status::Ok);
+    ctx.gitlab.clear_calls();
+    assert_eq!(ctx.post_json(uri, json!({"key": "ssh-rsa AAAA2"})).status(), Status::Ok);
+    let user = *ctx.gitlab.state().users.keys().next().unwrap();
+    let old = ctx.gitlab.state().calls[1].url.rsplit('/').next().unwrap().to_string();
+    assert_eq!(ctx.gitlab.calls(), vec![
+        format!("GET users/{}/keys", user),
+        format!("DELETE users/{}/keys/{}", user, old),
+        format!("POST users/{}/keys", user),
+    ]);
+    let keys = ctx.gitlab.state().keys[&user].clone();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0]["key"], "ssh-rsa AAAA2");
+}
+
+#[test]
+fn update_key_unknown_user() {
+    let ctx = TestContext::new();
+    let response = ctx.post_json("/users/nobody%40shanghaitech.edu.cn/key", json!({"key": "ssh-rsa AAAA"}));
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn create_course() {
+    let ctx = TestContext::new();
+    ctx.create_course();
+    assert_eq!(ctx.gitlab.calls(), vec!["POST groups"]);
+    let group = ctx.gitlab.state().groups.values().next().unwrap().clone();
+    assert_eq!(group["path"], "SI100c");
+    assert_eq!(group["visibility"], "private");
+    assert_eq!(ctx.client.get(format!("/courses/{}", COURSE)).dispatch().status(), Status::Ok);
+}
+
+#[test]
+fn get_course_gone_from_gitlab() {
+    let ctx = TestContext::new();
+    ctx.create_course();
+    ctx.gitlab.state().groups.clear();
+    assert_eq!(ctx.client.get(format!("/courses/{}", COURSE)).dispatch().status(), Status::NotFound);
+    assert!(ctx.db.query("SELECT * FROM uuids").is_empty());
+}
+
+#[test]
+fn delete_course() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    let (course, assignment) = {
+        let state = ctx.gitlab.state();
+        let mut ids = state.groups.keys();
+        (*ids.next().unwrap(), *ids.next().unwrap())
+    };
+    ctx.gitlab.clear_calls();
+    let job = ctx.wait_job(ctx.client.delete(format!("/courses/{}", COURSE)).dispatch());
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(ctx.gitlab.calls(), vec![
+        format!("GET groups/{}/subgroups", course),
+        format!("DELETE groups/{}", course),
+    ]);
+    assert!(!ctx.gitlab.state().groups.contains_key(&assignment));
+    assert!(ctx.db.query("SELECT * FROM uuids").is_empty());
+    assert_eq!(ctx.client.get(format!("/courses/{}", COURSE)).dispatch().status(), Status::NotFound);
+}
+
+#[test]
+fn create_assignment() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    let state = ctx.gitlab.state();
+    let mut groups = state.groups.values();
+    let course = groups.next().unwrap();
+    let assignment = groups.next().unwrap();
+    assert_eq!(assignment["parent_id"], course["id"]);
+    assert_eq!(assignment["path"], "hw0");
+}
+
+#[test]
+fn create_assignment_unknown_course() {
+    let ctx = TestContext::new();
+    let response = ctx.post_json(&format!("/courses/{}/assignments", COURSE), json!({"name": "hw0", "uuid": ASSIGNMENT}));
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(ctx.gitlab.calls().is_empty());
+}
+
+#[test]
+fn get_and_delete_assignment() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    let uri = format!("/courses/{}/assignments/{}", COURSE, ASSIGNMENT);
+    assert_eq!(ctx.client.get(uri.clone()).dispatch().status(), Status::Ok);
+    let assignment = *ctx.gitlab.state().groups.keys().last().unwrap();
+    ctx.gitlab.clear_calls();
+    let job = ctx.wait_job(ctx.client.delete(uri.clone()).dispatch());
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(ctx.gitlab.calls(), vec![format!("DELETE groups/{}", assignment)]);
+    assert_eq!(ctx.client.get(uri).dispatch().status(), Status::NotFound);
+}
+
+#[test]
+fn add_instructor_to_course() {
+    let ctx = TestContext::new();
+    ctx.create_course();
+    ctx.create_user("chenhao@shanghaitech.edu.cn");
+    let response = ctx.post_json(&format!("/courses/{}/instructors", COURSE), json!({"instructor_name": "chenhao@shanghaitech.edu.cn"}));
+    assert_eq!(response.status(), Status::Ok);
+    let state = ctx.gitlab.state();
+    let course = state.groups.keys().next().unwrap();
+    let members = &state.group_members[course];
+    assert_eq!(members[0]["username"], "chenhao");
+    // maintainer, only middleware may delete the course
+    assert_eq!(members[0]["access_level"], 40);
+}
+
+#[test]
+fn course_staff() {
+    let ctx = TestContext::new();
+    ctx.create_course();
+    ctx.create_user("chenhao@shanghaitech.edu.cn");
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    let uri = format!("/courses/{}/staff", COURSE);
+    let staff = json!({"email": "chenhao@shanghaitech.edu.cn", "role": "instructor"});
+    assert_eq!(ctx.post_json(&uri, staff.clone()).status(), Status::Created);
+    assert_eq!(ctx.post_json(&uri, staff).status(), Status::Conflict);
+    let ta = json!({"email": "wangdch@shanghaitech.edu.cn", "role": "ta", "access_level": "reporter"});
+    assert_eq!(ctx.post_json(&uri, ta).status(), Status::Created);
+    let mut response = ctx.client.get(uri.clone()).dispatch();
+    let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body, json!([
+        {"email": "chenhao@shanghaitech.edu.cn", "role": "instructor", "access_level": "maintainer"},
+        {"email": "wangdch@shanghaitech.edu.cn", "role": "ta", "access_level": "reporter"},
+    ]));
+    {
+        let state = ctx.gitlab.state();
+        let levels: Vec<&Value> = state.group_members.values().next().unwrap().iter().map(|m| &m["access_level"]).collect();
+        assert_eq!(levels, vec![40, 20]);
+    }
+
+    let member = format!("{}/wangdch@shanghaitech.edu.cn", uri);
+    let response = ctx.client.put(member.clone()).header(ContentType::JSON).body(json!({"role": "ta"}).to_string()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(ctx.gitlab.state().group_members.values().next().unwrap()[1]["access_level"], 30);
+    assert_eq!(ctx.client.delete(member.clone()).dispatch().status(), Status::Ok);
+    assert_eq!(ctx.client.delete(member).dispatch().status(), Status::NotFound);
+    assert_eq!(ctx.gitlab.state().group_members.values().next().unwrap().len(), 1);
+    let mut response = ctx.client.get(uri).dispatch();
+    assert_eq!(response.body_string().unwrap(), r#"[{"email":"chenhao@shanghaitech.edu.cn","role":"instructor","access_level":"maintainer"}]"#);
+}
+
+#[test]
+fn course_staff_rejects_bad_access_level() {
+    let ctx = TestContext::new();
+    ctx.create_course();
+    ctx.create_user("chenhao@shanghaitech.edu.cn");
+    let uri = format!("/courses/{}/staff", COURSE);
+    for staff in &[json!({"email": "chenhao@shanghaitech.edu.cn", "role": "observer", "access_level": "developer"}),
+                   json!({"email": "chenhao@shanghaitech.edu.cn", "role": "ta", "access_level": "owner"})] {
+        assert_eq!(ctx.post_json(&uri, staff.clone()).status(), Status::BadRequest, "{}", staff);
+    }
+    let staff = json!({"email": "chenhao@shanghaitech.edu.cn", "role": "admin"});
+    assert_eq!(ctx.post_json(&uri, staff).status(), Status::UnprocessableEntity);
+    assert!(ctx.gitlab.state().group_members.values().all(Vec::is_empty));
+    assert!(ctx.db.query("SELECT * FROM course_staff").is_empty());
+}
+
+#[test]
+fn course_staff_takes_in_old_instructors() {
+    let ctx = TestContext::new();
+    ctx.create_course();
+    ctx.create_user("chenhao@shanghaitech.edu.cn");
+    {
+        // made owner before roles were kept, next to whoever created the group
+        let mut state = ctx.gitlab.state();
+        let user = state.users.values().next().unwrap().clone();
+        let course = *state.groups.keys().next().unwrap();
+        let members = state.group_members.entry(course).or_default();
+        members.push(json!({"id": 999, "username": "root", "access_level": 50}));
+        // on the second page
+        for id in 1000..1100 {
+            members.push(json!({"id": id, "username": format!("user{}", id), "access_level": 30}));
+        }
+        members.push(json!({"id": user["id"], "username": user["username"], "access_level": 50}));
+    }
+    let uri = format!("/courses/{}/staff", COURSE);
+    let mut response = ctx.client.get(uri.clone()).dispatch();
+    assert_eq!(response.body_string().unwrap(), r#"[{"email":"chenhao@shanghaitech.edu.cn","role":"instructor","access_level":"owner"}]"#);
+    let member = format!("{}/chenhao@shanghaitech.edu.cn", uri);
+    let response = ctx.client.put(member).header(ContentType::JSON).body(json!({"role": "instructor"}).to_string()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(ctx.gitlab.state().group_members.values().next().unwrap().last().unwrap()["access_level"], 40);
+    assert_eq!(ctx.db.query("SELECT role, access_level FROM course_staff"), vec![vec!["instructor", "maintainer"]]);
+}
+
+#[test]
+fn create_repo() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    ctx.create_user("chenhao@shanghaitech.edu.cn");
+    ctx.gitlab.clear_calls();
+    let mut response = ctx.post_json(REPO_PATH, json!({
+        "owners": ["wangdch@shanghaitech.edu.cn", "chenhao@shanghaitech.edu.cn"],
+        "repo_name": "wangdch",
+        "ddl": "2099-10-22",
+        "additional_data": "lol what",
+    }));
+    assert_eq!(response.status(), Status::Ok);
+    let state = ctx.gitlab.state();
+    let (&id, project) = state.projects.iter().next().unwrap();
+    let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body["ssh_url_to_repo"], project["ssh_url_to_repo"]);
+    drop(state);
+    assert_eq!(ctx.gitlab.calls(), vec![
+        "POST projects/".to_string(),
+        format!("POST projects/{}/hooks", id),
+        format!("POST projects/{}/protected_branches?name=*", id),
+        format!("POST projects/{}/members", id),
+        format!("POST projects/{}/members", id),
+    ]);
+    let state = ctx.gitlab.state();
+    let hook = &state.hooks[&id][0];
+    assert_eq!(hook["url"], format!("{}/hooks/{}/{}?data=lol%20what", MIDDLEWARE_BASE, COURSE, ASSIGNMENT));
+    assert_eq!(hook["push_events"], true);
+    assert_eq!(hook["tag_push_events"], true);
+    assert_eq!(hook["merge_requests_events"], true);
+    assert_eq!(hook["note_events"], true);
+    assert_eq!(hook["pipeline_events"], true);
+    let members = &state.project_members[&id];
+    assert_eq!(members.len(), 2);
+    assert_eq!(members[0]["access_level"], 40);
+    assert_eq!(members[0]["expires_at"], "2099-10-23");
+    assert_eq!(ctx.db.query("SELECT repo_id, name FROM repo_ids"), vec![vec![id.to_string(), "wangdch".to_string()]]);
+}
+
+#[test]
+fn create_repo_twice() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    ctx.gitlab.clear_calls();
+    let response = ctx.post_json(REPO_PATH, json!({
+        "owners": ["wangdch@shanghaitech.edu.cn"],
+        "repo_name": "wangdch",
+        "ddl": "2099-10-22",
+    }));
+    assert_eq!(response.status(), Status::Conflict);
+    assert!(ctx.gitlab.calls().is_empty());
+}
+
+#[test]
+fn create_repo_unknown_owner() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    ctx.gitlab.clear_calls();
+    let response = ctx.post_json(REPO_PATH, json!({
+        "owners": ["nobody@shanghaitech.edu.cn"],
+        "repo_name": "nobody",
+        "ddl": "2099-10-22",
+    }));
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(ctx.gitlab.calls().is_empty());
+}
+
+#[test]
+fn create_repo_rolls_back() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    ctx.create_user("wangdch@shanghaitech.edu.cn");
+    ctx.create_user("zhangsan@shanghaitech.edu.cn");
+    // removed from gitlab behind our back, adding the user as member will 404
+    let (id, zhangsan) = {
+        let state = ctx.gitlab.state();
+        let (id, user) = state.users.iter().find(|(_, u)| u["username"] == "zhangsan").unwrap();
+        (*id, user.clone())
+    };
+    ctx.gitlab.state().users.remove(&id);
+    let body = json!({
+        "owners": ["wangdch@shanghaitech.edu.cn", "zhangsan@shanghaitech.edu.cn"],
+        "repo_name": "wangdch",
+        "ddl": "2099-10-22",
+    });
+    let response = ctx.post_json(REPO_PATH, body.clone());
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(ctx.gitlab.state().projects.is_empty());
+    assert!(ctx.db.query("SELECT * FROM repo_ids").is_empty());
+    assert!(ctx.gitlab.calls().last().unwrap().starts_with("DELETE projects/"));
+
+    // nothing left behind, so the retry goes through
+    ctx.gitlab.state().users.insert(id, zhangsan);
+    let response = ctx.post_json(REPO_PATH, body);
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(ctx.gitlab.state().projects.len(), 1);
+    assert_eq!(ctx.db.query("SELECT name FROM repo_ids"), vec![vec!["wangdch"]]);
+}
+
+#[test]
+fn get_and_delete_repo() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    let uri = format!("{}/wangdch", REPO_PATH);
+    assert_eq!(ctx.client.get(uri.clone()).dispatch().status(), Status::Ok);
+    ctx.gitlab.clear_calls();
+    assert_eq!(ctx.client.delete(uri.clone()).dispatch().status(), Status::Ok);
+    assert_eq!(ctx.gitlab.calls(), vec![format!("DELETE projects/{}", id)]);
+    assert!(ctx.db.query("SELECT * FROM repo_ids").is_empty());
+    assert_eq!(ctx.client.get(uri).dispatch().status(), Status::NotFound);
+}
+
+#[test]
+fn get_repo_gone_from_gitlab() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    ctx.gitlab.state().projects.remove(&id);
+    assert_eq!(ctx.client.get(format!("{}/wangdch", REPO_PATH)).dispatch().status(), Status::NotFound);
+    assert!(ctx.db.query("SELECT * FROM repo_ids").is_empty());
+}
+
+#[test]
+fn repo_members() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    ctx.create_user("yuanzh@shanghaitech.edu.cn");
+    let uri = format!("{}/wangdch/members", REPO_PATH);
+    let member = format!("{}/yuanzh@shanghaitech.edu.cn", uri);
+    assert_eq!(ctx.client.post(member.clone()).dispatch().status(), Status::Ok);
+    assert_eq!(ctx.client.post(member.clone()).dispatch().status(), Status::Conflict);
+    assert_eq!(ctx.gitlab.state().project_members[&id][1]["expires_at"], "2099-10-23");
+    let mut response = ctx.client.get(uri.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let members: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(members, json!(["wangdch@shanghaitech.edu.cn", "yuanzh@shanghaitech.edu.cn"]));
+
+    assert_eq!(ctx.client.delete(format!("{}/wangdch@shanghaitech.edu.cn", uri)).dispatch().status(), Status::Ok);
+    assert_eq!(ctx.client.delete(format!("{}/wangdch@shanghaitech.edu.cn", uri)).dispatch().status(), Status::NotFound);
+    assert_eq!(ctx.client.post(format!("{}/nobody@shanghaitech.edu.cn", uri)).dispatch().status(), Status::NotFound);
+    let mut response = ctx.client.get(uri).dispatch();
+    assert_eq!(response.body_string().unwrap(), r#"["yuanzh@shanghaitech.edu.cn"]"#);
+}
+
+#[test]
+fn member_added_after_deadline() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo_with_ddl("2012-10-22");
+    ctx.create_user("yuanzh@shanghaitech.edu.cn");
+    let uri = format!("{}/wangdch/members/yuanzh@shanghaitech.edu.cn", REPO_PATH);
+    assert_eq!(ctx.client.post(uri).dispatch().status(), Status::Ok);
+    let state = ctx.gitlab.state();
+    let member = state.project_members[&id].iter().find(|m| m["username"] != "wangdch").unwrap();
+    assert_eq!(member["access_level"], 20);
+    assert!(member["expires_at"].is_null());
+}
+
+#[test]
+fn download_repo() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    let uri = format!("{}/wangdch/download?format=zip", REPO_PATH);
+    assert_eq!(ctx.client.get(uri.clone()).dispatch().status(), Status::NotFound);
+    ctx.gitlab.push_commit(id, "0123456789abcdef0123456789abcdef01234567", "init");
+    ctx.gitlab.clear_calls();
+    let mut response = ctx.client.get(uri).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(ctx.gitlab.calls(), vec![format!("GET projects/{}/repository/archive.zip", id)]);
+    assert!(response.headers().get_one("Content-Disposition").unwrap().ends_with(".zip\""));
+    assert_eq!(response.content_type(), Some(ContentType::new("application", "zip")));
+    assert_eq!(response.body_string().unwrap(), "archive of 0123456789abcdef0123456789abcdef01234567");
+}
+
+#[test]
+fn download_format() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    ctx.gitlab.push_commit(id, "0123456789abcdef0123456789abcdef01234567", "init");
+    let download = |format: &str| ctx.client.get(format!("{}/wangdch/download?format={}", REPO_PATH, format)).dispatch();
+    assert_eq!(download("tbz2").content_type(), Some(ContentType::new("application", "x-bzip2")));
+    assert_eq!(download("tar").content_type(), Some(ContentType::new("application", "x-tar")));
+    assert_eq!(ctx.client.get(format!("{}/wangdch/download", REPO_PATH)).dispatch().content_type(),
+               Some(ContentType::new("application", "gzip")));
+    ctx.gitlab.clear_calls();
+    for format in &["rar", "zip%2F..%2F..%2Fusers", "zip%3Fsha%3Dmaster"] {
+        assert_eq!(download(format).status(), Status::BadRequest, "{}", format);
+    }
+    assert!(ctx.gitlab.calls().is_empty());
+}
+
+#[test]
+fn download_repo_at() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    let first = "1111111111111111111111111111111111111111";
+    let second = "2222222222222222222222222222222222222222";
+    ctx.gitlab.push_commit_at(id, first, "init", "2012-10-22T12:00:00Z");
+    ctx.gitlab.push_commit_at(id, second, "late", "2012-10-23T12:00:00Z");
+    let download = |query: &str| {
+        ctx.gitlab.clear_calls();
+        let mut response = ctx.client.get(format!("{}/wangdch/download?format=zip&{}", REPO_PATH, query)).dispatch();
+        (response.status(), response.body_string())
+    };
+
+    let (status, body) = download("before=2012-10-22");
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body.unwrap(), format!("archive of {}", first));
+    assert_eq!(ctx.gitlab.calls()[1], format!("GET projects/{}/repository/archive.zip?sha={}", id, first));
+    assert_eq!(download(&format!("sha={}", second)).1.unwrap(), format!("archive of {}", second));
+    assert_eq!(download("ref=master").0, Status::Ok);
+    assert_eq!(ctx.gitlab.calls(), vec![format!("GET projects/{}/repository/archive.zip?sha=master", id)]);
+    // can't smuggle in another parameter
+    download("ref=x%26sha%3Dmaster");
+    assert_eq!(ctx.gitlab.calls(), vec![format!("GET projects/{}/repository/archive.zip?sha=x%26sha%3Dmaster", id)]);
+
+    assert_eq!(download("before=2012-10-21T00:00:00Z").0, Status::NotFound);
+    assert_eq!(download(&format!("sha={}&before=2012-10-22", first)).0, Status::BadRequest);
+    assert_eq!(download("before=yesterday").0, Status::BadRequest);
+}
+
+impl TestContext {
+    /// As `create_repo`, with a few files committed.
+    fn create_repo_with_files(&self) -> u64 {
+        let id = self.create_repo();
+        let files = [("notes.txt", "hello\n"), (".gitignore", "*.o\n"), ("src/main.c", "int main() {}\n"), ("src/lib/util.h", "")];
+        self.gitlab.state().files.insert(id, files.iter().map(|(path, content)| (path.to_string(), content.as_bytes().to_vec())).collect());
+        self.gitlab.push_commit(id, "0123456789abcdef0123456789abcdef01234567", "Initial commit");
+        id
+    }
+
+    fn get_json(&self, uri: String) -> (Status, Value) {
+        let mut response = self.client.get(uri).dispatch();
+        let body = response.body_string().map(|body| serde_json::from_str(&body).unwrap_or(Value::Null)).unwrap_or(Value::Null);
+        (response.status(), body)
+    }
+}
+
+#[test]
+fn repo_tree() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo_with_files();
+    assert_eq!(ctx.get_json(format!("{}/wangdch/tree", REPO_PATH)), (Status::Ok, json!([
+        {"name": ".gitignore", "path": ".gitignore", "type": "file"},
+        {"name": "notes.txt", "path": "notes.txt", "type": "file"},
+        {"name": "src", "path": "src", "type": "dir"},
+    ])));
+    assert_eq!(ctx.get_json(format!("{}/wangdch/tree?path=src%2F", REPO_PATH)), (Status::Ok, json!([
+        {"name": "lib", "path": "src/lib", "type": "dir"},
+        {"name": "main.c", "path": "src/main.c", "type": "file"},
+    ])));
+    ctx.gitlab.create_branch(id, "fix", &[("src/main.c", None)]);
+    ctx.gitlab.clear_calls();
+    assert_eq!(ctx.get_json(format!("{}/wangdch/tree?ref=fix&path=src", REPO_PATH)).1, json!([{"name": "lib", "path": "src/lib", "type": "dir"}]));
+    assert_eq!(ctx.gitlab.calls(), vec![format!("GET projects/{}/repository/tree?per_page=100&ref=fix&path=src", id)]);
+    assert_eq!(ctx.get_json(format!("{}/wangdch/tree?path=nothing", REPO_PATH)).0, Status::NotFound);
+}
+
+#[test]
+fn tree_of_empty_repo() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    assert_eq!(ctx.get_json(format!("{}/wangdch/tree", REPO_PATH)), (Status::Ok, json!([])));
+    assert_eq!(ctx.get_json(format!("{}/wangdch/tree?path=src", REPO_PATH)).0, Status::NotFound);
+}
+
+#[test]
+fn repo_file() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo_with_files();
+    let mut response = ctx.client.get(format!("{}/wangdch/files/notes.txt", REPO_PATH)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::Plain));
+    assert!(response.body().unwrap().is_sized());
+    assert_eq!(response.body_string().unwrap(), "hello\n");
+
+    let mut response = ctx.client.get(format!("{}/wangdch/files/src/main.c", REPO_PATH)).dispatch();
+    assert_eq!(response.content_type(), Some(ContentType::Binary));
+    assert_eq!(response.body_string().unwrap(), "int main() {}\n");
+    let mut response = ctx.client.get(format!("{}/wangdch/files/.gitignore", REPO_PATH)).dispatch();
+    assert_eq!(response.body_string().unwrap(), "*.o\n");
+
+    ctx.gitlab.create_branch(id, "fix", &[("src/main.c", Some("int main() { return 1; }\n"))]);
+    let mut response = ctx.client.get(format!("{}/wangdch/files/src/main.c?ref=fix", REPO_PATH)).dispatch();
+    assert_eq!(response.body_string().unwrap(), "int main() { return 1; }\n");
+    let response = ctx.client.get(format!("{}/wangdch/files/src/nothing.c", REPO_PATH)).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    ctx.gitlab.clear_calls();
+    for path in &["files/%2e%2e/%2e%2e/users", "files/src%2F..%2F..%2Fusers", "files/./notes.txt", "tree?path=src%2F.."] {
+        let response = ctx.client.get(format!("{}/wangdch/{}", REPO_PATH, path)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", path);
+    }
+    assert!(ctx.gitlab.calls().is_empty());
+}
+
+#[test]
+fn compare() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo_with_files();
+    ctx.gitlab.create_branch(id, "fix", &[("notes.txt", Some("hello\nworld\n")), ("src/lib/util.h", None), ("Makefile", Some("all:\n"))]);
+    ctx.gitlab.clear_calls();
+    let uri = format!("{}/wangdch/compare?from=master&to=fix", REPO_PATH);
+    assert_eq!(ctx.get_json(uri.clone()), (Status::Ok, json!([
+        {"old_path": "Makefile", "new_path": "Makefile", "status": "added", "old_mode": null, "new_mode": "100644", "diff": "@@ -0,0 +1,1 @@\n+all:\n"},
+        {"old_path": "notes.txt", "new_path": "notes.txt", "status": "modified", "old_mode": "100644", "new_mode": "100644",
+         "diff": "@@ -1,1 +1,2 @@\n-hello\n+hello\n+world\n"},
+        {"old_path": "src/lib/util.h", "new_path": "src/lib/util.h", "status": "deleted", "old_mode": "100644", "new_mode": null, "diff": "@@ -0,0 +0,0 @@\n"},
+    ])));
+    assert_eq!(ctx.gitlab.calls(), vec![format!("GET projects/{}/repository/compare?from=master&to=fix&straight=true", id)]);
+
+    let mut response = ctx.client.get(format!("{}&format=patch", uri)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::new("text", "x-patch")));
+    assert_eq!(response.body_string().unwrap(), "\
+diff --git a/Makefile b/Makefile
+new file mode 100644
+--- /dev/null
++++ b/Makefile
+@@ -0,0 +1,1 @@
++all:
+diff --git a/notes.txt b/notes.txt
+--- a/notes.txt
++++ b/notes.txt
+@@ -1,1 +1,2 @@
+-hello
++hello
++world
+diff --git a/src/lib/util.h b/src/lib/util.h
+deleted file mode 100644
+--- a/src/lib/util.h
++++ /dev/null
+@@ -0,0 +0,0 @@
+");
+
+    for query in &["from=master", "to=fix", "from=master&to=fix&format=html"] {
+        let response = ctx.client.get(format!("{}/wangdch/compare?{}", REPO_PATH, query)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", query);
+    }
+    assert_eq!(ctx.get_json(format!("{}/wangdch/compare?from=master&to=nothing", REPO_PATH)).0, Status::NotFound);
+    ctx.gitlab.clear_calls();
+    assert_eq!(ctx.get_json(format!("{}/wangdch/compare?from=master&to=fix%26straight%3Dfalse", REPO_PATH)).0, Status::NotFound);
+    assert_eq!(ctx.gitlab.calls(), vec![format!("GET projects/{}/repository/compare?from=master&to=fix%26straight%3Dfalse&straight=true", id)]);
+    assert_eq!(ctx.get_json(format!("{}/nobody/compare?from=master&to=fix", REPO_PATH)).0, Status::NotFound);
+}
+
+#[test]
+fn commits() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    for i in 0..150 {
+        ctx.gitlab.push_commit(id, &format!("{:040}", i), &format!("commit {}", i));
+    }
+    let mut response = ctx.client.get(format!("{}/wangdch/commits", REPO_PATH)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let link = response.headers().get_one("Link").unwrap().to_string();
+    let page: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(page.as_array().unwrap().len(), 100);
+    assert_eq!(page[0]["sha"], format!("{:040}", 149));
+    assert!(link.starts_with("<commits?page=") && link.ends_with(r#">; rel="next""#));
+    let next = &link[1..link.find('>').unwrap()];
+    let mut response = ctx.client.get(format!("{}/wangdch/{}", REPO_PATH, next)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.headers().get_one("Link").is_none());
+    let page: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(page.as_array().unwrap().len(), 50);
+}
+
+#[test]
+fn commits_normalized() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    let first = format!("{:040}", 1);
+    let second = format!("{:040}", 2);
+    ctx.gitlab.push_commit_as(id, &first, "Initial commit", "wangdch@shanghaitech.edu.cn", "2012-10-22T14:13:35.000+08:00");
+    ctx.gitlab.push_commit_as(id, &second, "Fix typo", "someone@example.com", "2012-10-23T00:00:00Z");
+    let mut response = ctx.client.get(format!("{}/wangdch/commits", REPO_PATH)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let commits: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(commits, json!([
+        {
+            "sha": second,
+            "message": "Fix typo",
+            "author_name": "someone",
+            "author_email": "someone@example.com",
+            "author": null,
+            "authored_at": "2012-10-23T00:00:00Z",
+            "committed_at": "2012-10-23T00:00:00Z",
+            "parents": [first],
+        },
+        {
+            "sha": first,
+            "message": "Initial commit",
+            "author_name": "wangdch",
+            "author_email": "wangdch@shanghaitech.edu.cn",
+            "author": "wangdch@shanghaitech.edu.cn",
+            "authored_at": "2012-10-22T06:13:35Z",
+            "committed_at": "2012-10-22T06:13:35Z",
+            "parents": [],
+        },
+    ]));
+}
+
+#[test]
+fn commits_filtered() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    for day in 20..25 {
+        ctx.gitlab.push_commit_at(id, &format!("{:040}", day), &format!("day {}", day), &format!("2012-10-{}T12:00:00Z", day));
+    }
+    ctx.gitlab.clear_calls();
+    let uri = format!("{}/wangdch/commits?ref=master&path=src%2Fmain.c&since=2012-10-21T00:00:00Z&until=2012-10-23T23:00:00Z&per_page=2", REPO_PATH);
+    let mut response = ctx.client.get(uri).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(ctx.gitlab.calls(), vec![format!("GET projects/{}/repository/commits?per_page=2&page=1&ref_name=master&path=src%2Fmain.c\
+                                                 &since=2012-10-21T00%3A00%3A00Z&until=2012-10-23T23%3A00%3A00Z", id)]);
+    let commits: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let shas: Vec<&Value> = commits.as_array().unwrap().iter().map(|c| &c["sha"]).collect();
+    assert_eq!(shas, vec![&json!(format!("{:040}", 23)), &json!(format!("{:040}", 22))]);
+    // filters are kept on the next page
+    let link = response.headers().get_one("Link").unwrap().to_string();
+    assert!(link.starts_with("<commits?ref=master&path=src%2Fmain.c&since=2012-10-21T00%3A00%3A00Z&until=2012-10-23T23%3A00%3A00Z&per_page=2&page="), "{}", link);
+    let mut response = ctx.client.get(format!("{}/wangdch/{}", REPO_PATH, &link[1..link.find('>').unwrap()])).dispatch();
+    let commits: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(commits.as_array().unwrap().len(), 1);
+    assert_eq!(commits[0]["sha"], format!("{:040}", 21));
+    assert!(response.headers().get_one("Link").is_none());
+
+    for query in &["per_page=0", "per_page=101", "per_page=lots", "since=yesterday"] {
+        let response = ctx.client.get(format!("{}/wangdch/commits?{}", REPO_PATH, query)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", query);
+    }
+    assert_eq!(ctx.client.get(format!("{}/wangdch/commits?ref=nothing", REPO_PATH)).dispatch().status(), Status::NotFound);
+}
+
+#[test]
+fn commits_schema_changed() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    ctx.gitlab.push_commit(id, "0123456789abcdef0123456789abcdef01234567", "Initial commit");
+    ctx.gitlab.state().commits.get_mut(&id).unwrap()[0]["committed_date"] = json!("yesterday");
+    let response = ctx.client.get(format!("{}/wangdch/commits", REPO_PATH)).dispatch();
+    assert_eq!(response.status(), Status::BadGateway);
+}
+
+#[test]
+fn commits_of_empty_repo() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let mut response = ctx.client.get(format!("{}/wangdch/commits", REPO_PATH)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.body_string().unwrap(), "[]");
+    assert!(response.headers().get_one("Link").is_none());
+}
+
+#[test]
+fn commits_cursor_rejected() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    for i in 0..150 {
+        ctx.gitlab.push_commit(id, &format!("{:040}", i), &format!("commit {}", i));
+    }
+    ctx.create_user("a@shanghaitech.edu.cn");
+    let response = ctx.post_json(REPO_PATH, json!({"owners": ["a@shanghaitech.edu.cn"], "repo_name": "a", "ddl": "2099-10-22"}));
+    assert_eq!(response.status(), Status::Ok);
+    let response = ctx.client.get(format!("{}/wangdch/commits", REPO_PATH)).dispatch();
+    let link = response.headers().get_one("Link").unwrap().to_string();
+    let cursor = &link["<commits?page=".len()..link.find('>').unwrap()];
+    // not for another repo
+    assert_eq!(ctx.client.get(format!("{}/a/commits?page={}", REPO_PATH, cursor)).dispatch().status(), Status::BadRequest);
+    // nor tampered with
+    let mut tampered = cursor.to_string().into_bytes();
+    tampered[0] = if tampered[0] == b'A' { b'B' } else { b'A' };
+    let tampered = String::from_utf8(tampered).unwrap();
+    for cursor in &[tampered.as_str(), "2", ""] {
+        let response = ctx.client.get(format!("{}/wangdch/commits?page={}", REPO_PATH, cursor)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", cursor);
+    }
+}
+
+/// The hook gitlab would call for the only repo, as (uri, token).
+pub fn hook(ctx: &TestContext) -> (String, String) {
+    let state = ctx.gitlab.state();
+    let hook = &state.hooks.values().next().unwrap()[0];
+    let uri = hook["url"].as_str().unwrap().trim_start_matches(MIDDLEWARE_BASE).to_string();
+    (uri, hook["token"].as_str().unwrap().to_string())
+}
+
+pub fn push_payload() -> Value {
+    json!({
+        "object_kind": "push",
+        "ref": "refs/heads/master",
+        "after": "0123456789abcdef0123456789abcdef01234567",
+        "user_id": 3,
+        "user_username": "wangdch",
+        "project": {"id": 4, "git_ssh_url": "git@gitlab.test:3/wangdch.git"},
+    })
+}
+
+#[test]
+fn webhook() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let (uri, token) = hook(&ctx);
+    let response = ctx.client.post(uri).header(ContentType::JSON)
+        .header(Header::new("X-Gitlab-Event", "Push Hook"))
+        .header(Header::new("X-Gitlab-Token", token))
+        .body(push_payload().to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let calls = ctx.backend.wait_calls(1);
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].url, "/internal/submission");
+    assert_eq!(calls[0].header("Authorization"), Some("backend-secret"));
+    let mut body = calls[0].body.clone();
+    let pushed_at = body.as_object_mut().unwrap().remove("pushed_at").unwrap();
+    assert!(::time::strptime(pushed_at.as_str().unwrap(), "%Y-%m-%dT%H:%M:%SZ").is_ok());
+    assert_eq!(body, json!({
+        "assignment_uid": ASSIGNMENT,
+        "upstream": "git@gitlab.test:3/wangdch.git",
+        "repo_name": "wangdch",
+        "ref": "refs/heads/master",
+        "branch": "master",
+        "after": "0123456789abcdef0123456789abcdef01234567",
+        "pusher_email": "wangdch@shanghaitech.edu.cn",
+        "late": false,
+    }));
+}
+
+#[test]
+fn webhook_unknown_pusher() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let (uri, token) = hook(&ctx);
+    let mut payload = push_payload();
+    payload["user_id"] = json!(1);
+    payload["ref"] = json!("refs/notes/commits");
+    let response = ctx.client.post(uri).header(ContentType::JSON)
+        .header(Header::new("X-Gitlab-Event", "Push Hook"))
+        .header(Header::new("X-Gitlab-Token", token))
+        .body(payload.to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body = &ctx.backend.wait_calls(1)[0].body;
+    assert_eq!(body["ref"], "refs/notes/commits");
+    assert!(body.get("branch").is_none());
+    assert!(body.get("pusher_email").is_none());
+    assert_eq!(body["repo_name"], "wangdch");
+}
+
+#[test]
+fn webhook_push_without_repo() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let (uri, token) = hook(&ctx);
+    let mut payload = push_payload();
+    payload.as_object_mut().unwrap().remove("project");
+    let mut response = ctx.client.post(uri).header(ContentType::JSON)
+        .header(Header::new("X-Gitlab-Event", "Push Hook"))
+        .header(Header::new("X-Gitlab-Token", token))
+        .body(payload.to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    assert!(response.body_string().unwrap().contains("Push without a repo"));
+    assert!(ctx.backend.calls().is_empty());
+}
+
+#[test]
+fn webhook_rejects_bad_token() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let (uri, _) = hook(&ctx);
+    let response = ctx.client.post(uri).header(ContentType::JSON)
+        .header(Header::new("X-Gitlab-Event", "Push Hook"))
+        .header(Header::new("X-Gitlab-Token", "forged"))
+        .body(push_payload().to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(ctx.backend.calls().is_empty());
+    assert!(ctx.db.query("SELECT * FROM webhook_outbox").is_empty());
+}
+
+#[test]
+fn webhook_rejects_other_events() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let (uri, token) = hook(&ctx);
+    let response = ctx.client.post(uri).header(ContentType::JSON)
+        .header(Header::new("X-Gitlab-Event", "Issue Hook"))
+        .header(Header::new("X-Gitlab-Token", token))
+        .body(push_payload().to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    assert!(ctx.backend.calls().is_empty());
+    assert!(ctx.db.query("SELECT * FROM webhook_outbox").is_empty());
+}
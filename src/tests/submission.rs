@@ -0,0 +1,129 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use rocket::http::{ContentType, Header, Status};
+use serde_json::{json, Value};
+
+use super::*;
+use super::routes::{hook, push_payload, REPO_PATH};
+
+const SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+/// An annotated tag, pointing at the commit
+const TAG_SHA: &str = "fedcba9876543210fedcba9876543210fedcba98";
+
+fn tag_payload(tag: &str, sha: &str) -> Value {
+    let mut payload = push_payload();
+    payload["object_kind"] = json!("tag_push");
+    payload["ref"] = json!(format!("refs/tags/{}", tag));
+    if sha.bytes().all(|b| b == b'0') {
+        payload["after"] = json!(sha);
+        payload["checkout_sha"] = Value::Null;
+    } else {
+        payload["after"] = json!(TAG_SHA);
+        payload["checkout_sha"] = json!(sha);
+    }
+    payload
+}
+
+impl TestContext {
+    fn push_tag_hook(&self, tag: &str, sha: &str, token: Option<&str>) -> Status {
+        let (uri, valid) = hook(self);
+        self.client.post(uri).header(ContentType::JSON)
+            .header(Header::new("X-Gitlab-Event", "Tag Push Hook"))
+            .header(Header::new("X-Gitlab-Token", token.unwrap_or(&valid).to_string()))
+            .body(tag_payload(tag, sha).to_string())
+            .dispatch()
+            .status()
+    }
+}
+
+#[test]
+fn submission_tag_forwarded() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    assert_eq!(ctx.push_tag_hook("submit-1", SHA, None), Status::Ok);
+    let calls = ctx.backend.wait_calls(1);
+    assert_eq!(calls[0].url, "/internal/submission");
+    let body = &calls[0].body;
+    assert_eq!(body["tag"], "submit-1");
+    assert_eq!(body["ref"], "refs/tags/submit-1");
+    assert_eq!(body["after"], SHA);
+    assert_eq!(body["repo_name"], "wangdch");
+    assert_eq!(body["pusher_email"], "wangdch@shanghaitech.edu.cn");
+    assert_eq!(body["late"], false);
+    assert!(body.get("branch").is_none());
+}
+
+#[test]
+fn tag_as_push_ignored() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    let (uri, token) = hook(&ctx);
+    let mut payload = push_payload();
+    payload["ref"] = json!("refs/tags/submit-1");
+    let response = ctx.client.post(uri).header(ContentType::JSON)
+        .header(Header::new("X-Gitlab-Event", "Push Hook"))
+        .header(Header::new("X-Gitlab-Token", token))
+        .body(payload.to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(ctx.db.query("SELECT * FROM webhook_outbox").is_empty());
+}
+
+#[test]
+fn other_tags_ignored() {
+    let ctx = TestContext::new();
+    ctx.create_repo();
+    assert_eq!(ctx.push_tag_hook("v1", SHA, None), Status::Ok);
+    // deleted
+    assert_eq!(ctx.push_tag_hook("submit-1", "0000000000000000000000000000000000000000", None), Status::Ok);
+    assert!(ctx.db.query("SELECT * FROM webhook_outbox").is_empty());
+    assert_eq!(ctx.push_tag_hook("submit-1", SHA, Some("forged")), Status::Forbidden);
+    assert!(ctx.db.query("SELECT * FROM webhook_outbox").is_empty());
+}
+
+#[test]
+fn submission_tag_pattern() {
+    let ctx = TestContext::with_config(|c| c.extra("submission_tag_pattern", "final-?"));
+    ctx.create_repo();
+    for tag in &["submit-1", "final-10", "final-"] {
+        assert_eq!(ctx.push_tag_hook(tag, SHA, None), Status::Ok);
+    }
+    assert!(ctx.db.query("SELECT * FROM webhook_outbox").is_empty());
+    assert_eq!(ctx.push_tag_hook("final-1", SHA, None), Status::Ok);
+    assert_eq!(ctx.backend.wait_calls(1)[0].body["tag"], "final-1");
+}
+
+#[test]
+fn list_submissions() {
+    let ctx = TestContext::new();
+    let id = ctx.create_repo();
+    let first = format!("{:040}", 1);
+    let second = format!("{:040}", 2);
+    ctx.gitlab.push_commit_at(id, &first, "First try", "2012-10-21T10:00:00Z");
+    ctx.gitlab.push_tag(id, "submit-1", &first, "");
+    ctx.gitlab.push_commit_at(id, &second, "Second try", "2012-10-22T10:00:00+08:00");
+    ctx.gitlab.push_tag(id, "v1", &second, "");
+    ctx.gitlab.push_tag(id, "submit-2", &second, "Final answer");
+    let mut response = ctx.client.get(format!("{}/wangdch/submissions", REPO_PATH)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body, json!([
+        {"name": "submit-2", "sha": second, "message": "Final answer", "committed_at": "2012-10-22T02:00:00Z"},
+        {"name": "submit-1", "sha": first, "message": null, "committed_at": "2012-10-21T10:00:00Z"},
+    ]));
+    assert_eq!(ctx.client.get(format!("{}/nobody/submissions", REPO_PATH)).dispatch().status(), Status::NotFound);
+}
@@ -0,0 +1,188 @@
+/*
+ *  Copyright (c) 2018-2019, llk89.
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Affero General Public License as
+ *  published by the Free Software Foundation, either version 3 of the
+ *  License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Affero General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Affero General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use rocket::http::{ContentType, Status};
+use serde_json::{json, Value};
+
+use super::*;
+use super::routes::{ASSIGNMENT, COURSE, REPO_PATH};
+
+const STARTER: &[(&str, &str)] = &[("README.md", "# hw0\n"), ("src/main.c", "int main() {}\n")];
+
+impl TestContext {
+    fn template_uri(&self) -> String {
+        format!("/courses/{}/assignments/{}/template", COURSE, ASSIGNMENT)
+    }
+
+    /// Template `templates/hw0` registered for the assignment. Returns its gitlab project id.
+    fn register_template(&self, strip_history: bool) -> u64 {
+        self.create_assignment();
+        let template = self.gitlab.create_project("templates/hw0", STARTER);
+        let response = self.client.put(self.template_uri()).header(ContentType::JSON)
+            .body(json!({"path": "templates/hw0", "strip_history": strip_history}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        template
+    }
+
+    /// As `create_repo`, in the assignment already there.
+    fn create_student_repo(&self) -> u64 {
+        self.create_user("wangdch@shanghaitech.edu.cn");
+        let response = self.post_json(REPO_PATH, json!({"owners": ["wangdch@shanghaitech.edu.cn"], "repo_name": "wangdch", "ddl": "2099-10-22"}));
+        assert_eq!(response.status(), Status::Ok);
+        *self.gitlab.state().projects.keys().last().unwrap()
+    }
+
+    fn starter_files(&self, project: u64) -> Vec<(String, String)> {
+        self.gitlab.state().files[&project].iter()
+            .map(|(path, content)| (path.clone(), String::from_utf8(content.clone()).unwrap()))
+            .collect()
+    }
+}
+
+fn expected_files() -> Vec<(String, String)> {
+    STARTER.iter().map(|(path, content)| (path.to_string(), content.to_string())).collect()
+}
+
+#[test]
+fn repo_forked_from_template() {
+    let ctx = TestContext::new();
+    let template = ctx.register_template(false);
+    let mut response = ctx.client.get(ctx.template_uri()).dispatch();
+    let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body, json!({"path": "templates/hw0", "strip_history": false}));
+
+    let project = ctx.create_student_repo();
+    let calls = ctx.gitlab.calls();
+    let forked = calls.iter().position(|c| *c == format!("POST projects/{}/fork", template)).unwrap();
+    let hooked = calls.iter().position(|c| *c == format!("POST projects/{}/hooks", project)).unwrap();
+    assert!(calls[forked..hooked].iter().filter(|c| **c == format!("GET projects/{}", project)).count() >= 2);
+    let state = ctx.gitlab.state();
+    assert!(state.projects[&project].get("forked_from_project").is_none());
+    assert_eq!(state.projects[&project]["visibility"], "private");
+    assert_eq!(state.commits[&project], state.commits[&template]);
+    drop(state);
+    assert_eq!(ctx.starter_files(project), expected_files());
+    assert_eq!(ctx.gitlab.state().project_members[&project].len(), 1);
+}
+
+#[test]
+fn repo_from_template_without_history() {
+    let ctx = TestContext::new();
+    let template = ctx.register_template(true);
+    let project = ctx.create_student_repo();
+    let state = ctx.gitlab.state();
+    assert!(state.projects[&project].get("forked_from_project").is_none());
+    assert_eq!(state.commits[&project].len(), 1);
+    assert_eq!(state.commits[&project][0]["message"], "Initial commit");
+    assert_ne!(state.commits[&project], state.commits[&template]);
+    drop(state);
+    assert_eq!(ctx.starter_files(project), expected_files());
+}
+
+#[test]
+fn template_unknown_or_removed() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    let response = ctx.client.put(ctx.template_uri()).header(ContentType::JSON)
+        .body(json!({"path": "templates/nothing"}).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(ctx.client.get(ctx.template_uri()).dispatch().status(), Status::NotFound);
+    assert_eq!(ctx.client.delete(ctx.template_uri()).dispatch().status(), Status::NotFound);
+
+    let ctx = TestContext::new();
+    ctx.register_template(false);
+    assert_eq!(ctx.client.delete(ctx.template_uri()).dispatch().status(), Status::Ok);
+    let project = ctx.create_student_repo();
+    assert!(!ctx.gitlab.state().files.contains_key(&project));
+}
+
+impl TestContext {
+    fn push_fix(&self, body: Value) -> Value {
+        let response = self.client.post(format!("{}/fix", self.template_uri())).header(ContentType::JSON).body(body.to_string()).dispatch();
+        let job = self.wait_job(response);
+        assert_eq!(job["kind"], "push_template_fix");
+        assert_eq!(job["status"], "succeeded", "{}", job);
+        job["result"].clone()
+    }
+}
+
+#[test]
+fn template_fix_opens_merge_request() {
+    let ctx = TestContext::new();
+    let template = ctx.register_template(false);
+    let project = ctx.create_student_repo();
+    ctx.gitlab.state().files.get_mut(&project).unwrap().insert("src/main.c".to_string(), b"int main() { return 0; }\n".to_vec());
+    ctx.gitlab.create_branch(template, "fix", &[("README.md", None), ("Makefile", Some("all:\n")), ("src/main.c", Some("int main() { return 1; }\n"))]);
+
+    let result = ctx.push_fix(json!({"ref": "fix"}));
+    let url = format!("https://gitlab.test/{}/merge_requests/1", project);
+    assert_eq!(result, json!([{"repo_name": "wangdch", "status": "opened", "merge_request_url": url}]));
+    let state = ctx.gitlab.state();
+    let fixed: Vec<(&str, &[u8])> = state.branches[&project]["upstream-fix"].iter().map(|(p, c)| (p.as_str(), c.as_slice())).collect();
+    assert_eq!(fixed, vec![("Makefile", &b"all:\n"[..]), ("src/main.c", &b"int main() { return 1; }\n"[..])]);
+    // student work on the default branch is untouched
+    assert_eq!(state.files[&project]["src/main.c"], b"int main() { return 0; }\n".to_vec());
+    assert_eq!(state.merge_requests[&project][0]["source_branch"], "upstream-fix");
+    assert_eq!(state.merge_requests[&project][0]["target_branch"], "master");
+    drop(state);
+
+    // the pending fix branch is never pushed over
+    ctx.gitlab.create_branch(template, "fix2", &[("Makefile", Some("all: main\n"))]);
+    assert_eq!(ctx.push_fix(json!({"ref": "fix2"})), json!([{"repo_name": "wangdch", "status": "conflict"}]));
+    assert_eq!(ctx.gitlab.state().branches[&project]["upstream-fix"]["Makefile"], b"all:\n".to_vec());
+    assert_eq!(ctx.gitlab.state().merge_requests[&project].len(), 1);
+}
+
+#[test]
+fn template_fix_as_branch() {
+    let ctx = TestContext::new();
+    let template = ctx.register_template(true);
+    let project = ctx.create_student_repo();
+    // nothing to fix
+    assert_eq!(ctx.push_fix(json!({"ref": "master", "merge_request": false})), json!([{"repo_name": "wangdch", "status": "skipped"}]));
+
+    ctx.gitlab.create_branch(template, "fix", &[("src/main.c", Some("int main() { return 1; }\n"))]);
+    assert_eq!(ctx.push_fix(json!({"ref": "fix", "base": "master", "merge_request": false})),
+               json!([{"repo_name": "wangdch", "status": "pushed"}]));
+    let state = ctx.gitlab.state();
+    assert_eq!(state.branches[&project]["upstream-fix"]["src/main.c"], b"int main() { return 1; }\n".to_vec());
+    assert!(!state.merge_requests.contains_key(&project));
+}
+
+#[test]
+fn template_fix_without_template() {
+    let ctx = TestContext::new();
+    ctx.create_assignment();
+    let response = ctx.client.post(format!("{}/fix", ctx.template_uri())).header(ContentType::JSON)
+        .body(json!({"ref": "fix"}).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn template_branch_kept_in_query() {
+    let ctx = TestContext::new();
+    let template = ctx.register_template(true);
+    let branch = "hw0&recursive=false#1";
+    ctx.gitlab.state().projects.get_mut(&template).unwrap()["default_branch"] = json!(branch);
+    let project = ctx.create_student_repo();
+    let tree = format!("GET projects/{}/repository/tree?ref=hw0%26recursive%3Dfalse%231&recursive=true&per_page=100", template);
+    assert!(ctx.gitlab.calls().contains(&tree), "{:?}", ctx.gitlab.calls());
+    assert_eq!(ctx.gitlab.state().projects[&project]["default_branch"], branch);
+    assert_eq!(ctx.starter_files(project), expected_files());
+}